@@ -0,0 +1,3 @@
+fn main() {
+    project_book_htmx_lib::run_cli()
+}
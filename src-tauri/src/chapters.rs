@@ -0,0 +1,195 @@
+mod cue;
+mod error;
+mod id3;
+mod mp4;
+
+pub use error::*;
+
+use std::{
+    fs::{self, File},
+    io::BufReader,
+    path::{Path, PathBuf},
+    sync::Arc,
+};
+
+use log::debug;
+use serde::{Deserialize, Serialize};
+
+/// A chapter on the books timeline, `start` and `end` are in milliseconds
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub(crate) struct Chapter {
+    pub(crate) title: Arc<str>,
+    pub(crate) start: u64,
+    pub(crate) end: u64,
+}
+
+#[cfg(feature = "app")]
+impl Chapter {
+    pub(crate) fn duration(&self) -> u64 {
+        self.end.saturating_sub(self.start)
+    }
+}
+
+/// Chapters found in a single file, relative to the start of that file
+pub(crate) struct FileChapters {
+    chapters: Vec<Chapter>,
+    duration: u64,
+}
+
+pub(crate) fn read_file(path: &Path) -> Result<FileChapters> {
+    debug!("Reading chapters from {path:?}");
+    let extension = path
+        .extension()
+        .map(|ext| ext.to_string_lossy().to_lowercase())
+        .unwrap_or_default();
+
+    let mut reader = BufReader::new(File::open(path)?);
+    let mut file = match extension.as_str() {
+        "m4b" | "m4a" | "mp4" => mp4::read_chapters(&mut reader),
+        "mp3" => id3::read_chapters(&mut reader),
+        _ => Err(Error::UnsupportedFormat),
+    }?;
+
+    // a sidecar cue sheet wins over anything embedded in the file
+    let cue_path = path.with_extension("cue");
+    if let Ok(cue) = fs::read_to_string(&cue_path) {
+        debug!("Reading chapters from {cue_path:?}");
+        let file_name = path
+            .file_name()
+            .map(|name| name.to_string_lossy())
+            .unwrap_or_default();
+        let chapters = cue::read_chapters(&cue, server_name(&file_name), file.duration);
+        if !chapters.is_empty() {
+            file.chapters = chapters;
+        }
+    }
+
+    Ok(file)
+}
+
+/// Downloaded parts are saved as `{index:03}-{name}` to keep them apart, this is the `name`
+fn server_name(name: &str) -> &str {
+    match name.split_once('-') {
+        Some((index, name)) if index.len() == 3 && index.bytes().all(|b| b.is_ascii_digit()) => {
+            name
+        }
+        _ => name,
+    }
+}
+
+/// Each chapter ends where the next one starts
+fn close_chapters(chapters: &mut [Chapter]) {
+    for index in 1..chapters.len() {
+        chapters[index - 1].end = chapters[index].start;
+    }
+}
+
+/// Joins the chapters of consecutive files into a single timeline, files without chapters
+/// of their own become a chapter named after the file. Each file comes with the duration
+/// the server reports for it, files in a format we can't read add no chapters but still
+/// take up that much of the timeline
+pub(crate) fn from_files(files: &[(PathBuf, u64)]) -> Result<Box<[Chapter]>> {
+    let mut chapters = Vec::new();
+    let mut offset = 0;
+
+    for (path, duration) in files {
+        let file = match read_file(path) {
+            Ok(file) => file,
+            Err(Error::UnsupportedFormat) => {
+                debug!("No chapters for unsupported {path:?}");
+                offset += duration;
+                continue;
+            }
+            Err(err) => return Err(err),
+        };
+        if file.chapters.is_empty() {
+            let title = path
+                .file_stem()
+                .map(|stem| server_name(&stem.to_string_lossy()).into())
+                .unwrap_or_default();
+            chapters.push(Chapter {
+                title,
+                start: offset,
+                end: offset + file.duration,
+            });
+        } else {
+            chapters.extend(file.chapters.into_iter().map(|chapter| Chapter {
+                start: chapter.start + offset,
+                end: chapter.end + offset,
+                ..chapter
+            }));
+        }
+        offset += file.duration;
+    }
+
+    Ok(chapters.into())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn fixture(name: &str) -> PathBuf {
+        Path::new(env!("CARGO_MANIFEST_DIR"))
+            .join("tests/fixtures")
+            .join(name)
+    }
+
+    #[test]
+    fn sidecar_cue_overrides_embedded_chapters() {
+        // chapters.mp3 has chapters of its own, chapters.cue sits next to it
+        let file = read_file(&fixture("chapters.mp3")).unwrap();
+        let titles = file
+            .chapters
+            .iter()
+            .map(|chapter| chapter.title.as_ref())
+            .collect::<Vec<_>>();
+        assert_eq!(file.duration, 12_000);
+        assert_eq!(titles, ["Part One", "Part Two"]);
+        assert_eq!(file.chapters[1].start, 4_493);
+        assert_eq!(file.chapters[1].end, 12_000);
+    }
+
+    #[test]
+    fn joins_files_into_one_timeline() {
+        let chapters =
+            from_files(&[(fixture("chapters.mp3"), 0), (fixture("xing.mp3"), 0)]).unwrap();
+        let spans = chapters
+            .iter()
+            .map(|chapter| (chapter.title.as_ref(), chapter.start, chapter.end))
+            .collect::<Vec<_>>();
+        assert_eq!(
+            spans,
+            [
+                ("Part One", 0, 4_493),
+                ("Part Two", 4_493, 12_000),
+                ("xing", 12_000, 14_612)
+            ]
+        );
+    }
+
+    #[test]
+    fn skips_files_in_unknown_formats() {
+        // the cue sheet stands in for a part we can't read
+        let chapters =
+            from_files(&[(fixture("chapters.cue"), 5_000), (fixture("xing.mp3"), 0)]).unwrap();
+        let spans = chapters
+            .iter()
+            .map(|chapter| (chapter.title.as_ref(), chapter.start, chapter.end))
+            .collect::<Vec<_>>();
+        assert_eq!(spans, [("xing", 5_000, 7_612)]);
+    }
+
+    #[test]
+    fn names_chapters_without_the_download_prefix() {
+        assert_eq!(server_name("001-Chapter 1"), "Chapter 1");
+        assert_eq!(server_name("Part-2"), "Part-2");
+        assert_eq!(server_name("xing"), "xing");
+    }
+
+    #[test]
+    fn rejects_unknown_formats() {
+        let result = read_file(&fixture("chapters.cue"));
+        assert!(matches!(result, Err(Error::UnsupportedFormat)));
+    }
+}
@@ -0,0 +1,167 @@
+use std::{collections::HashSet, sync::Arc};
+
+use super::{close_chapters, Chapter};
+
+// INDEX times are mm:ss:ff with 75 frames a second
+const FRAMES_PER_SECOND: u64 = 75;
+
+struct CueTrack {
+    file: Option<String>,
+    title: Option<String>,
+    start: Option<u64>,
+}
+
+/// Reads the tracks of a cue sheet as chapters of `file_name`, if none of the sheets
+/// `FILE` entries match the name every track is used as long as the sheet is for one file
+pub(super) fn read_chapters(cue: &str, file_name: &str, duration: u64) -> Vec<Chapter> {
+    let mut file = None;
+    let mut tracks: Vec<CueTrack> = Vec::new();
+
+    for line in cue.lines() {
+        let line = line.trim();
+        let (command, rest) = line.split_once(char::is_whitespace).unwrap_or((line, ""));
+        match command.to_uppercase().as_str() {
+            "FILE" => file = Some(unquote(rest)),
+            "TRACK" => tracks.push(CueTrack {
+                file: file.clone(),
+                title: None,
+                start: None,
+            }),
+            "TITLE" => {
+                if let Some(track) = tracks.last_mut() {
+                    track.title = Some(unquote(rest));
+                }
+            }
+            "INDEX" => {
+                let (number, time) = rest
+                    .trim()
+                    .split_once(char::is_whitespace)
+                    .unwrap_or_default();
+                if let (Some(track), "01") = (tracks.last_mut(), number) {
+                    track.start = parse_time(time.trim());
+                }
+            }
+            _ => (),
+        }
+    }
+
+    let matches = |track: &&CueTrack| {
+        track
+            .file
+            .as_ref()
+            .is_some_and(|file| file.eq_ignore_ascii_case(file_name))
+    };
+    let any_match = tracks.iter().any(|track| matches(&track));
+    // tracks of several files can't be put on one timeline without knowing which file is ours
+    let files = tracks
+        .iter()
+        .filter_map(|track| track.file.as_ref())
+        .collect::<HashSet<_>>();
+    if !any_match && files.len() > 1 {
+        return Vec::new();
+    }
+
+    let mut chapters = tracks
+        .iter()
+        .filter(|track| !any_match || matches(track))
+        .enumerate()
+        .filter_map(|(index, track)| {
+            Some(Chapter {
+                title: Arc::from(
+                    track
+                        .title
+                        .clone()
+                        .unwrap_or_else(|| format!("Track {}", index + 1)),
+                ),
+                start: track.start?,
+                end: duration,
+            })
+        })
+        .collect::<Vec<_>>();
+
+    chapters.sort_by_key(|chapter| chapter.start);
+    close_chapters(&mut chapters);
+
+    chapters
+}
+
+fn unquote(value: &str) -> String {
+    let value = value.trim();
+    match value.strip_prefix('"') {
+        Some(quoted) => quoted.split('"').next().unwrap_or_default().to_string(),
+        None => value
+            .split_whitespace()
+            .next()
+            .unwrap_or_default()
+            .to_string(),
+    }
+}
+
+/// Converts `mm:ss:ff` into milliseconds
+fn parse_time(time: &str) -> Option<u64> {
+    let mut parts = time.split(':').map(|part| part.parse::<u64>().ok());
+    let (minutes, seconds, frames) = (parts.next()??, parts.next()??, parts.next()??);
+
+    Some((minutes * 60 + seconds) * 1000 + frames * 1000 / FRAMES_PER_SECOND)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const SHEET: &str = include_str!("../../tests/fixtures/chapters.cue");
+
+    fn spans(chapters: &[Chapter]) -> Vec<(&str, u64, u64)> {
+        chapters
+            .iter()
+            .map(|chapter| (chapter.title.as_ref(), chapter.start, chapter.end))
+            .collect()
+    }
+
+    #[test]
+    fn parses_index_times() {
+        assert_eq!(parse_time("00:00:00"), Some(0));
+        assert_eq!(parse_time("01:02:15"), Some(62_200));
+        assert_eq!(parse_time("00:00:74"), Some(986));
+        assert_eq!(parse_time("120:00:00"), Some(7_200_000));
+        assert_eq!(parse_time("01:02"), None);
+        assert_eq!(parse_time("aa:00:00"), None);
+        assert_eq!(parse_time(""), None);
+    }
+
+    #[test]
+    fn reads_tracks_of_the_matching_file() {
+        let chapters = read_chapters(SHEET, "CHAPTERS.MP3", 12_000);
+        assert_eq!(
+            spans(&chapters),
+            [("Part One", 0, 4_493), ("Part Two", 4_493, 12_000)]
+        );
+    }
+
+    #[test]
+    fn reads_nothing_of_several_files_without_a_matching_one() {
+        assert!(read_chapters(SHEET, "missing.mp3", 20_000).is_empty());
+    }
+
+    #[test]
+    fn reads_a_single_file_sheet_under_another_name() {
+        let sheet = "FILE \"old name.mp3\" MP3\nTRACK 01 AUDIO\nTITLE Intro\nINDEX 01 00:00:00\n\
+                     TRACK 02 AUDIO\nTITLE Story\nINDEX 01 00:30:00\n";
+        let chapters = read_chapters(sheet, "book.mp3", 90_000);
+        assert_eq!(
+            spans(&chapters),
+            [("Intro", 0, 30_000), ("Story", 30_000, 90_000)]
+        );
+    }
+
+    #[test]
+    fn names_untitled_tracks() {
+        let sheet = "FILE book.mp3 MP3\nTRACK 01 AUDIO\nINDEX 01 00:00:00\n\
+                     TRACK 02 AUDIO\nINDEX 01 01:00:00\nTRACK 03 AUDIO\n";
+        let chapters = read_chapters(sheet, "book.mp3", 90_000);
+        assert_eq!(
+            spans(&chapters),
+            [("Track 1", 0, 60_000), ("Track 2", 60_000, 90_000)]
+        );
+    }
+}
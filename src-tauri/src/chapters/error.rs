@@ -0,0 +1,12 @@
+use derive_more::{Display, Error, From};
+
+pub type Result<T> = core::result::Result<T, Error>;
+
+#[derive(Debug, From, Error, Display)]
+pub enum Error {
+    Io(std::io::Error),
+    InvalidAtom,
+    InvalidTag,
+    MoovNotFound,
+    UnsupportedFormat,
+}
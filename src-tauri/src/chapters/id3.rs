@@ -0,0 +1,357 @@
+use std::{
+    collections::HashMap,
+    io::{Read, Seek, SeekFrom},
+    sync::Arc,
+};
+
+use log::debug;
+
+use super::{Chapter, Error, FileChapters, Result};
+
+const HEADER_SIZE: usize = 10;
+
+pub(super) fn read_chapters<R: Read + Seek>(reader: &mut R) -> Result<FileChapters> {
+    let length = reader.seek(SeekFrom::End(0))?;
+    reader.seek(SeekFrom::Start(0))?;
+
+    let mut header = [0u8; HEADER_SIZE];
+    reader.read_exact(&mut header)?;
+
+    let version = header[3];
+    let mut tag = Vec::new();
+    if &header[0..3] == b"ID3" {
+        // the header can claim up to 256MB, more than the file holds is not a tag
+        let size = u64::from(synchsafe(&header[6..10]));
+        if size > length - HEADER_SIZE as u64 {
+            return Err(Error::InvalidTag);
+        }
+        tag = vec![0u8; size as usize];
+        reader.read_exact(&mut tag)?;
+    }
+    let tag_size = if tag.is_empty() {
+        0
+    } else {
+        (HEADER_SIZE + tag.len()) as u64
+    };
+    let frames = read_frames(&tag, version, header[5]);
+
+    let duration = frames
+        .iter()
+        .find(|frame| frame.id == *b"TLEN")
+        .and_then(|frame| text(frame.body)?.trim().parse().ok());
+    let duration = match duration {
+        Some(duration) => duration,
+        None => {
+            reader.seek(SeekFrom::Start(tag_size))?;
+            let mut audio = vec![0u8; 4096.min(length.saturating_sub(tag_size)) as usize];
+            reader.read_exact(&mut audio)?;
+            mpeg_duration(&audio, length - tag_size).unwrap_or_default()
+        }
+    };
+
+    let chapters = chapters(&frames, version, duration);
+    debug!("found {} chapters", chapters.len());
+
+    Ok(FileChapters { chapters, duration })
+}
+
+struct Frame<'a> {
+    id: [u8; 4],
+    body: &'a [u8],
+}
+
+fn synchsafe(bytes: &[u8]) -> u32 {
+    bytes
+        .iter()
+        .fold(0, |size, byte| (size << 7) | (*byte & 0x7F) as u32)
+}
+
+fn read_u32(data: &[u8], position: usize) -> Option<u32> {
+    Some(u32::from_be_bytes(
+        data.get(position..position + 4)?.try_into().ok()?,
+    ))
+}
+
+/// Splits a tag (or a `CHAP`/`CTOC` body) into frames, only v2.3 and v2.4 are supported
+fn read_frames(data: &[u8], version: u8, flags: u8) -> Vec<Frame<'_>> {
+    if version != 3 && version != 4 {
+        return Vec::new();
+    }
+
+    let mut position = 0;
+    if flags & 0x40 != 0 {
+        // extended header, v2.3 doesn't count its own size field
+        position = match (version, data.get(0..4)) {
+            (3, Some(size)) => 4 + u32::from_be_bytes(size.try_into().unwrap()) as usize,
+            (_, Some(size)) => synchsafe(size) as usize,
+            _ => return Vec::new(),
+        };
+    }
+
+    split_frames(data, position, version)
+}
+
+fn split_frames(data: &[u8], mut position: usize, version: u8) -> Vec<Frame<'_>> {
+    let mut frames = Vec::new();
+    while let Some(header) = data.get(position..position + HEADER_SIZE) {
+        if header[0] == 0 {
+            break; // padding
+        }
+        let size = match version {
+            4 => synchsafe(&header[4..8]),
+            _ => u32::from_be_bytes([header[4], header[5], header[6], header[7]]),
+        } as usize;
+        let start = position + HEADER_SIZE;
+        let Some(body) = data.get(start..start + size) else {
+            break;
+        };
+
+        frames.push(Frame {
+            id: [header[0], header[1], header[2], header[3]],
+            body,
+        });
+        position = start + size;
+    }
+
+    frames
+}
+
+/// Decodes a text frame body, the first byte is the encoding
+fn text(body: &[u8]) -> Option<String> {
+    let (encoding, text) = body.split_first()?;
+    let text = match encoding {
+        0 => text.iter().map(|byte| *byte as char).collect(),
+        1 | 2 => {
+            let (big_endian, text) = match text {
+                [0xFF, 0xFE, rest @ ..] => (false, rest),
+                [0xFE, 0xFF, rest @ ..] => (true, rest),
+                rest => (*encoding == 2, rest),
+            };
+            let units = text
+                .chunks_exact(2)
+                .map(|unit| match big_endian {
+                    true => u16::from_be_bytes([unit[0], unit[1]]),
+                    false => u16::from_le_bytes([unit[0], unit[1]]),
+                })
+                .collect::<Vec<_>>();
+            String::from_utf16_lossy(&units)
+        }
+        _ => String::from_utf8_lossy(text).into_owned(),
+    };
+
+    Some(text.trim_end_matches('\0').to_string())
+}
+
+/// Splits a null terminated element id off the front of a `CHAP` or `CTOC` body
+fn element_id(body: &[u8]) -> Option<(&[u8], &[u8])> {
+    let end = body.iter().position(|byte| *byte == 0)?;
+    Some((&body[..end], &body[end + 1..]))
+}
+
+fn chapters(frames: &[Frame], version: u8, duration: u64) -> Vec<Chapter> {
+    let mut chapters = HashMap::new();
+    for frame in frames.iter().filter(|frame| frame.id == *b"CHAP") {
+        let Some((id, body)) = element_id(frame.body) else {
+            continue;
+        };
+        let (Some(start), Some(end)) = (read_u32(body, 0), read_u32(body, 4)) else {
+            continue;
+        };
+        // sub frames use the same layout as the rest of the tag
+        let title = split_frames(body.get(16..).unwrap_or_default(), 0, version)
+            .iter()
+            .find(|frame| frame.id == *b"TIT2")
+            .and_then(|frame| text(frame.body))
+            .unwrap_or_else(|| String::from_utf8_lossy(id).into_owned());
+
+        let end = if end == u32::MAX {
+            duration
+        } else {
+            end as u64
+        };
+        chapters.insert(
+            id,
+            Chapter {
+                title: Arc::from(title),
+                start: start as u64,
+                end,
+            },
+        );
+    }
+
+    // the top level table of contents gives the intended order, otherwise go by start time
+    let order = frames
+        .iter()
+        .filter(|frame| frame.id == *b"CTOC")
+        .filter_map(|frame| element_id(frame.body))
+        .find(|(_, body)| body.first().is_some_and(|flags| flags & 0x02 != 0))
+        .map(|(_, body)| table_of_contents(body));
+
+    match order {
+        Some(order) if !order.is_empty() => {
+            order.iter().filter_map(|id| chapters.remove(id)).collect()
+        }
+        _ => {
+            let mut chapters = chapters.into_values().collect::<Vec<_>>();
+            chapters.sort_by_key(|chapter| chapter.start);
+            chapters
+        }
+    }
+}
+
+fn table_of_contents(body: &[u8]) -> Vec<&[u8]> {
+    let Some(count) = body.get(1) else {
+        return Vec::new();
+    };
+
+    let mut entries = Vec::with_capacity(*count as usize);
+    let mut rest = body.get(2..).unwrap_or_default();
+    for _ in 0..*count {
+        let Some((id, remaining)) = element_id(rest) else {
+            break;
+        };
+        entries.push(id);
+        rest = remaining;
+    }
+
+    entries
+}
+
+/// Works out the length of mpeg audio from the first frame, using the Xing/Info header
+/// when the file is variable bitrate
+fn mpeg_duration(audio: &[u8], audio_size: u64) -> Option<u64> {
+    let start = audio
+        .windows(2)
+        .position(|bytes| bytes[0] == 0xFF && bytes[1] & 0xE0 == 0xE0)?;
+    let header = read_u32(audio, start)?;
+
+    let version = (header >> 19) & 0b11; // 0 = 2.5, 2 = 2, 3 = 1
+    let layer = (header >> 17) & 0b11; // 1 = layer III
+    if layer != 1 || version == 1 {
+        return None;
+    }
+    let mpeg1 = version == 3;
+
+    let bitrate = match mpeg1 {
+        true => [
+            0, 32, 40, 48, 56, 64, 80, 96, 112, 128, 160, 192, 224, 256, 320,
+        ],
+        false => [0, 8, 16, 24, 32, 40, 48, 56, 64, 80, 96, 112, 128, 144, 160],
+    }
+    .get(((header >> 12) & 0xF) as usize)
+    .copied()? as u64
+        * 1000;
+    let sample_rate = match version {
+        3 => [44100, 48000, 32000],
+        2 => [22050, 24000, 16000],
+        _ => [11025, 12000, 8000],
+    }
+    .get(((header >> 10) & 0b11) as usize)
+    .copied()? as u64;
+    let samples_per_frame = if mpeg1 { 1152 } else { 576 };
+
+    let mono = (header >> 6) & 0b11 == 0b11;
+    let side_info = match (mpeg1, mono) {
+        (true, false) => 32,
+        (true, true) | (false, false) => 17,
+        (false, true) => 9,
+    };
+    let xing = start + 4 + side_info;
+    if let Some(b"Xing" | b"Info") = audio.get(xing..xing + 4) {
+        let flags = read_u32(audio, xing + 4)?;
+        if flags & 0x1 != 0 {
+            let frames = read_u32(audio, xing + 8)? as u64;
+            return Some(frames * samples_per_frame * 1000 / sample_rate);
+        }
+    }
+
+    if bitrate == 0 {
+        return None;
+    }
+    Some(audio_size * 8 * 1000 / bitrate)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Cursor;
+
+    use super::*;
+
+    fn read(fixture: &[u8]) -> FileChapters {
+        read_chapters(&mut Cursor::new(fixture)).unwrap()
+    }
+
+    fn spans(chapters: &[Chapter]) -> Vec<(&str, u64, u64)> {
+        chapters
+            .iter()
+            .map(|chapter| (chapter.title.as_ref(), chapter.start, chapter.end))
+            .collect()
+    }
+
+    /// A first frame of mpeg 1 layer III audio at 128 kbit/s and 44.1 kHz
+    fn mpeg_frame(xing_frames: Option<u32>) -> Vec<u8> {
+        let mut frame = vec![0xFF, 0xFB, 0x90, 0x00];
+        frame.resize(36, 0);
+        if let Some(frames) = xing_frames {
+            frame.extend(b"Xing");
+            frame.extend(1u32.to_be_bytes());
+            frame.extend(frames.to_be_bytes());
+        }
+        frame.resize(417, 0);
+        frame
+    }
+
+    #[test]
+    fn reads_chapters_in_table_of_contents_order() {
+        let file = read(include_bytes!("../../tests/fixtures/chapters.mp3"));
+        assert_eq!(file.duration, 12_000);
+        // the open ended chapter runs to TLEN and the one left out of the CTOC is dropped
+        assert_eq!(
+            spans(&file.chapters),
+            [("Opening", 0, 5_000), ("Ending", 5_000, 12_000)]
+        );
+    }
+
+    #[test]
+    fn falls_back_to_the_xing_header_without_tlen() {
+        let file = read(include_bytes!("../../tests/fixtures/xing.mp3"));
+        assert_eq!(file.duration, 100 * 1152 * 1000 / 44_100);
+        assert!(file.chapters.is_empty());
+    }
+
+    #[test]
+    fn falls_back_to_the_bitrate_without_xing() {
+        let mut audio = mpeg_frame(None);
+        audio.resize(16_000, 0);
+        assert_eq!(read(&audio).duration, 1_000);
+        assert_eq!(mpeg_duration(&audio, 32_000), Some(2_000));
+    }
+
+    #[test]
+    fn prefers_xing_frames_over_the_bitrate() {
+        assert_eq!(
+            mpeg_duration(&mpeg_frame(Some(441)), 1_000_000),
+            Some(11_520)
+        );
+    }
+
+    #[test]
+    fn ignores_data_that_is_not_mpeg_audio() {
+        assert_eq!(mpeg_duration(&[0u8; 64], 64), None);
+        assert_eq!(read(&[0u8; 64]).duration, 0);
+    }
+
+    #[test]
+    fn rejects_a_tag_larger_than_the_file() {
+        let mut file = b"ID3\x04\x00\x00\x7F\x7F\x7F\x7F".to_vec();
+        file.resize(64, 0);
+        let result = read_chapters(&mut Cursor::new(file));
+        assert!(matches!(result, Err(Error::InvalidTag)));
+    }
+
+    #[test]
+    fn reads_synchsafe_sizes() {
+        assert_eq!(synchsafe(&[0x00, 0x00, 0x02, 0x01]), 0x101);
+        assert_eq!(synchsafe(&[0x7F, 0x7F, 0x7F, 0x7F]), 0x0FFF_FFFF);
+    }
+}
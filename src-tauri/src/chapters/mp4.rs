@@ -0,0 +1,476 @@
+use std::{
+    io::{Read, Seek, SeekFrom},
+    sync::Arc,
+};
+
+use log::debug;
+
+use super::{close_chapters, Chapter, Error, FileChapters, Result};
+
+// Nero chapter start times are in 100ns units
+const CHPL_TIMESCALE: u64 = 10_000_000;
+// a text sample is a 16 bit length and the text, anything after that isn't needed
+const MAX_TEXT_SAMPLE: u64 = 2 + u16::MAX as u64;
+
+pub(super) fn read_chapters<R: Read + Seek>(reader: &mut R) -> Result<FileChapters> {
+    let length = reader.seek(SeekFrom::End(0))?;
+    let moov = read_moov(reader, length)?;
+    let (timescale, duration) = children(&moov)
+        .find(|(kind, _)| kind == b"mvhd")
+        .and_then(|(_, mvhd)| media_header(mvhd))
+        .ok_or(Error::InvalidAtom)?;
+    let duration = to_millis(duration, timescale);
+
+    let mut chapters = chapter_track(reader, &moov, length, duration)?;
+    if chapters.is_empty() {
+        chapters = nero_chapters(&moov, duration).unwrap_or_default();
+    }
+    debug!("found {} chapters", chapters.len());
+
+    Ok(FileChapters { chapters, duration })
+}
+
+/// Reads the `moov` atom into memory, sizes are checked against the file so a corrupt one can't
+/// ask for more than is there
+fn read_moov<R: Read + Seek>(reader: &mut R, length: u64) -> Result<Vec<u8>> {
+    let mut position = 0u64;
+
+    // moov can be at the start or end of the file so walk the top level atoms
+    while length - position >= 8 {
+        reader.seek(SeekFrom::Start(position))?;
+        let mut header = [0u8; 8];
+        reader.read_exact(&mut header)?;
+
+        let mut size = u32::from_be_bytes([header[0], header[1], header[2], header[3]]) as u64;
+        let mut header_size = 8;
+        if size == 1 {
+            let mut large = [0u8; 8];
+            reader.read_exact(&mut large)?;
+            size = u64::from_be_bytes(large);
+            header_size = 16;
+        } else if size == 0 {
+            size = length - position;
+        }
+        if size < header_size || size > length - position {
+            return Err(Error::InvalidAtom);
+        }
+
+        if &header[4..8] == b"moov" {
+            let mut moov = vec![0u8; (size - header_size) as usize];
+            reader.read_exact(&mut moov)?;
+            return Ok(moov);
+        }
+        position += size;
+    }
+
+    Err(Error::MoovNotFound)
+}
+
+/// Iterates the child atoms contained in `data`
+fn children(data: &[u8]) -> impl Iterator<Item = ([u8; 4], &[u8])> {
+    let mut position = 0;
+    std::iter::from_fn(move || {
+        let size = read_u32(data, position)? as usize;
+        let kind: [u8; 4] = read_bytes(data, position + 4)?;
+        let (start, end) = match size {
+            0 => (position + 8, data.len()),
+            1 => (
+                position + 16,
+                position.checked_add(usize::try_from(read_u64(data, position + 8)?).ok()?)?,
+            ),
+            size => (position + 8, position.checked_add(size)?),
+        };
+        let body = data.get(start..end)?;
+        position = end;
+        Some((kind, body))
+    })
+}
+
+fn find<'a>(data: &'a [u8], path: &[&[u8; 4]]) -> Option<&'a [u8]> {
+    path.iter().try_fold(data, |data, kind| {
+        children(data)
+            .find(|(child, _)| child == *kind)
+            .map(|(_, body)| body)
+    })
+}
+
+fn read_u8(data: &[u8], position: usize) -> Option<u8> {
+    data.get(position).copied()
+}
+
+/// `N` bytes at `position`, positions come from the file so may be anything
+fn read_bytes<const N: usize>(data: &[u8], position: usize) -> Option<[u8; N]> {
+    data.get(position..position.checked_add(N)?)?
+        .try_into()
+        .ok()
+}
+
+fn read_u16(data: &[u8], position: usize) -> Option<u16> {
+    read_bytes(data, position).map(u16::from_be_bytes)
+}
+
+fn read_u32(data: &[u8], position: usize) -> Option<u32> {
+    read_bytes(data, position).map(u32::from_be_bytes)
+}
+
+fn read_u64(data: &[u8], position: usize) -> Option<u64> {
+    read_bytes(data, position).map(u64::from_be_bytes)
+}
+
+fn to_millis(value: u64, timescale: u64) -> u64 {
+    if timescale == 0 {
+        0
+    } else {
+        (value as u128 * 1000 / timescale as u128) as u64
+    }
+}
+
+/// Reads the timescale and duration out of a `mvhd` or `mdhd` atom
+fn media_header(data: &[u8]) -> Option<(u64, u64)> {
+    match read_u8(data, 0)? {
+        1 => Some((read_u32(data, 20)? as u64, read_u64(data, 24)?)),
+        _ => Some((read_u32(data, 12)? as u64, read_u32(data, 16)? as u64)),
+    }
+}
+
+fn track_id(trak: &[u8]) -> Option<u32> {
+    let tkhd = find(trak, &[b"tkhd"])?;
+    match read_u8(tkhd, 0)? {
+        1 => read_u32(tkhd, 20),
+        _ => read_u32(tkhd, 12),
+    }
+}
+
+/// Chapters stored as a text track referenced by another tracks `tref/chap`
+fn chapter_track<R: Read + Seek>(
+    reader: &mut R,
+    moov: &[u8],
+    length: u64,
+    duration: u64,
+) -> Result<Vec<Chapter>> {
+    let tracks = children(moov)
+        .filter(|(kind, _)| kind == b"trak")
+        .map(|(_, trak)| trak)
+        .collect::<Vec<_>>();
+
+    let chapter_ids = tracks
+        .iter()
+        .filter_map(|trak| find(trak, &[b"tref", b"chap"]))
+        .flat_map(|chap| chap.chunks_exact(4).map(|id| read_u32(id, 0)))
+        .flatten()
+        .collect::<Vec<_>>();
+
+    let Some(trak) = tracks
+        .iter()
+        .find(|trak| track_id(trak).is_some_and(|id| chapter_ids.contains(&id)))
+    else {
+        return Ok(Vec::new());
+    };
+
+    let (timescale, _) = find(trak, &[b"mdia", b"mdhd"])
+        .and_then(media_header)
+        .ok_or(Error::InvalidAtom)?;
+    let stbl = find(trak, &[b"mdia", b"minf", b"stbl"]).ok_or(Error::InvalidAtom)?;
+
+    let locations = sample_locations(stbl, length).ok_or(Error::InvalidAtom)?;
+    let starts = sample_times(stbl, locations.len())
+        .ok_or(Error::InvalidAtom)?
+        .into_iter()
+        .map(|time| to_millis(time, timescale));
+
+    let mut chapters = Vec::with_capacity(locations.len());
+    for (start, (offset, size)) in starts.zip(locations) {
+        reader.seek(SeekFrom::Start(offset))?;
+        let mut sample = vec![0u8; size.min(MAX_TEXT_SAMPLE) as usize];
+        reader.read_exact(&mut sample)?;
+
+        chapters.push(Chapter {
+            title: text_sample(&sample).unwrap_or_default(),
+            start,
+            end: duration,
+        });
+    }
+    close_chapters(&mut chapters);
+
+    Ok(chapters)
+}
+
+/// Start time of the first `limit` samples in the tracks timescale, from `stts`
+fn sample_times(stbl: &[u8], limit: usize) -> Option<Vec<u64>> {
+    let stts = find(stbl, &[b"stts"])?;
+    let count = read_u32(stts, 4)? as usize;
+
+    let mut times = Vec::with_capacity(limit);
+    let mut time = 0u64;
+    for entry in 0..count {
+        let samples = read_u32(stts, 8 + entry * 8)?;
+        let delta = read_u32(stts, 12 + entry * 8)? as u64;
+        for _ in 0..samples {
+            if times.len() == limit {
+                return Some(times);
+            }
+            times.push(time);
+            time = time.checked_add(delta)?;
+        }
+    }
+
+    Some(times)
+}
+
+/// File offset and size of every sample, from `stsz`, `stsc` and `stco`/`co64`, every sample
+/// has to be inside the file that is `length` bytes long
+fn sample_locations(stbl: &[u8], length: u64) -> Option<Vec<(u64, u64)>> {
+    let stsz = find(stbl, &[b"stsz"])?;
+    let uniform_size = read_u32(stsz, 4)? as u64;
+    // there can't be more samples than the table has room for, or than fit in the file
+    let sample_count = (read_u32(stsz, 8)? as u64).min(match uniform_size {
+        0 => stsz.len().saturating_sub(12) as u64 / 4,
+        size => length / size,
+    }) as usize;
+    let size = |sample: usize| match uniform_size {
+        0 => read_u32(stsz, 12 + sample * 4).map(u64::from),
+        size => Some(size),
+    };
+
+    let chunk_offsets = if let Some(stco) = find(stbl, &[b"stco"]) {
+        let count = read_u32(stco, 4)? as usize;
+        (0..count)
+            .map(|chunk| read_u32(stco, 8 + chunk * 4).map(u64::from))
+            .collect::<Option<Vec<_>>>()?
+    } else {
+        let co64 = find(stbl, &[b"co64"])?;
+        let count = read_u32(co64, 4)? as usize;
+        (0..count)
+            .map(|chunk| read_u64(co64, 8 + chunk * 8))
+            .collect::<Option<Vec<_>>>()?
+    };
+
+    let stsc = find(stbl, &[b"stsc"])?;
+    let runs = (0..read_u32(stsc, 4)? as usize)
+        .map(|run| {
+            Some((
+                read_u32(stsc, 8 + run * 12)?,
+                read_u32(stsc, 12 + run * 12)?,
+            ))
+        })
+        .collect::<Option<Vec<_>>>()?;
+
+    let mut locations = Vec::with_capacity(sample_count);
+    let mut sample = 0;
+    for (chunk, chunk_offset) in chunk_offsets.iter().enumerate() {
+        let chunk = chunk as u32 + 1;
+        let per_chunk = runs
+            .iter()
+            .take_while(|(first, _)| *first <= chunk)
+            .last()
+            .map(|(_, samples)| *samples)?;
+
+        let mut offset = *chunk_offset;
+        for _ in 0..per_chunk {
+            if sample == sample_count {
+                return Some(locations);
+            }
+            let size = size(sample)?;
+            let end = offset.checked_add(size).filter(|end| *end <= length)?;
+            locations.push((offset, size));
+            offset = end;
+            sample += 1;
+        }
+    }
+
+    Some(locations)
+}
+
+/// Text samples are a length prefixed string, optionally utf-16 with a BOM
+fn text_sample(sample: &[u8]) -> Option<Arc<str>> {
+    let length = read_u16(sample, 0)? as usize;
+    let text = sample.get(2..2 + length)?;
+
+    let title = if let Some(utf16) = text.strip_prefix(&[0xFE, 0xFF]) {
+        let units = utf16
+            .chunks_exact(2)
+            .map(|unit| u16::from_be_bytes([unit[0], unit[1]]))
+            .collect::<Vec<_>>();
+        String::from_utf16_lossy(&units)
+    } else {
+        String::from_utf8_lossy(text).into_owned()
+    };
+
+    Some(title.into())
+}
+
+/// Nero style chapters stored in `moov/udta/chpl`
+fn nero_chapters(moov: &[u8], duration: u64) -> Option<Vec<Chapter>> {
+    let chpl = find(moov, &[b"udta", b"chpl"])?;
+    let mut position = match read_u8(chpl, 0)? {
+        0 => 4,
+        _ => 8, // version 1 has an extra reserved field
+    };
+    let count = read_u8(chpl, position)?;
+    position += 1;
+
+    let mut chapters = Vec::with_capacity(count as usize);
+    for _ in 0..count {
+        let start = read_u64(chpl, position)?;
+        let length = read_u8(chpl, position + 8)? as usize;
+        let title = chpl.get(position + 9..(position + 9).checked_add(length)?)?;
+        position += 9 + length;
+
+        chapters.push(Chapter {
+            title: String::from_utf8_lossy(title).into(),
+            start: to_millis(start, CHPL_TIMESCALE),
+            end: duration,
+        });
+    }
+    close_chapters(&mut chapters);
+
+    Some(chapters)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Cursor;
+
+    use super::*;
+
+    fn atom(kind: &[u8; 4], body: &[u8]) -> Vec<u8> {
+        [&(8 + body.len() as u32).to_be_bytes(), kind, body].concat()
+    }
+
+    fn full_atom(kind: &[u8; 4], fields: &[u32]) -> Vec<u8> {
+        let body = fields
+            .iter()
+            .flat_map(|field| field.to_be_bytes())
+            .collect::<Vec<_>>();
+        atom(kind, &body)
+    }
+
+    /// A minute long file with an `mvhd` and whatever else is put in `moov`
+    fn file(moov: &[Vec<u8>], rest: &[u8]) -> Vec<u8> {
+        let mvhd = full_atom(b"mvhd", &[0, 0, 0, 1000, 60_000]);
+        [
+            atom(b"moov", &[mvhd, moov.concat()].concat()),
+            rest.to_vec(),
+        ]
+        .concat()
+    }
+
+    fn spans(file: &FileChapters) -> Vec<(&str, u64, u64)> {
+        file.chapters
+            .iter()
+            .map(|chapter| (chapter.title.as_ref(), chapter.start, chapter.end))
+            .collect()
+    }
+
+    /// A chapter text track with two samples, `stsz` and `stts` can be swapped out
+    fn chapter_file(stsz: Vec<u8>, stts: Vec<u8>) -> Vec<u8> {
+        let samples = [&[0, 5][..], b"Intro", &[0, 4], b"Main"].concat();
+        let audio = atom(
+            b"trak",
+            &[
+                full_atom(b"tkhd", &[0, 0, 0, 1]),
+                atom(b"tref", &full_atom(b"chap", &[2])),
+            ]
+            .concat(),
+        );
+        let text = |chunk_offset: u32| {
+            let stbl = [
+                stts.clone(),
+                stsz.clone(),
+                full_atom(b"stsc", &[0, 1, 1, 2, 1]),
+                full_atom(b"stco", &[0, 1, chunk_offset]),
+            ]
+            .concat();
+            let mdia = [
+                full_atom(b"mdhd", &[0, 0, 0, 1000, 60_000]),
+                atom(b"minf", &atom(b"stbl", &stbl)),
+            ]
+            .concat();
+            atom(
+                b"trak",
+                &[full_atom(b"tkhd", &[0, 0, 0, 2]), atom(b"mdia", &mdia)].concat(),
+            )
+        };
+
+        // the offset is a fixed width so the moov is the same size either way
+        let moov_size = file(&[audio.clone(), text(0)], &[]).len() as u32;
+        file(&[audio, text(moov_size + 8)], &atom(b"mdat", &samples))
+    }
+
+    fn read(data: Vec<u8>) -> Result<FileChapters> {
+        read_chapters(&mut Cursor::new(data))
+    }
+
+    #[test]
+    fn reads_a_chapter_track() {
+        let data = chapter_file(
+            full_atom(b"stsz", &[0, 0, 2, 7, 6]),
+            full_atom(b"stts", &[0, 1, 2, 30_000]),
+        );
+        let file = read(data).unwrap();
+        assert_eq!(file.duration, 60_000);
+        assert_eq!(
+            spans(&file),
+            [("Intro", 0, 30_000), ("Main", 30_000, 60_000)]
+        );
+    }
+
+    #[test]
+    fn reads_nero_chapters() {
+        let chpl = [&[0u8, 0, 0, 0, 2][..], &0u64.to_be_bytes(), &[5], b"Intro"]
+            .concat()
+            .into_iter()
+            .chain([&300_000_000u64.to_be_bytes()[..], &[4], b"Main"].concat())
+            .collect::<Vec<_>>();
+        let file = read(file(&[atom(b"udta", &atom(b"chpl", &chpl))], &[])).unwrap();
+        assert_eq!(
+            spans(&file),
+            [("Intro", 0, 30_000), ("Main", 30_000, 60_000)]
+        );
+    }
+
+    #[test]
+    fn rejects_atoms_larger_than_the_file() {
+        let mut data = file(&[], &[]);
+        data[0..4].copy_from_slice(&u32::MAX.to_be_bytes());
+        assert!(matches!(read(data), Err(Error::InvalidAtom)));
+
+        let mut data = file(&[], &[]);
+        data[0..4].copy_from_slice(&1u32.to_be_bytes());
+        data.splice(8..8, u64::MAX.to_be_bytes());
+        assert!(matches!(read(data), Err(Error::InvalidAtom)));
+    }
+
+    #[test]
+    fn ignores_child_sizes_past_the_end() {
+        let data = [
+            &u32::MAX.to_be_bytes()[..],
+            b"udta",
+            &1u32.to_be_bytes(),
+            b"chpl",
+            &u64::MAX.to_be_bytes(),
+        ]
+        .concat();
+        assert_eq!(children(&data).count(), 0);
+        assert_eq!(children(&data[8..]).count(), 0);
+    }
+
+    #[test]
+    fn bounds_sample_counts_by_the_data() {
+        // billions of one byte samples, only as many as the chunks hold are read
+        let data = chapter_file(
+            full_atom(b"stsz", &[0, 1, u32::MAX]),
+            full_atom(b"stts", &[0, 1, u32::MAX, u32::MAX]),
+        );
+        assert_eq!(read(data).unwrap().chapters.len(), 2);
+
+        // a table claiming more sizes than it holds only yields the ones it has
+        let data = chapter_file(
+            full_atom(b"stsz", &[0, 0, u32::MAX, 7, 6]),
+            full_atom(b"stts", &[0, 1, u32::MAX, u32::MAX]),
+        );
+        let file = read(data).unwrap();
+        assert_eq!(file.chapters.len(), 2);
+        assert_eq!(file.chapters[1].start, u32::MAX as u64);
+    }
+}
@@ -0,0 +1,308 @@
+mod error;
+
+pub use error::*;
+
+use std::{
+    collections::HashMap,
+    env, fs,
+    io::{self, Write},
+    path::{self, PathBuf},
+    process,
+    sync::Arc,
+    thread,
+    time::{Duration, Instant},
+};
+
+use serde::Serialize;
+use tokio::runtime::{self, Runtime};
+
+use crate::{
+    plex,
+    state::{self, AppSettings, Book, Bookmark, Books, DownloadProgress, FileStore},
+};
+
+const USAGE: &str = "usage: project-book-cli [--store <path>] [--downloads <dir>] <command>
+
+commands:
+    signin                  sign in to plex with a pin
+    signout
+    servers [name]          list servers, or select one
+    libraries [name]        list libraries on the selected server, or select one
+    books                   list books in the selected library
+    download <key>...       download books by key
+    export-progress [path]  write progress and bookmarks as json, to stdout without a path
+
+the store defaults to `store.bin` in the working directory, the app's store can be used as is";
+
+const PIN_CHECK: Duration = Duration::from_secs(2);
+// plex pins are only good for a while
+const PIN_TIMEOUT: Duration = Duration::from_secs(15 * 60);
+
+/// Entry point for the `project-book-cli` binary
+pub fn run_cli() {
+    let args = env::args().skip(1).collect::<Vec<_>>();
+    if let Err(err) = run(args) {
+        eprintln!("error: {err}");
+        if matches!(err, Error::UnknownCommand | Error::MissingArgument) {
+            eprintln!("\n{USAGE}");
+        }
+        process::exit(1);
+    }
+}
+
+fn run(args: Vec<String>) -> Result<()> {
+    let mut store = PathBuf::from(state::BIN);
+    let mut download_dir = None;
+    let mut command = Vec::new();
+
+    let mut args = args.into_iter();
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "--store" => store = args.next().ok_or(Error::MissingArgument)?.into(),
+            "--downloads" => download_dir = Some(args.next().ok_or(Error::MissingArgument)?.into()),
+            "-h" | "--help" | "help" => {
+                println!("{USAGE}");
+                return Ok(());
+            }
+            _ => command.push(arg),
+        }
+    }
+    // books remember where they were downloaded, so that can't depend on the working directory
+    let store = path::absolute(store)?;
+    // downloads sit next to the store like they do for the app
+    let download_dir = path::absolute(
+        download_dir.unwrap_or_else(|| store.parent().unwrap_or(&store).join(state::DOWNLOAD_DIR)),
+    )?;
+
+    let (command, args) = command.split_first().ok_or(Error::MissingArgument)?;
+    let mut cli = Cli::open(store, download_dir)?;
+    match command.as_str() {
+        "signin" => cli.signin(),
+        "signout" => cli.signout(),
+        "servers" => cli.servers(args.first()),
+        "libraries" => cli.libraries(args.first()),
+        "books" => cli.books(),
+        "download" => cli.download(args),
+        "export-progress" => cli.export_progress(args.first()),
+        _ => Err(Error::UnknownCommand),
+    }
+}
+
+/// The parts of the app state the cli needs, saved to a `FileStore`
+struct Cli {
+    runtime: Runtime,
+    store: FileStore,
+    settings: AppSettings,
+    books: HashMap<Arc<str>, Book>,
+    download_dir: PathBuf,
+}
+
+impl Cli {
+    fn open(store: PathBuf, download_dir: PathBuf) -> Result<Self> {
+        // plex is async, the cli just waits on it
+        let runtime = runtime::Builder::new_current_thread()
+            .enable_all()
+            .build()?;
+        let mut store = FileStore::open(store)?;
+        let mut settings = AppSettings::from_store(&mut store);
+        runtime.block_on(settings.plex.refresh())?;
+        // plex may have moved to another connection
+        settings.save(&mut store);
+        let books = Book::get_all_books(&mut store, &download_dir);
+        let keys = books.keys().map(|key| key.as_ref()).collect::<Vec<_>>();
+        // progress can still be exported without the titles
+        runtime.block_on(settings.plex.load_albums(&keys)).ok();
+
+        Ok(Self {
+            runtime,
+            store,
+            settings,
+            books,
+            download_dir,
+        })
+    }
+
+    fn signin(&mut self) -> Result<()> {
+        let pin = self
+            .runtime
+            .block_on(self.settings.plex.create_login_pin())?;
+        println!(
+            "use the pin {} at https://www.plex.tv/link/?pin={}",
+            pin.pin_ref(),
+            pin.pin_ref()
+        );
+
+        let started = Instant::now();
+        loop {
+            thread::sleep(PIN_CHECK);
+            match self.runtime.block_on(self.settings.plex.check_pin(&pin)) {
+                Ok(_) => break,
+                Err(plex::Error::WaitingOnPin) if started.elapsed() < PIN_TIMEOUT => continue,
+                Err(plex::Error::WaitingOnPin) => return Err(Error::PinExpired),
+                Err(err) => return Err(err.into()),
+            }
+        }
+        self.settings.save(&mut self.store);
+        println!("signed in");
+
+        Ok(())
+    }
+
+    fn signout(&mut self) -> Result<()> {
+        self.settings.plex.signout()?;
+        self.settings.save(&mut self.store);
+        println!("signed out");
+
+        Ok(())
+    }
+
+    fn servers(&mut self, select: Option<&String>) -> Result<()> {
+        let plex = &mut self.settings.plex;
+        if let Some(server) = select {
+            plex.reset_library_selection();
+            self.runtime.block_on(plex.select_server(server))?;
+            self.settings.save(&mut self.store);
+            println!("selected {server}");
+            return Ok(());
+        }
+
+        let selected = plex.get_selected_server();
+        let mut servers = plex.get_servers();
+        servers.sort();
+        for server in servers.iter() {
+            let marker = if selected == Some(*server) { "*" } else { " " };
+            println!("{marker} {server}");
+        }
+
+        Ok(())
+    }
+
+    fn libraries(&mut self, select: Option<&String>) -> Result<()> {
+        let plex = &mut self.settings.plex;
+        if let Some(library) = select {
+            self.runtime.block_on(plex.select_library(library))?;
+            self.settings.save(&mut self.store);
+            println!("selected {library}");
+            return Ok(());
+        }
+
+        let selected = plex.get_selected_library();
+        let mut libraries = plex.get_libraries();
+        libraries.sort();
+        for library in libraries.iter() {
+            let marker = if selected == Some(*library) { "*" } else { " " };
+            println!("{marker} {library}");
+        }
+
+        Ok(())
+    }
+
+    fn books(&mut self) -> Result<()> {
+        self.runtime
+            .block_on(self.settings.plex.fetch_all_albums())?;
+        for album in self.settings.plex.get_albums().iter() {
+            let downloaded = self
+                .books
+                .get(album.key_ref())
+                .is_some_and(|book| book.is_downloaded());
+            let marker = if downloaded { "*" } else { " " };
+            println!(
+                "{marker} {}\t{}\t{}",
+                album.key_ref(),
+                album.parent_ref(),
+                album.title_ref()
+            );
+        }
+
+        Ok(())
+    }
+
+    fn download(&mut self, keys: &[String]) -> Result<()> {
+        if keys.is_empty() {
+            return Err(Error::MissingArgument);
+        }
+
+        let keys = keys.iter().map(String::as_str).collect::<Vec<_>>();
+        self.runtime
+            .block_on(self.settings.plex.load_albums(&keys))?;
+        for key in keys {
+            let album = self.settings.plex.get_album(key)?.key_clone();
+            let location = self.download_dir.join(album.as_ref());
+            let chapters = self.runtime.block_on(state::download_now(
+                &self.settings.plex,
+                album.clone(),
+                location.clone(),
+                &report,
+            ))?;
+            eprintln!();
+
+            let (book, _) = self.books.get_book_or_insert(album.clone())?;
+            book.set_downloaded(&location);
+            if !chapters.is_empty() {
+                book.chapters = chapters;
+            }
+            if book.playlist.is_empty() {
+                book.playlist = self
+                    .runtime
+                    .block_on(self.settings.plex.get_playlist(&album))
+                    .unwrap_or_default();
+            }
+            self.books.save(&mut self.store)?;
+            self.settings.save(&mut self.store);
+            println!("downloaded {album} to {location:?}");
+        }
+
+        Ok(())
+    }
+
+    fn export_progress(&self, path: Option<&String>) -> Result<()> {
+        let mut books = self
+            .books
+            .values()
+            .map(|book| {
+                let album = self.settings.plex.get_album(&book.album_key).ok();
+                let position = book.position();
+                BookProgress {
+                    key: &book.album_key,
+                    title: album.map(|album| album.title_ref()).unwrap_or_default(),
+                    author: album.map(|album| album.parent_ref()).unwrap_or_default(),
+                    chapter: book
+                        .chapter_at(position)
+                        .map(|chapter| chapter.title.as_ref())
+                        .unwrap_or_default(),
+                    position,
+                    duration: book.duration(),
+                    bookmarks: &book.bookmarks,
+                }
+            })
+            .collect::<Vec<_>>();
+        books.sort_by_key(|book| book.key);
+
+        let progress = serde_json::to_vec_pretty(&books)?;
+        match path {
+            Some(path) => fs::write(path, progress)?,
+            None => io::stdout().write_all(&progress)?,
+        }
+
+        Ok(())
+    }
+}
+
+/// Times are in milliseconds from the start of the book
+#[derive(Serialize)]
+struct BookProgress<'a> {
+    key: &'a str,
+    title: &'a str,
+    author: &'a str,
+    chapter: &'a str,
+    position: u64,
+    duration: u64,
+    bookmarks: &'a [Bookmark],
+}
+
+fn report(progress: &DownloadProgress) {
+    let percent = (progress.downloaded * 100)
+        .checked_div(progress.total)
+        .unwrap_or_default();
+    eprint!("\r{}: {percent}%", progress.key);
+}
@@ -0,0 +1,16 @@
+use derive_more::{Display, Error, From};
+
+use crate::{plex, state};
+
+pub type Result<T> = core::result::Result<T, Error>;
+
+#[derive(Debug, From, Error, Display)]
+pub enum Error {
+    Io(std::io::Error),
+    InvalidJson(serde_json::Error),
+    State(state::Error),
+    Plex(plex::Error),
+    UnknownCommand,
+    MissingArgument,
+    PinExpired,
+}
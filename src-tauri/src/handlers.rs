@@ -1,5 +1,7 @@
 mod commands;
 mod error;
+mod protocol;
 
 pub(crate) use commands::*;
 pub use error::*;
+pub(crate) use protocol::*;
@@ -1,4 +1,4 @@
-use std::sync::MutexGuard;
+use std::{mem, sync::MutexGuard, time::Duration};
 
 use askama::Template;
 use log::{debug, info, warn};
@@ -6,11 +6,18 @@ use rayon::iter::{IntoParallelRefIterator, ParallelIterator};
 use tauri::{AppHandle, Emitter, State};
 
 use crate::{
-    state::{AppSettings, AppState, Books, InnerAppState, ReadingState},
+    chapters::Chapter,
+    player::{PlaybackState, PlaylistItem, Processing, MAX_SPEED, MIN_SPEED},
+    plex::{self, Plex, ViewOffset},
+    state::{
+        AppSettings, AppState, Book, Bookmark, Books, InnerAppState, ReadingState, RemoteSettings,
+        SleepMode, SleepTimer, MAX_FADE_OUT, MAX_SKIP, MAX_SLEEP, SLEEP_TIMER_EVENT,
+        TOGGLE_PLAYING_EVENT, UPDATE_DOWNLOADED_EVENT,
+    },
     Error,
 };
 
-use super::Result;
+use super::{thumb_uri, Result};
 
 // might move templates to seperate rs file
 #[derive(Template)]
@@ -44,14 +51,18 @@ struct LibraryPaginationTemplate<'a> {
 }
 
 #[tauri::command]
-pub(crate) fn library_pagination(state: State<'_, AppState>, current: &str) -> Result<String> {
+pub(crate) async fn library_pagination(
+    state: State<'_, AppState>,
+    current: &str,
+) -> Result<String> {
     debug!("Requesting `library_pagination` at {current:?}");
     let current: usize = current.parse()?;
-    let state = state.lock()?;
     let page_size = 12; // more likely to fit evenly into the display
 
-    let albums = state.settings.plex.get_albums()?;
-    let albums = &albums[current..(current + page_size).min(albums.len())];
+    let mut plex = state.lock()?.settings.plex.clone();
+    plex.fetch_album_page(current, page_size).await?;
+    state.lock()?.adopt_plex(&plex);
+    let albums = plex.get_album_page(current, page_size);
 
     let next = if albums.len() == page_size {
         current + page_size
@@ -64,15 +75,13 @@ pub(crate) fn library_pagination(state: State<'_, AppState>, current: &str) -> R
             .par_iter()
             .map(|album| BookTemplate {
                 author: album.parent_ref(),
-                thumb: state
-                    .settings
-                    .plex
-                    .authenticated_thumb(album.thumb_ref())
-                    .unwrap_or_default(),
+                thumb: thumb_uri(album.thumb_ref()),
                 title: album.title_ref(),
                 key: album.key_ref(),
                 summary: album.summary_ref(),
                 downloaded: false, // todo check against downloaded books in state
+                chapters: Box::new([]),
+                remote: None,
             })
             .collect(),
         next,
@@ -90,30 +99,100 @@ struct BookTemplate<'a> {
     key: &'a str,
     summary: &'a str,
     downloaded: bool,
+    chapters: Box<[ChapterTemplate<'a>]>,
+    /// Where another device got to, when it is ahead of us
+    remote: Option<u64>,
+}
+
+struct ChapterTemplate<'a> {
+    title: &'a str,
+    start: u64,
+    duration: u64,
+}
+
+fn chapter_templates(chapters: &[Chapter]) -> Box<[ChapterTemplate<'_>]> {
+    chapters
+        .iter()
+        .map(|chapter| ChapterTemplate {
+            title: chapter.title.as_ref(),
+            start: chapter.start,
+            duration: chapter.duration(),
+        })
+        .collect()
+}
+
+mod filters {
+    /// Formats milliseconds as `h:mm:ss`
+    pub(super) fn duration(ms: &u64) -> askama::Result<String> {
+        let seconds = ms / 1000;
+        Ok(format!(
+            "{}:{:02}:{:02}",
+            seconds / 3600,
+            (seconds / 60) % 60,
+            seconds % 60
+        ))
+    }
+}
+
+/// Checks the servers position against ours, books we have never played just take it
+fn server_ahead(state: &mut InnerAppState, key: &str, view: Option<ViewOffset>) -> Option<u64> {
+    let view = view?;
+    let book = state.books.get_mut(key)?;
+
+    let progress = book.progress;
+    let remote = book
+        .reconcile_progress(&view)
+        .map(|position| book.position_of(&position));
+    if book.progress != progress {
+        state.save_book(key);
+    }
+
+    remote
 }
 
 #[tauri::command]
-pub(crate) fn book(state: State<'_, AppState>, key: &str) -> Result<String> {
+pub(crate) async fn book(state: State<'_, AppState>, key: &str) -> Result<String> {
     debug!("Requesting `book` at {key:?}");
-    let state = state.lock()?;
+    // plex is only asked once the state is unlocked
+    let (mut plex, opened, chapters) = {
+        let state = state.lock()?;
+        let book = state.books.get(key);
+        // never opened books don't need the server position, the player picks it up when they are
+        let opened = book.is_some_and(|book| !book.playlist.is_empty());
+        let chapters = book
+            .map(|book| book.chapters.clone())
+            .filter(|chapters| !chapters.is_empty());
+        (state.settings.plex.clone(), opened, chapters)
+    };
+
+    let view = if opened {
+        plex.get_view_offset(key).await.ok().flatten()
+    } else {
+        None
+    };
+    let chapters = match chapters {
+        Some(chapters) => chapters,
+        None => plex.get_chapters(key).await.unwrap_or_default(),
+    };
+
+    let mut state = state.lock()?;
+    state.adopt_plex(&plex);
+    let remote = server_ahead(&mut state, key, view);
     let album = state.settings.plex.get_album(key)?;
     let book = BookTemplate {
         author: album.parent_ref(),
-        thumb: state
-            .settings
-            .plex
-            .authenticated_thumb(album.thumb_ref())
-            .unwrap_or_default(),
+        thumb: thumb_uri(album.thumb_ref()),
         title: album.title_ref(),
         key: album.key_ref(),
         summary: album.summary_ref(),
         downloaded: false, // todo check against downloaded books in state
+        chapters: chapter_templates(&chapters),
+        remote,
     };
 
     Ok(book.render()?)
 }
 
-const UPDATE_DOWNLOADED_EVENT: &str = "update-downloaded";
 #[tauri::command]
 pub(crate) fn plex_download_book(
     state: State<'_, AppState>,
@@ -124,19 +203,59 @@ pub(crate) fn plex_download_book(
     let mut state = state.lock()?;
 
     let album = state.settings.plex.get_album(key)?.key_clone();
-    let new_book = state.books.download_book(album)?;
-
+    let (_, new_book) = state.books.get_book_or_insert(album.clone())?;
     if new_book {
         state.save_books();
-    } else {
-        state.save_book(key);
     }
 
+    state.downloads.enqueue(album);
+    state.save_downloads();
+
     app.emit(UPDATE_DOWNLOADED_EVENT, ())?;
 
     Ok(())
 }
 
+#[tauri::command]
+pub(crate) fn plex_cancel_download(
+    state: State<'_, AppState>,
+    key: &str,
+    app: AppHandle,
+) -> Result<()> {
+    debug!("Requesting `plex_cancel_download` at {key:?}");
+    let mut state = state.lock()?;
+
+    let location = state.download_dir.join(key);
+    state.downloads.cancel(key, &location);
+    state.save_downloads();
+
+    app.emit(UPDATE_DOWNLOADED_EVENT, ())?;
+
+    Ok(())
+}
+
+#[tauri::command]
+pub(crate) fn pause_downloads(state: State<'_, AppState>) -> Result<()> {
+    debug!("Requesting `pause_downloads`");
+    let mut state = state.lock()?;
+
+    state.downloads.pause();
+    state.save_downloads();
+
+    Ok(())
+}
+
+#[tauri::command]
+pub(crate) fn resume_downloads(state: State<'_, AppState>) -> Result<()> {
+    debug!("Requesting `resume_downloads`");
+    let mut state = state.lock()?;
+
+    state.downloads.resume();
+    state.save_downloads();
+
+    Ok(())
+}
+
 #[tauri::command]
 pub(crate) fn plex_delete_book(
     state: State<'_, AppState>,
@@ -159,68 +278,649 @@ pub(crate) fn plex_delete_book(
 struct PlayerTemplate<'a> {
     thumb: &'a str,
     title: &'a str,
+    progress: ProgressTemplate<'a>,
+    chapters: Box<[ChapterTemplate<'a>]>,
+    playing: bool,
+    speeds: Box<[SpeedTemplate]>,
+    processing: Processing,
 }
 
 const UPDATE_PLAYER_EVENT: &str = "update-player";
 
-fn create_player(mut state: MutexGuard<InnerAppState>, key: &str) -> Result<String> {
+/// Times in the player, all in milliseconds, `chapter_position` is from the start of the chapter
+#[derive(Template)]
+#[template(path = "player/progress.html")]
+struct ProgressTemplate<'a> {
+    position: u64,
+    duration: u64,
+    chapter: &'a str,
+    chapter_position: u64,
+    chapter_duration: u64,
+}
+
+impl<'a> ProgressTemplate<'a> {
+    /// Books without chapters are treated as one long chapter
+    fn new(book: &'a Book, position: u64) -> Self {
+        let duration = book.duration();
+        let (chapter, start, end) = book
+            .chapter_at(position)
+            .map(|chapter| (chapter.title.as_ref(), chapter.start, chapter.end))
+            .unwrap_or(("", 0, duration));
+
+        Self {
+            position,
+            duration,
+            chapter,
+            chapter_position: position
+                .saturating_sub(start)
+                .min(end.saturating_sub(start)),
+            chapter_duration: end.saturating_sub(start),
+        }
+    }
+}
+
+/// Choices offered for playback speed, the player accepts anything between `MIN_SPEED` and `MAX_SPEED`
+const SPEEDS: [f64; 9] = [MIN_SPEED, 0.75, 1.0, 1.25, 1.5, 1.75, 2.0, 2.5, MAX_SPEED];
+
+struct SpeedTemplate {
+    value: f64,
+    selected: bool,
+}
+
+fn speed_templates(selected: f64) -> Box<[SpeedTemplate]> {
+    SPEEDS
+        .iter()
+        .map(|&value| SpeedTemplate {
+            value,
+            selected: value == selected,
+        })
+        .collect()
+}
+
+/// What a book needs from plex before it can be played, fetched while the state is unlocked
+struct ServerBook {
+    chapters: Option<Box<[Chapter]>>,
+    playlist: Option<Box<[PlaylistItem]>>,
+    view: plex::Result<Option<ViewOffset>>,
+}
+
+impl ServerBook {
+    /// Only asks for chapters and the playlist when `book` is missing them
+    fn needs(book: Option<&Book>) -> (bool, bool) {
+        let chapters = book.is_none_or(|book| book.chapters.is_empty());
+        let playlist =
+            book.is_none_or(|book| book.playlist.iter().all(|item| item.rating_key.is_empty()));
+
+        (chapters, playlist)
+    }
+
+    async fn fetch(plex: &mut Plex, key: &str, (chapters, playlist): (bool, bool)) -> Result<Self> {
+        let chapters = if chapters {
+            Some(plex.get_chapters(key).await.unwrap_or_default())
+        } else {
+            None
+        };
+        let playlist = if playlist {
+            Some(plex.get_playlist(key).await?)
+        } else {
+            None
+        };
+
+        Ok(Self {
+            chapters,
+            playlist,
+            view: plex.get_view_offset(key).await,
+        })
+    }
+}
+
+/// Opens `key` in the player, at `start` milliseconds into the book when given rather than
+/// wherever it was left
+fn create_player(
+    mut state: MutexGuard<InnerAppState>,
+    key: &str,
+    server: ServerBook,
+    start: Option<u64>,
+) -> Result<String> {
+    let state = &mut *state;
     let album = state.settings.plex.get_album(key)?.key_clone();
     state.current_book = Some(album.clone());
     let (book, new_book) = state.books.get_book_or_insert(album)?;
     book.state = ReadingState::Playing;
+    if let Some(chapters) = server.chapters {
+        book.chapters = chapters;
+    }
+    if let Some(playlist) = server.playlist {
+        book.playlist = playlist;
+    }
+    if let Ok(Some(view)) = server.view {
+        book.reconcile_progress(&view);
+    }
+    // only lands on the right track once the playlist is there
+    if let Some(start) = start {
+        let progress = book.track_position_at(start);
+        book.set_progress(progress);
+    }
+
+    state
+        .player
+        .load(book.player_tracks(&state.settings.plex)?, book.progress)?;
+    state
+        .player
+        .set_speed(book.speed.unwrap_or(state.settings.playback_speed))?;
+    state.player.set_processing(book.processing)?;
+    state.player.play()?;
 
     state.save_current_book();
     if new_book {
         state.save_books();
+    } else {
+        state.save_book(key);
     }
 
+    render_player(state, key, true)
+}
+
+fn render_player(state: &InnerAppState, key: &str, playing: bool) -> Result<String> {
+    let book = state
+        .books
+        .get(key)
+        .ok_or(crate::state::Error::NoBookFound)?;
     let album = state.settings.plex.get_album(key)?;
-    let book = PlayerTemplate {
-        thumb: &state
-            .settings
-            .plex
-            .authenticated_thumb(album.thumb_ref())
-            .unwrap_or_default(),
+    let player = PlayerTemplate {
+        thumb: &thumb_uri(album.thumb_ref()),
         title: album.title_ref(),
+        // the player may not have caught up with a book that was just loaded
+        progress: ProgressTemplate::new(book, book.position()),
+        chapters: chapter_templates(&book.chapters),
+        playing,
+        speeds: speed_templates(book.speed.unwrap_or(state.settings.playback_speed)),
+        processing: book.processing,
     };
 
-    Ok(book.render()?)
+    Ok(player.render()?)
+}
+
+/// The player for whatever was open when the app was last closed, already loaded by `setup_state`
+#[tauri::command]
+pub(crate) fn load_current(state: State<'_, AppState>) -> Result<String> {
+    debug!("Requesting `load_current`");
+    let state = state.lock()?;
+
+    match &state.current_book {
+        Some(current) => render_player(&state, current, false),
+        None => Ok(String::new()),
+    }
 }
 
 #[tauri::command]
-pub(crate) fn start_playing(
+pub(crate) async fn start_playing(
     state: State<'_, AppState>,
     app: AppHandle,
     key: &str,
     chapter: Option<&str>,
     _paused: Option<&str>,
+    from_server: Option<&str>,
 ) -> Result<String> {
     debug!("Requesting `book` at {key:?}");
-    let mut state = state.lock()?;
     let _chapter = chapter.unwrap_or("0");
+    let from_server = from_server.is_some();
 
-    if let Some(current) = &state.current_book.clone() {
-        debug!("Requesting `book` at {key:?}");
-        if current.as_ref() != key {
-            if let Some(old) = state.books.get_mut(current) {
-                old.state = ReadingState::Paused; // maybe should be something like UnLoaded
-            }
-            app.emit(UPDATE_PLAYER_EVENT, ())?;
-            create_player(state, key)
-        } else {
-            if let Some(current) = state.books.get_mut(current) {
-                current.state = match current.state {
-                    ReadingState::Playing => ReadingState::Paused,
-                    _ => ReadingState::Playing,
-                };
+    let (mut plex, needs) = {
+        let mut state = state.lock()?;
+        if let Some(current) = &state.current_book.clone() {
+            debug!("Requesting `book` at {key:?}");
+            if current.as_ref() == key && !from_server {
+                let playing = state
+                    .books
+                    .get(current)
+                    .is_some_and(|book| matches!(book.state, ReadingState::Playing));
+                if playing {
+                    state.player.pause()?;
+                    set_current_state(&mut state, ReadingState::Paused);
+                    state.checkpoint();
+                } else {
+                    state.player.play()?;
+                    set_current_state(&mut state, ReadingState::Playing);
+                    state.extend_sleep_timer();
+                }
+                app.emit(TOGGLE_PLAYING_EVENT, ())?;
+
+                return Err(Error::NoChange); // unsure on if this should be Error or just an empty string...
             }
-            app.emit("toggle-playing", ())?;
 
-            Err(Error::NoChange) // unsure on if this should be Error or just an empty string...
+            unload_current(&mut state, &app)?;
+        }
+
+        (
+            state.settings.plex.clone(),
+            ServerBook::needs(state.books.get(key)),
+        )
+    };
+    let mut server = ServerBook::fetch(&mut plex, key, needs).await?;
+
+    let mut state = state.lock()?;
+    state.adopt_plex(&plex);
+    if from_server {
+        let view = mem::replace(&mut server.view, Ok(None))?;
+        take_server_progress(&mut state, key, view);
+    }
+    create_player(state, key, server, None)
+}
+
+/// Saves where the current book got to and removes its player, before another book is loaded
+fn unload_current(state: &mut InnerAppState, app: &AppHandle) -> Result<()> {
+    state.checkpoint();
+    if let Some(current) = state.current_book.clone() {
+        if let Some(old) = state.books.get_mut(&current) {
+            old.state = ReadingState::Paused; // maybe should be something like UnLoaded
         }
-    } else {
-        create_player(state, key)
     }
+    app.emit(UPDATE_PLAYER_EVENT, ())?;
+
+    Ok(())
+}
+
+/// Moves the book to wherever the server has it, even if ours is more recent
+fn take_server_progress(state: &mut InnerAppState, key: &str, view: Option<ViewOffset>) {
+    if let (Some(view), Some(book)) = (view, state.books.get_mut(key)) {
+        if let Some(position) = book.server_progress(&view) {
+            info!("Resuming {key} from the server at {position:?}");
+            book.set_progress(position);
+        }
+    }
+}
+
+/// Sets the reading state of the current book to match what the player was told to do
+fn set_current_state(state: &mut InnerAppState, reading: ReadingState) {
+    if let Some(current) = state.current_book.clone() {
+        if let Some(book) = state.books.get_mut(&current) {
+            book.state = reading;
+        }
+    }
+}
+
+#[tauri::command]
+pub(crate) fn player_play(state: State<'_, AppState>) -> Result<()> {
+    debug!("Requesting `player_play`");
+    let mut state = state.lock()?;
+
+    state.player.play()?;
+    set_current_state(&mut state, ReadingState::Playing);
+    state.extend_sleep_timer();
+
+    Ok(())
+}
+
+#[tauri::command]
+pub(crate) fn player_pause(state: State<'_, AppState>) -> Result<()> {
+    debug!("Requesting `player_pause`");
+    let mut state = state.lock()?;
+
+    state.player.pause()?;
+    set_current_state(&mut state, ReadingState::Paused);
+    state.checkpoint();
+
+    Ok(())
+}
+
+#[tauri::command]
+pub(crate) fn player_seek(state: State<'_, AppState>, position: &str) -> Result<()> {
+    debug!("Requesting `player_seek` to {position:?}");
+    let position: u64 = position.parse()?;
+    let mut state = state.lock()?;
+
+    state.player.seek(position)?;
+    state.extend_sleep_timer();
+
+    Ok(())
+}
+
+#[tauri::command]
+pub(crate) fn player_stop(state: State<'_, AppState>) -> Result<()> {
+    debug!("Requesting `player_stop`");
+    let mut state = state.lock()?;
+
+    state.checkpoint();
+    state.player.stop()?;
+    set_current_state(&mut state, ReadingState::Paused);
+
+    Ok(())
+}
+
+#[tauri::command]
+pub(crate) fn player_rewind(state: State<'_, AppState>) -> Result<()> {
+    debug!("Requesting `player_rewind`");
+    let mut state = state.lock()?;
+
+    let position = state
+        .player
+        .position()
+        .saturating_sub(state.settings.skip.rewind.saturating_mul(1000));
+    state.player.seek(position)?;
+    state.extend_sleep_timer();
+
+    Ok(())
+}
+
+/// Seeking past the end finishes the book
+#[tauri::command]
+pub(crate) fn player_forward(state: State<'_, AppState>) -> Result<()> {
+    debug!("Requesting `player_forward`");
+    let mut state = state.lock()?;
+
+    let position = state
+        .player
+        .position()
+        .saturating_add(state.settings.skip.forward.saturating_mul(1000));
+    state.player.seek(position)?;
+    state.extend_sleep_timer();
+
+    Ok(())
+}
+
+#[tauri::command]
+pub(crate) fn player_previous_chapter(state: State<'_, AppState>) -> Result<()> {
+    debug!("Requesting `player_previous_chapter`");
+    let mut state = state.lock()?;
+
+    let position = state.player.position();
+    let start = state
+        .current_book
+        .as_ref()
+        .and_then(|current| state.books.get(current))
+        .ok_or(crate::state::Error::NoBookFound)?
+        .previous_chapter(position);
+    state.player.seek(start)?;
+    state.extend_sleep_timer();
+
+    Ok(())
+}
+
+#[tauri::command]
+pub(crate) fn player_next_chapter(state: State<'_, AppState>) -> Result<()> {
+    debug!("Requesting `player_next_chapter`");
+    let mut state = state.lock()?;
+
+    let position = state.player.position();
+    let next = state
+        .current_book
+        .as_ref()
+        .and_then(|current| state.books.get(current))
+        .ok_or(crate::state::Error::NoBookFound)?
+        .next_chapter(position)
+        .ok_or(Error::NoChange)?;
+    state.player.seek(next)?;
+    state.extend_sleep_timer();
+
+    Ok(())
+}
+
+/// Changes the speed of the current book, remembered for the next time it's played
+#[tauri::command]
+pub(crate) fn player_speed(state: State<'_, AppState>, speed: &str) -> Result<()> {
+    debug!("Requesting `player_speed` at {speed:?}");
+    let speed = speed.parse::<f64>()?.clamp(MIN_SPEED, MAX_SPEED);
+    let mut state = state.lock()?;
+
+    state.player.set_speed(speed)?;
+    if let Some(current) = state.current_book.clone() {
+        if let Some(book) = state.books.get_mut(&current) {
+            book.speed = Some(speed);
+        }
+        state.save_book(&current);
+    }
+    state.extend_sleep_timer();
+
+    Ok(())
+}
+
+/// Changes the processing of the current book and remembers it for next time
+fn update_processing(
+    state: &mut InnerAppState,
+    update: impl FnOnce(&mut Processing),
+) -> Result<()> {
+    let current = state
+        .current_book
+        .clone()
+        .ok_or(crate::state::Error::NoBookFound)?;
+    let book = state
+        .books
+        .get_mut(&current)
+        .ok_or(crate::state::Error::NoBookFound)?;
+    update(&mut book.processing);
+    state.player.set_processing(book.processing)?;
+    state.save_book(&current);
+
+    Ok(())
+}
+
+/// Checkboxes only send a value when ticked
+#[tauri::command]
+pub(crate) fn player_skip_silence(state: State<'_, AppState>, enabled: Option<&str>) -> Result<()> {
+    debug!("Requesting `player_skip_silence` at {enabled:?}");
+    let mut state = state.lock()?;
+
+    update_processing(&mut state, |processing| {
+        processing.skip_silence = enabled.is_some()
+    })
+}
+
+#[tauri::command]
+pub(crate) fn player_boost(state: State<'_, AppState>, enabled: Option<&str>) -> Result<()> {
+    debug!("Requesting `player_boost` at {enabled:?}");
+    let mut state = state.lock()?;
+
+    update_processing(&mut state, |processing| {
+        processing.boost = enabled.is_some()
+    })
+}
+
+/// Sets the sleep timer to `timer` minutes of listening, `chapter` for the end of the current
+/// chapter or `off` to cancel it
+#[tauri::command]
+pub(crate) fn player_sleep_timer(
+    state: State<'_, AppState>,
+    timer: &str,
+    app: AppHandle,
+) -> Result<()> {
+    debug!("Requesting `player_sleep_timer` at {timer:?}");
+    let mode = match timer {
+        "off" => None,
+        "chapter" => Some(SleepMode::EndOfChapter),
+        minutes => Some(SleepMode::Duration(sleep_duration(minutes)?)),
+    };
+    let mut state = state.lock()?;
+    let state = &mut *state;
+
+    let position = state.player.position();
+    let book = state
+        .current_book
+        .as_ref()
+        .and_then(|current| state.books.get(current));
+    state.sleep_timer = mode
+        .zip(book)
+        .map(|(mode, book)| SleepTimer::new(mode, book, position));
+    if state.sleep_timer.is_none() {
+        state.player.set_volume(1.0)?;
+        app.emit(SLEEP_TIMER_EVENT, None::<u64>)?;
+    }
+
+    Ok(())
+}
+
+/// Minutes of listening for the sleep timer, at least one and at most `MAX_SLEEP`
+fn sleep_duration(minutes: &str) -> Result<Duration> {
+    let minutes: u64 = minutes.parse()?;
+    if !(1..=MAX_SLEEP).contains(&minutes) {
+        return Err(Error::SleepOutOfRange);
+    }
+
+    Ok(Duration::from_secs(minutes * 60))
+}
+
+/// Just the times of the player, for polling while it plays
+#[tauri::command]
+pub(crate) fn player_progress(state: State<'_, AppState>) -> Result<String> {
+    let state = state.lock()?;
+
+    let book = state
+        .current_book
+        .as_ref()
+        .and_then(|current| state.books.get(current))
+        .ok_or(crate::state::Error::NoBookFound)?;
+    let position = match state.player.state() {
+        PlaybackState::Stopped => book.position(),
+        _ => state.player.position(),
+    };
+
+    Ok(ProgressTemplate::new(book, position).render()?)
+}
+
+/// Milliseconds into the current book
+#[tauri::command]
+pub(crate) fn player_position(state: State<'_, AppState>) -> Result<u64> {
+    let state = state.lock()?;
+
+    Ok(state.player.position())
+}
+
+const UPDATE_BOOKMARKS_EVENT: &str = "update-bookmarks";
+
+#[derive(Template)]
+#[template(path = "library/bookmarks.html")]
+struct BookmarksTemplate<'a> {
+    key: &'a str,
+    bookmarks: &'a [Bookmark],
+}
+
+#[tauri::command]
+pub(crate) fn bookmarks(state: State<'_, AppState>, key: &str) -> Result<String> {
+    debug!("Requesting `bookmarks` at {key:?}");
+    let state = state.lock()?;
+
+    let bookmarks = state
+        .books
+        .get(key)
+        .map(|book| book.bookmarks.as_slice())
+        .unwrap_or_default();
+
+    Ok(BookmarksTemplate { key, bookmarks }.render()?)
+}
+
+/// Bookmarks where the player is in the current book
+#[tauri::command]
+pub(crate) fn add_bookmark(
+    state: State<'_, AppState>,
+    note: Option<&str>,
+    app: AppHandle,
+) -> Result<()> {
+    debug!("Requesting `add_bookmark`");
+    let mut state = state.lock()?;
+
+    let position = state.player.position();
+    let current = state
+        .current_book
+        .clone()
+        .ok_or(crate::state::Error::NoBookFound)?;
+    let book = state
+        .books
+        .get_mut(&current)
+        .ok_or(crate::state::Error::NoBookFound)?;
+    let id = book.add_bookmark(position, note);
+    info!("Bookmarked {current} at {position} as {id}");
+    state.save_book(&current);
+
+    app.emit(UPDATE_BOOKMARKS_EVENT, ())?;
+
+    Ok(())
+}
+
+#[tauri::command]
+pub(crate) fn edit_bookmark(
+    state: State<'_, AppState>,
+    key: &str,
+    id: &str,
+    note: &str,
+) -> Result<()> {
+    debug!("Requesting `edit_bookmark` at {key:?} {id:?}");
+    let id: u64 = id.parse()?;
+    let mut state = state.lock()?;
+
+    state
+        .books
+        .get_mut(key)
+        .and_then(|book| book.edit_bookmark(id, note))
+        .ok_or(crate::state::Error::NoBookmarkFound)?;
+    state.save_book(key);
+
+    Ok(())
+}
+
+#[tauri::command]
+pub(crate) fn delete_bookmark(
+    state: State<'_, AppState>,
+    key: &str,
+    id: &str,
+    app: AppHandle,
+) -> Result<()> {
+    debug!("Requesting `delete_bookmark` at {key:?} {id:?}");
+    let id: u64 = id.parse()?;
+    let mut state = state.lock()?;
+
+    state
+        .books
+        .get_mut(key)
+        .and_then(|book| book.remove_bookmark(id))
+        .ok_or(crate::state::Error::NoBookmarkFound)?;
+    state.save_book(key);
+
+    app.emit(UPDATE_BOOKMARKS_EVENT, ())?;
+
+    Ok(())
+}
+
+/// Seeks to the bookmark, loading its book first when something else is playing
+#[tauri::command]
+pub(crate) async fn jump_to_bookmark(
+    state: State<'_, AppState>,
+    key: &str,
+    id: &str,
+    app: AppHandle,
+) -> Result<String> {
+    debug!("Requesting `jump_to_bookmark` at {key:?} {id:?}");
+    let id: u64 = id.parse()?;
+    let (mut plex, needs, position) = {
+        let mut state = state.lock()?;
+
+        let position = state
+            .books
+            .get(key)
+            .and_then(|book| book.bookmark(id))
+            .ok_or(crate::state::Error::NoBookmarkFound)?
+            .position;
+
+        if state.current_book.as_deref() == Some(key) {
+            state.player.seek(position)?;
+            state.extend_sleep_timer();
+            return Ok(String::new()); // the player is already open
+        }
+
+        if state.current_book.is_some() {
+            unload_current(&mut state, &app)?;
+        }
+
+        (
+            state.settings.plex.clone(),
+            ServerBook::needs(state.books.get(key)),
+            position,
+        )
+    };
+    let server = ServerBook::fetch(&mut plex, key, needs).await?;
+
+    let mut state = state.lock()?;
+    state.adopt_plex(&plex);
+    create_player(state, key, server, Some(position))
 }
 
 const UPDATE_SETTINGS_EVENT: &str = "update-settings";
@@ -251,6 +951,182 @@ pub(crate) fn settings_state(state: State<'_, AppState>) -> Result<String> {
     Ok(state.render()?)
 }
 
+#[derive(Template)]
+#[template(path = "settings/playback.html")]
+struct PlaybackSettingsTemplate {
+    speeds: Box<[SpeedTemplate]>,
+    fade_out: u64,
+    max_fade_out: u64,
+    extend_on_activity: bool,
+    rewind: u64,
+    forward: u64,
+    max_skip: u64,
+}
+
+#[tauri::command]
+pub(crate) fn playback_settings(state: State<'_, AppState>) -> Result<String> {
+    debug!("Requesting `playback_settings`");
+    let state = state.lock()?;
+
+    Ok(PlaybackSettingsTemplate {
+        speeds: speed_templates(state.settings.playback_speed),
+        fade_out: state.settings.sleep.fade_out,
+        max_fade_out: MAX_FADE_OUT,
+        extend_on_activity: state.settings.sleep.extend_on_activity,
+        rewind: state.settings.skip.rewind,
+        forward: state.settings.skip.forward,
+        max_skip: MAX_SKIP,
+    }
+    .render()?)
+}
+
+/// Sets the speed used by books that haven't had their own picked
+#[tauri::command]
+pub(crate) fn update_playback_speed(state: State<'_, AppState>, speed: &str) -> Result<()> {
+    debug!("Requesting `update_playback_speed` at {speed:?}");
+    let speed = speed.parse::<f64>()?.clamp(MIN_SPEED, MAX_SPEED);
+    let mut state = state.lock()?;
+
+    state.settings.playback_speed = speed;
+    let follows_default = state
+        .current_book
+        .as_ref()
+        .and_then(|current| state.books.get(current))
+        .is_some_and(|book| book.speed.is_none());
+    if follows_default {
+        state.player.set_speed(speed)?;
+    }
+    state.save_settings();
+
+    Ok(())
+}
+
+/// Seconds the rewind button goes back
+#[tauri::command]
+pub(crate) fn update_rewind_interval(state: State<'_, AppState>, rewind: &str) -> Result<()> {
+    debug!("Requesting `update_rewind_interval` at {rewind:?}");
+    let rewind = skip_interval(rewind)?;
+    let mut state = state.lock()?;
+
+    state.settings.skip.rewind = rewind;
+    state.save_settings();
+
+    Ok(())
+}
+
+/// Seconds the forward button skips
+#[tauri::command]
+pub(crate) fn update_forward_interval(state: State<'_, AppState>, forward: &str) -> Result<()> {
+    debug!("Requesting `update_forward_interval` at {forward:?}");
+    let forward = skip_interval(forward)?;
+    let mut state = state.lock()?;
+
+    state.settings.skip.forward = forward;
+    state.save_settings();
+
+    Ok(())
+}
+
+/// Seconds for the rewind or forward button, at least one and at most `MAX_SKIP`
+fn skip_interval(seconds: &str) -> Result<u64> {
+    let seconds = seconds.parse()?;
+    if !(1..=MAX_SKIP).contains(&seconds) {
+        return Err(Error::SkipOutOfRange);
+    }
+
+    Ok(seconds)
+}
+
+/// Seconds the sleep timer spends fading out before it pauses
+#[tauri::command]
+pub(crate) fn update_sleep_fade_out(state: State<'_, AppState>, fade_out: &str) -> Result<()> {
+    debug!("Requesting `update_sleep_fade_out` at {fade_out:?}");
+    let fade_out = fade_out.parse()?;
+    if fade_out > MAX_FADE_OUT {
+        return Err(Error::SleepOutOfRange);
+    }
+    let mut state = state.lock()?;
+
+    state.settings.sleep.fade_out = fade_out;
+    state.save_settings();
+
+    Ok(())
+}
+
+/// Checkboxes only send a value when ticked
+#[tauri::command]
+pub(crate) fn update_sleep_extend(state: State<'_, AppState>, extend: Option<&str>) -> Result<()> {
+    debug!("Requesting `update_sleep_extend` at {extend:?}");
+    let mut state = state.lock()?;
+
+    state.settings.sleep.extend_on_activity = extend.is_some();
+    state.save_settings();
+
+    Ok(())
+}
+
+#[derive(Template)]
+#[template(path = "settings/remote.html")]
+struct RemoteSettingsTemplate<'a> {
+    settings: &'a RemoteSettings,
+    running: bool,
+}
+
+#[tauri::command]
+pub(crate) fn remote_settings(state: State<'_, AppState>) -> Result<String> {
+    debug!("Requesting `remote_settings`");
+    let state = state.lock()?;
+
+    Ok(RemoteSettingsTemplate {
+        settings: &state.settings.remote,
+        running: state.remote.is_some(),
+    }
+    .render()?)
+}
+
+/// Sent by the whole form whenever any of it changes, the server is restarted to match
+#[tauri::command]
+pub(crate) fn update_remote(
+    state: State<'_, AppState>,
+    enabled: Option<&str>,
+    address: &str,
+    port: &str,
+    app: AppHandle,
+) -> Result<String> {
+    debug!("Requesting `update_remote` at {address:?} {port:?}");
+    let port = port.parse()?;
+    let mut state = state.lock()?;
+
+    let remote = &mut state.settings.remote;
+    remote.enabled = enabled.is_some();
+    remote.address = address.into();
+    remote.port = port;
+    state.save_settings();
+    state.restart_remote(app);
+
+    Ok(RemoteSettingsTemplate {
+        settings: &state.settings.remote,
+        running: state.remote.is_some(),
+    }
+    .render()?)
+}
+
+/// Anything using the old token has to be given the new one
+#[tauri::command]
+pub(crate) fn regenerate_remote_token(state: State<'_, AppState>) -> Result<String> {
+    debug!("Requesting `regenerate_remote_token`");
+    let mut state = state.lock()?;
+
+    state.settings.remote.token = RemoteSettings::new_token();
+    state.save_settings();
+
+    Ok(RemoteSettingsTemplate {
+        settings: &state.settings.remote,
+        running: state.remote.is_some(),
+    }
+    .render()?)
+}
+
 #[derive(Template)]
 #[template(path = "settings/plex/pin.html")]
 struct PinTemplate<'a> {
@@ -266,44 +1142,50 @@ struct PlexSignedInTemplate;
 struct PlexSignedOutTemplate;
 
 #[tauri::command]
-pub(crate) fn plex_signin(state: State<'_, AppState>) -> Result<String> {
+pub(crate) async fn plex_signin(state: State<'_, AppState>) -> Result<String> {
     debug!("Requesting `plex_signin`");
-    let mut state = state.lock()?;
-    let pin = state.settings.plex.create_login_pin()?;
+    let plex = state.lock()?.settings.plex.clone();
+    let pin = plex.create_login_pin().await?;
     let pin_html = PinTemplate { pin: pin.pin_ref() };
     let pin_html = pin_html.render()?;
-    state.plex_pin = Some(pin); // todo
+    state.lock()?.plex_pin = Some(pin); // todo
     Ok(pin_html)
 }
 
 #[tauri::command]
-pub(crate) fn plex_check(state: State<'_, AppState>, app: AppHandle) -> Result<String> {
+pub(crate) async fn plex_check(state: State<'_, AppState>, app: AppHandle) -> Result<String> {
     debug!("Requesting `plex_check`");
+    let (mut plex, pin) = {
+        let state = state.lock()?;
+        (state.settings.plex.clone(), state.plex_pin.clone())
+    };
+    let Some(pin) = pin else {
+        info!("Plex signin unsuccessful");
+        return Ok(PlexSignedOutTemplate.render()?);
+    };
+    let checked = plex.check_pin(&pin).await;
+
     let mut state = state.lock()?;
-    let plex = if let Some(pin) = state.plex_pin.clone() {
-        match state.settings.plex.check_pin(&pin) {
-            Ok(_) => {
-                info!("Plex signin successful");
-                state.save_settings();
-                app.emit(UPDATE_SETTINGS_EVENT, ())?; // move this to state/settings struct?
-                PlexSignedInTemplate.render()
-            }
-            Err(crate::plex::Error::WaitingOnPin) => {
-                debug!("Waiting for plex pin complete or retry");
-                PinTemplate { pin: pin.pin_ref() }.render()
-            }
-            Err(_) => {
-                warn!("Plex pin unsuccessful");
-                state.plex_pin = None;
-                PlexSignedOutTemplate.render()
-            }
+    let html = match checked {
+        Ok(_) => {
+            info!("Plex signin successful");
+            state.settings.plex.adopt_signin(&plex);
+            state.save_settings();
+            app.emit(UPDATE_SETTINGS_EVENT, ())?; // move this to state/settings struct?
+            PlexSignedInTemplate.render()
+        }
+        Err(plex::Error::WaitingOnPin) => {
+            debug!("Waiting for plex pin complete or retry");
+            PinTemplate { pin: pin.pin_ref() }.render()
+        }
+        Err(_) => {
+            warn!("Plex pin unsuccessful");
+            state.plex_pin = None;
+            PlexSignedOutTemplate.render()
         }
-    } else {
-        info!("Plex signin unsuccessful");
-        PlexSignedOutTemplate.render()
     }?;
 
-    Ok(plex)
+    Ok(html)
 }
 
 #[tauri::command]
@@ -352,19 +1234,21 @@ pub(crate) fn plex_server(state: State<'_, AppState>) -> Result<String> {
 }
 
 #[tauri::command]
-pub(crate) fn plex_update_server(
+pub(crate) async fn plex_update_server(
     state: State<'_, AppState>,
     server: Option<&str>,
     app: AppHandle,
 ) -> Result<()> {
     debug!("Requesting `plex_update_server`");
-    let mut state = state.lock()?;
+    let mut plex = state.lock()?.settings.plex.clone();
 
     if let Some(server) = server {
-        state.settings.plex.select_server(server)?; // maybe should error handle on this
+        plex.select_server(server).await?; // maybe should error handle on this
     } else {
-        state.settings.plex.reset_server_selection();
+        plex.reset_server_selection();
     }
+    let mut state = state.lock()?;
+    state.settings.plex.adopt_server(&plex);
     state.save_settings();
     app.emit(UPDATE_SETTINGS_EVENT, ())?; // move this to state/settings struct?
 
@@ -392,19 +1276,21 @@ pub(crate) fn plex_library(state: State<'_, AppState>) -> Result<String> {
 }
 
 #[tauri::command]
-pub(crate) fn plex_update_library(
+pub(crate) async fn plex_update_library(
     state: State<'_, AppState>,
     library: Option<&str>,
     app: AppHandle,
 ) -> Result<()> {
     debug!("Requesting `plex_update_library`");
-    let mut state = state.lock()?;
+    let mut plex = state.lock()?.settings.plex.clone();
 
     if let Some(library) = library {
-        state.settings.plex.select_library(library)?; // maybe should error handle on this
+        plex.select_library(library).await?; // maybe should error handle on this
     } else {
-        state.settings.plex.reset_library_selection();
+        plex.reset_library_selection();
     }
+    let mut state = state.lock()?;
+    state.settings.plex.adopt_library(&plex);
     state.save_settings();
     app.emit(UPDATE_SETTINGS_EVENT, ())?; // move this to state/settings struct?
 
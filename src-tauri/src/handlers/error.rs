@@ -1,11 +1,14 @@
-use std::{num::ParseIntError, sync::PoisonError};
+use std::{
+    num::{ParseFloatError, ParseIntError},
+    sync::PoisonError,
+};
 
 use derive_more::{Display, Error, From};
 use log::error;
 use serde_json::json;
 use tauri::ipc::InvokeError;
 
-use crate::{plex, state};
+use crate::{player, plex, state};
 
 pub type Result<T> = core::result::Result<T, Error>;
 
@@ -13,11 +16,15 @@ pub type Result<T> = core::result::Result<T, Error>;
 pub enum Error {
     Plex(plex::Error),
     State(state::Error),
+    Player(player::Error),
     Template(askama::Error),
     Tauri(tauri::Error),
     InvalidNumber(ParseIntError),
+    InvalidDecimal(ParseFloatError),
     FailedToLockState,
     NoChange,
+    SkipOutOfRange,
+    SleepOutOfRange,
 }
 
 impl From<Error> for InvokeError {
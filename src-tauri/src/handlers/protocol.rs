@@ -0,0 +1,61 @@
+use log::{debug, warn};
+use tauri::{
+    async_runtime,
+    http::{header::CONTENT_TYPE, Request, Response, StatusCode},
+    AppHandle, Manager, UriSchemeResponder,
+};
+
+use crate::state::AppState;
+
+/// Album art is served through the app so server tokens never end up in the page
+pub(crate) const THUMB_PROTOCOL: &str = "thumb";
+
+/// Where the webview can load the thumb at `path` on the selected server from
+pub(crate) fn thumb_uri(path: &str) -> String {
+    if cfg!(any(windows, target_os = "android")) {
+        format!("http://{THUMB_PROTOCOL}.localhost{path}")
+    } else {
+        format!("{THUMB_PROTOCOL}://localhost{path}")
+    }
+}
+
+pub(crate) fn thumb_protocol(
+    app: &AppHandle,
+    request: Request<Vec<u8>>,
+    responder: UriSchemeResponder,
+) {
+    let path = request.uri().path().to_owned();
+    debug!("Requesting thumb `{path}`");
+    let plex = app
+        .try_state::<AppState>()
+        .and_then(|state| state.lock().ok().map(|state| state.settings.plex.clone()));
+
+    let app = app.clone();
+    async_runtime::spawn(async move {
+        let thumb = match plex {
+            Some(mut plex) => {
+                let thumb = plex.get_thumb(&path).await;
+                if let Ok(mut state) = app.state::<AppState>().lock() {
+                    state.adopt_plex(&plex);
+                }
+                thumb.map_err(|err| err.to_string())
+            }
+            None => Err("app state is not ready".into()),
+        };
+        let response = match thumb {
+            Ok(image) => Response::builder()
+                .header(CONTENT_TYPE, image.content_type.as_ref())
+                .body(image.data),
+            Err(err) => {
+                warn!("Failed to get thumb `{path}`: {err}");
+                Response::builder()
+                    .status(StatusCode::BAD_GATEWAY)
+                    .body(Vec::new())
+            }
+        };
+        match response {
+            Ok(response) => responder.respond(response),
+            Err(err) => warn!("Failed to build thumb response: {err}"),
+        }
+    });
+}
@@ -1,9 +1,20 @@
+pub(crate) mod chapters;
+mod cli;
+#[cfg(feature = "app")]
 mod handlers;
+#[cfg(all(feature = "app", target_os = "linux"))]
+pub(crate) mod mpris;
+pub(crate) mod player;
 pub(crate) mod plex;
+#[cfg(feature = "app")]
+pub(crate) mod remote;
 pub(crate) mod state;
 
+pub use cli::run_cli;
+#[cfg(feature = "app")]
 use handlers::*;
 
+#[cfg(feature = "app")]
 #[cfg_attr(mobile, tauri::mobile_entry_point)]
 pub fn run() {
     tauri::Builder::default()
@@ -17,16 +28,49 @@ pub fn run() {
         )
         .plugin(tauri_plugin_store::Builder::new().build())
         .plugin(tauri_plugin_shell::init())
+        .register_asynchronous_uri_scheme_protocol(THUMB_PROTOCOL, thumb_protocol)
         .invoke_handler(tauri::generate_handler![
             home,
             library,
             library_pagination,
             book,
             plex_download_book,
+            plex_cancel_download,
+            pause_downloads,
+            resume_downloads,
             plex_delete_book,
             start_playing,
+            load_current,
+            player_play,
+            player_pause,
+            player_seek,
+            player_stop,
+            player_rewind,
+            player_forward,
+            player_previous_chapter,
+            player_next_chapter,
+            player_speed,
+            player_sleep_timer,
+            player_skip_silence,
+            player_boost,
+            player_progress,
+            player_position,
+            bookmarks,
+            add_bookmark,
+            edit_bookmark,
+            delete_bookmark,
+            jump_to_bookmark,
             settings,
             settings_state,
+            playback_settings,
+            update_playback_speed,
+            update_rewind_interval,
+            update_forward_interval,
+            update_sleep_fade_out,
+            update_sleep_extend,
+            remote_settings,
+            update_remote,
+            regenerate_remote_token,
             plex_signin,
             plex_check,
             plex_signout,
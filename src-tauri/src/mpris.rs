@@ -0,0 +1,758 @@
+mod error;
+
+pub use error::*;
+
+use std::{
+    collections::HashMap,
+    fs,
+    path::PathBuf,
+    sync::Arc,
+    thread,
+    time::{Duration, Instant},
+};
+
+use log::{debug, info, warn};
+use tauri::{async_runtime, AppHandle, Emitter, Manager, Url};
+use zbus::{
+    blocking::{connection::Builder, Connection},
+    fdo, interface,
+    zvariant::{ObjectPath, OwnedValue, Value},
+};
+
+use crate::{
+    handlers,
+    player::{PlaybackState, MAX_SPEED, MIN_SPEED},
+    state::{AppState, TOGGLE_PLAYING_EVENT},
+};
+
+const BUS_NAME: &str = "org.mpris.MediaPlayer2.project_book";
+const OBJECT_PATH: &str = "/org/mpris/MediaPlayer2";
+const TRACK_PATH: &str = "/org/mpris/MediaPlayer2/book";
+const NO_TRACK: &str = "/org/mpris/MediaPlayer2/TrackList/NoTrack";
+// covers are kept here under the app cache
+const ART_DIR: &str = "mpris";
+
+const POLL_INTERVAL: Duration = Duration::from_secs(1);
+// the position moving this many milliseconds away from where playing would have taken it is a seek
+const SEEK_TOLERANCE: u64 = 2_000;
+
+/// Serves the player on the session bus so media keys and desktop widgets can control it
+pub(crate) fn spawn_worker(app: AppHandle) {
+    thread::spawn(move || {
+        let connection = match Builder::session()
+            .map_err(Error::from)
+            .and_then(|builder| connect(Arc::new(AppControl { app: app.clone() }), builder))
+        {
+            Ok(connection) => connection,
+            Err(err) => {
+                warn!("Unable to start mpris: {err}");
+                return;
+            }
+        };
+        info!("Serving mpris as {BUS_NAME}");
+
+        let mut last = Snapshot::default();
+        loop {
+            thread::sleep(POLL_INTERVAL);
+            if let Err(err) = notify(&connection, &app, &mut last) {
+                warn!("Stopping mpris: {err}");
+                break;
+            }
+        }
+    });
+}
+
+/// Claims the mpris name on whichever bus `builder` is for, a private bus works as well as the
+/// session one
+pub(crate) fn connect(control: Arc<dyn Control>, builder: Builder<'_>) -> Result<Connection> {
+    Ok(builder
+        .name(BUS_NAME)?
+        .serve_at(
+            OBJECT_PATH,
+            MediaPlayer {
+                control: control.clone(),
+            },
+        )?
+        .serve_at(OBJECT_PATH, MediaPlayerPlayer { control })?
+        .build()?)
+}
+
+/// Everything the bus can see of and do to the player
+pub(crate) trait Control: Send + Sync {
+    /// Name desktops show for the player
+    fn identity(&self) -> String;
+    fn raise(&self);
+    fn playback(&self) -> PlaybackState;
+    /// Milliseconds into the book
+    fn position(&self) -> u64;
+    fn speed(&self) -> f64;
+    fn track(&self) -> Option<Track>;
+    fn play(&self) -> handlers::Result<()>;
+    fn pause(&self) -> handlers::Result<()>;
+    fn stop(&self) -> handlers::Result<()>;
+    fn seek(&self, position: u64) -> handlers::Result<()>;
+    fn set_speed(&self, speed: f64) -> handlers::Result<()>;
+    fn next_chapter(&self) -> handlers::Result<()>;
+    fn previous_chapter(&self) -> handlers::Result<()>;
+    /// Tells the player in the app that the button it shows is out of date, the engine may not
+    /// have caught up yet so this goes by what it was told to do
+    fn toggled(&self);
+}
+
+/// The loaded book, the only track there is
+pub(crate) struct Track {
+    pub(crate) key: Arc<str>,
+    pub(crate) duration: Option<u64>,
+    pub(crate) title: Option<Arc<str>>,
+    pub(crate) artist: Option<Arc<str>>,
+    /// A local copy of the cover, plex urls carry the server token so are never handed out
+    pub(crate) art: Option<PathBuf>,
+}
+
+/// The app behind the bus
+struct AppControl {
+    app: AppHandle,
+}
+
+impl Control for AppControl {
+    fn identity(&self) -> String {
+        self.app.package_info().name.clone()
+    }
+
+    fn raise(&self) {
+        if let Some(window) = self.app.webview_windows().values().next() {
+            window.set_focus().ok();
+        }
+    }
+
+    fn playback(&self) -> PlaybackState {
+        let state = self.app.state::<AppState>();
+        let state = state.lock();
+        state
+            .map(|state| state.player.state())
+            .unwrap_or(PlaybackState::Stopped)
+    }
+
+    fn position(&self) -> u64 {
+        let state = self.app.state::<AppState>();
+        let state = state.lock();
+        state
+            .map(|state| state.player.position())
+            .unwrap_or_default()
+    }
+
+    fn speed(&self) -> f64 {
+        let state = self.app.state::<AppState>();
+        let state = state.lock();
+        state.map(|state| state.speed()).unwrap_or(1.0)
+    }
+
+    fn track(&self) -> Option<Track> {
+        let state = self.app.state::<AppState>();
+        let state = state.lock().ok()?;
+        let key = state.current_book.clone()?;
+        let album = state.settings.plex.get_album(&key).ok();
+
+        Some(Track {
+            duration: state.books.get(&key).map(|book| book.duration()),
+            title: album.map(|album| Arc::from(album.title_ref())),
+            artist: album.map(|album| Arc::from(album.parent_ref())),
+            art: art_path(&self.app, &key).filter(|path| path.exists()),
+            key,
+        })
+    }
+
+    fn play(&self) -> handlers::Result<()> {
+        handlers::player_play(self.app.state())
+    }
+
+    fn pause(&self) -> handlers::Result<()> {
+        handlers::player_pause(self.app.state())
+    }
+
+    fn stop(&self) -> handlers::Result<()> {
+        handlers::player_stop(self.app.state())
+    }
+
+    fn seek(&self, position: u64) -> handlers::Result<()> {
+        handlers::player_seek(self.app.state(), &position.to_string())
+    }
+
+    fn set_speed(&self, speed: f64) -> handlers::Result<()> {
+        handlers::player_speed(self.app.state(), &speed.to_string())
+    }
+
+    fn next_chapter(&self) -> handlers::Result<()> {
+        handlers::player_next_chapter(self.app.state())
+    }
+
+    fn previous_chapter(&self) -> handlers::Result<()> {
+        handlers::player_previous_chapter(self.app.state())
+    }
+
+    fn toggled(&self) {
+        self.app.emit(TOGGLE_PLAYING_EVENT, ()).ok();
+    }
+}
+
+/// What was last told to the bus
+#[derive(Default)]
+struct Snapshot {
+    playback: Option<PlaybackState>,
+    book: Option<Arc<str>>,
+    speed: f64,
+    position: u64,
+    polled: Option<Instant>,
+}
+
+/// Sends out changes made from the app itself, the bus is only told about changes made through it
+fn notify(connection: &Connection, app: &AppHandle, last: &mut Snapshot) -> Result<()> {
+    let (playback, book, speed, position) = {
+        let state = app.state::<AppState>();
+        let state = state.lock()?;
+        (
+            state.player.state(),
+            state.current_book.clone(),
+            state.speed(),
+            state.player.position(),
+        )
+    };
+
+    let expected = match (last.playback, last.polled) {
+        (Some(PlaybackState::Playing), Some(polled)) => {
+            last.position + (polled.elapsed().as_millis() as f64 * last.speed) as u64
+        }
+        _ => last.position,
+    };
+    let seeked = last.polled.is_some() && position.abs_diff(expected) > SEEK_TOLERANCE;
+    let playback_changed = last.playback != Some(playback);
+    let book_changed = last.book != book;
+    let speed_changed = last.speed != speed;
+    *last = Snapshot {
+        playback: Some(playback),
+        book,
+        speed,
+        position,
+        polled: Some(Instant::now()),
+    };
+
+    if let Some(book) = last.book.as_ref().filter(|_| book_changed) {
+        cache_art(app, book);
+    }
+
+    let interface = connection
+        .object_server()
+        .interface::<_, MediaPlayerPlayer>(OBJECT_PATH)?;
+    let emitter = interface.signal_emitter();
+    let player = interface.get();
+    async_runtime::block_on(async {
+        if playback_changed {
+            player.playback_status_changed(emitter).await?;
+        }
+        if book_changed {
+            debug!("Updating mpris metadata");
+            player.metadata_changed(emitter).await?;
+            player.can_play_changed(emitter).await?;
+            player.can_pause_changed(emitter).await?;
+            player.can_seek_changed(emitter).await?;
+            player.can_go_next_changed(emitter).await?;
+            player.can_go_previous_changed(emitter).await?;
+        }
+        if speed_changed {
+            player.rate_changed(emitter).await?;
+        }
+        if seeked {
+            MediaPlayerPlayer::seeked(emitter, micros(position)).await?;
+        }
+
+        Ok(())
+    })
+}
+
+/// Where the cover of the book at `key` is kept for the bus
+fn art_path(app: &AppHandle, key: &str) -> Option<PathBuf> {
+    let dir = app.path().app_cache_dir().ok()?;
+    Some(dir.join(ART_DIR).join(flatten(key)))
+}
+
+/// Saves the cover of the book at `key` where desktops can read it, they load artUrl themselves
+/// and a plex url would give them the server token
+fn cache_art(app: &AppHandle, key: &str) {
+    let Some(path) = art_path(app, key).filter(|path| !path.exists()) else {
+        return;
+    };
+    let (mut plex, thumb) = {
+        let state = app.state::<AppState>();
+        let Ok(state) = state.lock() else {
+            return;
+        };
+        let album = state.settings.plex.get_album(key);
+        let Some(album) = album.ok().filter(|album| !album.thumb_ref().is_empty()) else {
+            return; // nothing to show
+        };
+        (state.settings.plex.clone(), album.thumb_ref().to_string())
+    };
+
+    let image = async_runtime::block_on(plex.get_thumb(&thumb));
+    if let Ok(mut state) = app.state::<AppState>().lock() {
+        state.adopt_plex(&plex);
+    }
+    let saved = match image {
+        Ok(image) => path
+            .parent()
+            .map_or(Ok(()), fs::create_dir_all)
+            .and_then(|_| fs::write(&path, image.data)),
+        Err(err) => {
+            warn!("Unable to get the cover for mpris: {err}");
+            return;
+        }
+    };
+    if let Err(err) = saved {
+        warn!("Unable to save the cover for mpris: {err}");
+    }
+}
+
+fn micros(ms: u64) -> i64 {
+    ms as i64 * 1000
+}
+
+fn failed(err: handlers::Error) -> fdo::Error {
+    fdo::Error::Failed(err.to_string())
+}
+
+/// `org.mpris.MediaPlayer2`, the application itself
+struct MediaPlayer {
+    control: Arc<dyn Control>,
+}
+
+#[interface(name = "org.mpris.MediaPlayer2")]
+impl MediaPlayer {
+    fn raise(&self) {
+        self.control.raise();
+    }
+
+    fn quit(&self) {}
+
+    #[zbus(property(emits_changed_signal = "const"))]
+    fn can_quit(&self) -> bool {
+        false
+    }
+
+    #[zbus(property(emits_changed_signal = "const"))]
+    fn can_raise(&self) -> bool {
+        true
+    }
+
+    #[zbus(property(emits_changed_signal = "const"))]
+    fn has_track_list(&self) -> bool {
+        false
+    }
+
+    #[zbus(property(emits_changed_signal = "const"))]
+    fn identity(&self) -> String {
+        self.control.identity()
+    }
+
+    #[zbus(property(emits_changed_signal = "const"))]
+    fn supported_uri_schemes(&self) -> Vec<String> {
+        Vec::new()
+    }
+
+    #[zbus(property(emits_changed_signal = "const"))]
+    fn supported_mime_types(&self) -> Vec<String> {
+        Vec::new()
+    }
+}
+
+/// `org.mpris.MediaPlayer2.Player`, the book that is loaded is the only track, chapters are
+/// what next and previous move between
+struct MediaPlayerPlayer {
+    control: Arc<dyn Control>,
+}
+
+impl MediaPlayerPlayer {
+    fn playback(&self) -> PlaybackState {
+        self.control.playback()
+    }
+
+    fn has_book(&self) -> bool {
+        self.control.track().is_some()
+    }
+}
+
+#[interface(name = "org.mpris.MediaPlayer2.Player")]
+impl MediaPlayerPlayer {
+    fn next(&self) -> fdo::Result<()> {
+        match self.control.next_chapter() {
+            Err(handlers::Error::NoChange) => Ok(()), // already in the last chapter
+            result => result.map_err(failed),
+        }
+    }
+
+    fn previous(&self) -> fdo::Result<()> {
+        self.control.previous_chapter().map_err(failed)
+    }
+
+    fn pause(&self) -> fdo::Result<()> {
+        if self.playback() == PlaybackState::Playing {
+            self.control.pause().map_err(failed)?;
+            self.control.toggled();
+        }
+
+        Ok(())
+    }
+
+    fn play_pause(&self) -> fdo::Result<()> {
+        match self.playback() {
+            PlaybackState::Playing => self.pause(),
+            _ => self.play(),
+        }
+    }
+
+    fn stop(&self) -> fdo::Result<()> {
+        let playing = self.playback() == PlaybackState::Playing;
+        self.control.stop().map_err(failed)?;
+        if playing {
+            self.control.toggled();
+        }
+
+        Ok(())
+    }
+
+    fn play(&self) -> fdo::Result<()> {
+        if self.playback() != PlaybackState::Playing {
+            self.control.play().map_err(failed)?;
+            self.control.toggled();
+        }
+
+        Ok(())
+    }
+
+    /// `offset` is in microseconds, like every time in mpris
+    fn seek(&self, offset: i64) -> fdo::Result<()> {
+        let position = self.position().saturating_add(offset);
+
+        self.control
+            .seek(position.max(0) as u64 / 1000)
+            .map_err(failed)
+    }
+
+    fn set_position(&self, track_id: ObjectPath<'_>, position: i64) -> fdo::Result<()> {
+        let current = track_path(self.control.track().as_ref());
+        if track_id.as_str() != current {
+            return Ok(()); // meant for a book that has since been replaced
+        }
+        if position < 0 {
+            return Ok(());
+        }
+
+        self.control.seek(position as u64 / 1000).map_err(failed)
+    }
+
+    fn open_uri(&self, _uri: &str) -> fdo::Result<()> {
+        Err(fdo::Error::NotSupported(
+            "Opening uris is not supported".into(),
+        ))
+    }
+
+    #[zbus(signal)]
+    async fn seeked(
+        emitter: &zbus::object_server::SignalEmitter<'_>,
+        position: i64,
+    ) -> zbus::Result<()>;
+
+    #[zbus(property)]
+    fn playback_status(&self) -> String {
+        match self.playback() {
+            PlaybackState::Playing => "Playing",
+            PlaybackState::Paused => "Paused",
+            PlaybackState::Stopped | PlaybackState::Finished => "Stopped",
+        }
+        .into()
+    }
+
+    #[zbus(property)]
+    fn rate(&self) -> f64 {
+        self.control.speed()
+    }
+
+    #[zbus(property)]
+    fn set_rate(&mut self, rate: f64) -> fdo::Result<()> {
+        self.control.set_speed(rate).map_err(failed)
+    }
+
+    #[zbus(property)]
+    fn metadata(&self) -> fdo::Result<HashMap<String, OwnedValue>> {
+        let track = self.control.track();
+        let track_id = ObjectPath::try_from(track_path(track.as_ref()))
+            .map_err(|err| fdo::Error::Failed(err.to_string()))?;
+
+        let mut metadata = HashMap::new();
+        let mut insert = |key: &str, value: Value<'_>| {
+            if let Ok(value) = OwnedValue::try_from(value) {
+                metadata.insert(key.to_string(), value);
+            }
+        };
+        insert("mpris:trackid", track_id.into());
+
+        let Some(track) = track else {
+            return Ok(metadata);
+        };
+        if let Some(duration) = track.duration {
+            insert("mpris:length", micros(duration).into());
+        }
+        if let Some(title) = &track.title {
+            insert("xesam:title", title.as_ref().into());
+            insert("xesam:album", title.as_ref().into());
+        }
+        if let Some(artist) = &track.artist {
+            insert("xesam:artist", vec![artist.as_ref()].into());
+        }
+        if let Some(art) = track.art.and_then(|art| Url::from_file_path(art).ok()) {
+            insert("mpris:artUrl", art.as_str().into());
+        }
+
+        Ok(metadata)
+    }
+
+    #[zbus(property(emits_changed_signal = "const"))]
+    fn volume(&self) -> f64 {
+        1.0
+    }
+
+    /// Microseconds into the book, too frequent to signal, `Seeked` covers jumps
+    #[zbus(property(emits_changed_signal = "false"))]
+    fn position(&self) -> i64 {
+        micros(self.control.position())
+    }
+
+    #[zbus(property(emits_changed_signal = "const"))]
+    fn minimum_rate(&self) -> f64 {
+        MIN_SPEED
+    }
+
+    #[zbus(property(emits_changed_signal = "const"))]
+    fn maximum_rate(&self) -> f64 {
+        MAX_SPEED
+    }
+
+    #[zbus(property)]
+    fn can_play(&self) -> bool {
+        self.has_book()
+    }
+
+    #[zbus(property)]
+    fn can_pause(&self) -> bool {
+        self.has_book()
+    }
+
+    #[zbus(property)]
+    fn can_seek(&self) -> bool {
+        self.has_book()
+    }
+
+    #[zbus(property)]
+    fn can_go_next(&self) -> bool {
+        self.has_book()
+    }
+
+    #[zbus(property)]
+    fn can_go_previous(&self) -> bool {
+        self.has_book()
+    }
+
+    #[zbus(property(emits_changed_signal = "const"))]
+    fn can_control(&self) -> bool {
+        true
+    }
+}
+
+/// Object path naming the current book
+fn track_path(track: Option<&Track>) -> String {
+    match track {
+        Some(track) => format!("{TRACK_PATH}/{}", flatten(&track.key)),
+        None => NO_TRACK.into(),
+    }
+}
+
+/// Plex keys are paths already but may hold characters object paths and file names can't
+fn flatten(key: &str) -> String {
+    key.chars()
+        .map(|char| match char {
+            'a'..='z' | 'A'..='Z' | '0'..='9' | '_' => char,
+            _ => '_',
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        io::{BufRead, BufReader},
+        process::{Child, Command, Stdio},
+        sync::atomic::{AtomicBool, AtomicU64, AtomicUsize, Ordering},
+    };
+
+    use zbus::{
+        blocking::{proxy, Proxy},
+        proxy::CacheProperties,
+    };
+
+    use super::*;
+
+    /// A bus of its own, so the session bus is left alone
+    struct PrivateBus(Child);
+
+    impl PrivateBus {
+        fn start() -> Option<(Self, String)> {
+            let mut daemon = Command::new("dbus-daemon")
+                .args(["--session", "--nofork", "--print-address"])
+                .stdout(Stdio::piped())
+                .spawn()
+                .ok()?;
+            let mut address = String::new();
+            BufReader::new(daemon.stdout.take()?)
+                .read_line(&mut address)
+                .ok()?;
+
+            Some((Self(daemon), address.trim().to_string()))
+        }
+    }
+
+    impl Drop for PrivateBus {
+        fn drop(&mut self) {
+            self.0.kill().ok();
+            self.0.wait().ok();
+        }
+    }
+
+    #[derive(Default)]
+    struct FakeControl {
+        playing: AtomicBool,
+        position: AtomicU64,
+        toggled: AtomicUsize,
+    }
+
+    impl Control for FakeControl {
+        fn identity(&self) -> String {
+            "Test".into()
+        }
+
+        fn raise(&self) {}
+
+        fn playback(&self) -> PlaybackState {
+            match self.playing.load(Ordering::Relaxed) {
+                true => PlaybackState::Playing,
+                false => PlaybackState::Paused,
+            }
+        }
+
+        fn position(&self) -> u64 {
+            self.position.load(Ordering::Relaxed)
+        }
+
+        fn speed(&self) -> f64 {
+            1.0
+        }
+
+        fn track(&self) -> Option<Track> {
+            Some(Track {
+                key: "/library/metadata/1".into(),
+                duration: Some(60_000),
+                title: Some("Book".into()),
+                artist: Some("Author".into()),
+                art: Some("/tmp/cover".into()),
+            })
+        }
+
+        fn play(&self) -> handlers::Result<()> {
+            self.playing.store(true, Ordering::Relaxed);
+            Ok(())
+        }
+
+        fn pause(&self) -> handlers::Result<()> {
+            self.playing.store(false, Ordering::Relaxed);
+            Ok(())
+        }
+
+        fn stop(&self) -> handlers::Result<()> {
+            self.playing.store(false, Ordering::Relaxed);
+            self.position.store(0, Ordering::Relaxed);
+            Ok(())
+        }
+
+        fn seek(&self, position: u64) -> handlers::Result<()> {
+            self.position.store(position, Ordering::Relaxed);
+            Ok(())
+        }
+
+        fn set_speed(&self, _speed: f64) -> handlers::Result<()> {
+            Ok(())
+        }
+
+        fn next_chapter(&self) -> handlers::Result<()> {
+            Ok(())
+        }
+
+        fn previous_chapter(&self) -> handlers::Result<()> {
+            Ok(())
+        }
+
+        fn toggled(&self) {
+            self.toggled.fetch_add(1, Ordering::Relaxed);
+        }
+    }
+
+    #[test]
+    fn controls_the_player_over_a_private_bus() {
+        let Some((_bus, address)) = PrivateBus::start() else {
+            eprintln!("skipping, dbus-daemon is not available");
+            return;
+        };
+        let control = Arc::new(FakeControl::default());
+        let _server =
+            connect(control.clone(), Builder::address(address.as_str()).unwrap()).unwrap();
+
+        let client = Builder::address(address.as_str()).unwrap().build().unwrap();
+        // nothing is signalled from the fake, so every read has to go to the bus
+        let player = proxy::Builder::<Proxy>::new(&client)
+            .destination(BUS_NAME)
+            .unwrap()
+            .path(OBJECT_PATH)
+            .unwrap()
+            .interface("org.mpris.MediaPlayer2.Player")
+            .unwrap()
+            .cache_properties(CacheProperties::No)
+            .build()
+            .unwrap();
+        let status = || player.get_property::<String>("PlaybackStatus").unwrap();
+        let position = || player.get_property::<i64>("Position").unwrap();
+
+        assert_eq!(status(), "Paused");
+        player.call_method("PlayPause", &()).unwrap();
+        assert_eq!(status(), "Playing");
+
+        player.call_method("Seek", &(5_000_000i64,)).unwrap();
+        assert_eq!(position(), 5_000_000);
+        assert_eq!(control.position(), 5_000);
+        player.call_method("Seek", &(-60_000_000i64,)).unwrap();
+        assert_eq!(position(), 0);
+
+        player.call_method("PlayPause", &()).unwrap();
+        assert_eq!(status(), "Paused");
+        assert_eq!(control.toggled.load(Ordering::Relaxed), 2);
+
+        let metadata = player
+            .get_property::<HashMap<String, OwnedValue>>("Metadata")
+            .unwrap();
+        let text = |key: &str| String::try_from(metadata[key].try_clone().unwrap()).unwrap();
+        assert_eq!(text("xesam:title"), "Book");
+        assert_eq!(text("mpris:artUrl"), "file:///tmp/cover");
+        assert_eq!(
+            ObjectPath::try_from(metadata["mpris:trackid"].try_clone().unwrap())
+                .unwrap()
+                .as_str(),
+            "/org/mpris/MediaPlayer2/book/_library_metadata_1"
+        );
+    }
+}
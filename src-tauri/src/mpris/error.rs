@@ -0,0 +1,17 @@
+use std::sync::PoisonError;
+
+use derive_more::{Display, Error, From};
+
+pub type Result<T> = core::result::Result<T, Error>;
+
+#[derive(Debug, From, Error, Display)]
+pub enum Error {
+    Bus(zbus::Error),
+    FailedToLockState,
+}
+
+impl<T> From<PoisonError<T>> for Error {
+    fn from(_err: PoisonError<T>) -> Self {
+        Self::FailedToLockState
+    }
+}
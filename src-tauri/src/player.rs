@@ -0,0 +1,249 @@
+#[cfg(feature = "app")]
+mod decoder;
+#[cfg(feature = "app")]
+mod dsp;
+#[cfg(feature = "app")]
+mod engine;
+#[cfg(feature = "app")]
+mod error;
+#[cfg(feature = "app")]
+mod opus;
+#[cfg(feature = "app")]
+mod output;
+#[cfg(feature = "app")]
+mod stream;
+#[cfg(feature = "app")]
+mod stretch;
+
+#[cfg(feature = "app")]
+pub use error::*;
+
+#[cfg(feature = "app")]
+pub(crate) use output::*;
+
+use std::sync::Arc;
+#[cfg(feature = "app")]
+use std::{
+    path::PathBuf,
+    sync::{
+        atomic::{AtomicBool, AtomicU64, AtomicU8, AtomicUsize, Ordering},
+        mpsc::{self, Sender},
+    },
+    thread,
+};
+
+#[cfg(feature = "app")]
+use log::warn;
+use serde::{Deserialize, Serialize};
+
+#[cfg(feature = "app")]
+use engine::Command;
+
+#[cfg(feature = "app")]
+pub(crate) const MIN_SPEED: f64 = 0.5;
+#[cfg(feature = "app")]
+pub(crate) const MAX_SPEED: f64 = 3.0;
+
+/// A file of the book as plex knows it, enough to play it back without asking plex again
+#[derive(Serialize, Deserialize, Clone)]
+pub(crate) struct PlaylistItem {
+    /// The plex track the part belongs to
+    #[serde(default)]
+    pub(crate) rating_key: Arc<str>,
+    pub(crate) part_key: Arc<str>,
+    pub(crate) file_name: Arc<str>,
+    pub(crate) duration: u64,
+}
+
+#[cfg(feature = "app")]
+pub(crate) enum PlayerSource {
+    File(PathBuf),
+    Stream(String),
+}
+
+/// A single file queued on the player, `duration` is in milliseconds
+#[cfg(feature = "app")]
+pub(crate) struct PlayerTrack {
+    pub(crate) source: PlayerSource,
+    pub(crate) duration: u64,
+}
+
+/// Where playback is in a book, `offset` is milliseconds into the track at `track`
+#[derive(Serialize, Deserialize, Clone, Copy, PartialEq, Default, Debug)]
+pub(crate) struct TrackPosition {
+    pub(crate) track: usize,
+    pub(crate) offset: u64,
+}
+
+/// Optional processing for recordings that are hard to listen to as they are
+#[derive(Serialize, Deserialize, Clone, Copy, PartialEq, Default, Debug)]
+pub(crate) struct Processing {
+    /// Shortens long pauses
+    pub(crate) skip_silence: bool,
+    /// Compresses and boosts quiet narration
+    pub(crate) boost: bool,
+}
+
+#[cfg(feature = "app")]
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub(crate) enum PlaybackState {
+    Stopped,
+    Paused,
+    Playing,
+    Finished,
+}
+
+/// Written by the engine thread, read by anyone holding the player
+#[cfg(feature = "app")]
+#[derive(Default)]
+struct Clock {
+    position: AtomicU64,
+    track: AtomicUsize,
+    offset: AtomicU64,
+    state: AtomicU8,
+    // set when a streamed book stopped on an error, along with whether it was playing
+    lost_stream: AtomicBool,
+    resume: AtomicBool,
+}
+
+#[cfg(feature = "app")]
+impl Clock {
+    fn position(&self) -> u64 {
+        self.position.load(Ordering::Relaxed)
+    }
+
+    fn track_position(&self) -> TrackPosition {
+        TrackPosition {
+            track: self.track.load(Ordering::Relaxed),
+            offset: self.offset.load(Ordering::Relaxed),
+        }
+    }
+
+    /// `position` is on the combined timeline, `track` and `offset` the same spot within a track
+    fn set_position(&self, position: u64, track: usize, offset: u64) {
+        self.position.store(position, Ordering::Relaxed);
+        self.track.store(track, Ordering::Relaxed);
+        self.offset.store(offset, Ordering::Relaxed);
+    }
+
+    fn state(&self) -> PlaybackState {
+        match self.state.load(Ordering::Relaxed) {
+            3 => PlaybackState::Finished,
+            2 => PlaybackState::Playing,
+            1 => PlaybackState::Paused,
+            _ => PlaybackState::Stopped,
+        }
+    }
+
+    fn set_state(&self, state: PlaybackState) {
+        self.state.store(state as u8, Ordering::Relaxed)
+    }
+
+    fn set_lost_stream(&self, resume: bool) {
+        self.resume.store(resume, Ordering::Relaxed);
+        self.lost_stream.store(true, Ordering::Relaxed);
+    }
+
+    fn take_lost_stream(&self) -> Option<bool> {
+        self.lost_stream
+            .swap(false, Ordering::Relaxed)
+            .then(|| self.resume.load(Ordering::Relaxed))
+    }
+}
+
+/// Handle to the playback engine, which decodes and plays on its own thread
+#[cfg(feature = "app")]
+pub(crate) struct Player {
+    commands: Sender<Command>,
+    clock: Arc<Clock>,
+}
+
+#[cfg(feature = "app")]
+impl Player {
+    /// The output is created on the engine thread as audio streams can't be moved between threads
+    pub(crate) fn new<F>(create_output: F) -> Self
+    where
+        F: FnOnce() -> BoxedOutput + Send + 'static,
+    {
+        let (commands, receiver) = mpsc::channel();
+        let clock = Arc::new(Clock::default());
+
+        let engine_clock = clock.clone();
+        thread::spawn(move || engine::run(create_output(), receiver, engine_clock));
+
+        Self { commands, clock }
+    }
+
+    fn send(&self, command: Command) -> Result<()> {
+        self.commands
+            .send(command)
+            .map_err(|_| Error::EngineStopped)
+    }
+
+    /// Replaces whatever is loaded, paused at `position`
+    pub(crate) fn load(&self, tracks: Box<[PlayerTrack]>, position: TrackPosition) -> Result<()> {
+        self.send(Command::Load(tracks, position))
+    }
+
+    pub(crate) fn play(&self) -> Result<()> {
+        self.send(Command::Play)
+    }
+
+    pub(crate) fn pause(&self) -> Result<()> {
+        self.send(Command::Pause)
+    }
+
+    pub(crate) fn seek(&self, position: u64) -> Result<()> {
+        self.send(Command::Seek(position))
+    }
+
+    /// Playback speed, kept between `MIN_SPEED` and `MAX_SPEED`
+    pub(crate) fn set_speed(&self, speed: f64) -> Result<()> {
+        self.send(Command::SetSpeed(speed.clamp(MIN_SPEED, MAX_SPEED)))
+    }
+
+    /// Loudness from silent at 0 to unchanged at 1
+    pub(crate) fn set_volume(&self, volume: f32) -> Result<()> {
+        self.send(Command::SetVolume(volume.clamp(0.0, 1.0)))
+    }
+
+    pub(crate) fn set_processing(&self, processing: Processing) -> Result<()> {
+        self.send(Command::SetProcessing(processing))
+    }
+
+    pub(crate) fn stop(&self) -> Result<()> {
+        self.send(Command::Stop)
+    }
+
+    /// Milliseconds from the start of the first track
+    pub(crate) fn position(&self) -> u64 {
+        self.clock.position()
+    }
+
+    pub(crate) fn track_position(&self) -> TrackPosition {
+        self.clock.track_position()
+    }
+
+    pub(crate) fn state(&self) -> PlaybackState {
+        self.clock.state()
+    }
+
+    /// Whether playback stopped because a stream failed since this was last asked, and if it
+    /// was playing at the time
+    pub(crate) fn take_lost_stream(&self) -> Option<bool> {
+        self.clock.take_lost_stream()
+    }
+}
+
+#[cfg(feature = "app")]
+impl Default for Player {
+    fn default() -> Self {
+        Self::new(|| match CpalOutput::new() {
+            Ok(output) => Box::new(output),
+            Err(err) => {
+                warn!("No audio output available, playing silently: {err}");
+                Box::new(NullOutput::default())
+            }
+        })
+    }
+}
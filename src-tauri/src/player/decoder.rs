@@ -0,0 +1,187 @@
+use std::{fs::File, io::ErrorKind, sync::OnceLock};
+
+use log::{debug, warn};
+use symphonia::core::{
+    audio::SampleBuffer,
+    codecs::{self, CodecRegistry, DecoderOptions, CODEC_TYPE_NULL},
+    errors::Error as SymphoniaError,
+    formats::{FormatOptions, FormatReader, SeekMode, SeekTo},
+    io::{MediaSource, MediaSourceStream},
+    meta::MetadataOptions,
+    probe::Hint,
+    units::{Time, TimeBase},
+};
+
+use super::{opus::OpusDecoder, stream::HttpStream, AudioSpec, Error, PlayerSource, Result};
+
+/// A block of decoded audio, `position` is where it starts in milliseconds
+pub(super) struct Decoded<'a> {
+    pub(super) samples: &'a [f32],
+    pub(super) spec: AudioSpec,
+    pub(super) position: u64,
+}
+
+/// Symphonias own codecs along with the ones it is missing
+fn codecs() -> &'static CodecRegistry {
+    static CODECS: OnceLock<CodecRegistry> = OnceLock::new();
+    CODECS.get_or_init(|| {
+        let mut registry = CodecRegistry::new();
+        symphonia::default::register_enabled_codecs(&mut registry);
+        registry.register_all::<OpusDecoder>();
+        registry
+    })
+}
+
+/// Decodes a single file, anything symphonia can probe is supported
+pub(super) struct Decoder {
+    format: Box<dyn FormatReader>,
+    decoder: Box<dyn codecs::Decoder>,
+    track_id: u32,
+    time_base: Option<TimeBase>,
+    buffer: Option<SampleBuffer<f32>>,
+    // frames to drop after an accurate seek lands before the requested time
+    skip: u64,
+}
+
+impl Decoder {
+    pub(super) fn open(source: &PlayerSource) -> Result<Self> {
+        let mut hint = Hint::new();
+        let media: Box<dyn MediaSource> = match source {
+            PlayerSource::File(path) => {
+                debug!("Opening {path:?}");
+                if let Some(extension) = path.extension() {
+                    hint.with_extension(&extension.to_string_lossy());
+                }
+                Box::new(File::open(path)?)
+            }
+            PlayerSource::Stream(url) => Box::new(HttpStream::open(url)?),
+        };
+
+        let stream = MediaSourceStream::new(media, Default::default());
+        let format = symphonia::default::get_probe()
+            .format(
+                &hint,
+                stream,
+                &FormatOptions {
+                    enable_gapless: true,
+                    ..Default::default()
+                },
+                &MetadataOptions::default(),
+            )?
+            .format;
+
+        let track = format
+            .tracks()
+            .iter()
+            .find(|track| track.codec_params.codec != CODEC_TYPE_NULL)
+            .ok_or(Error::NoAudioTrack)?;
+        let decoder = codecs().make(&track.codec_params, &DecoderOptions::default())?;
+
+        Ok(Self {
+            track_id: track.id,
+            time_base: track.codec_params.time_base,
+            format,
+            decoder,
+            buffer: None,
+            skip: 0,
+        })
+    }
+
+    pub(super) fn seek(&mut self, position: u64) -> Result<()> {
+        let seeked = self.format.seek(
+            SeekMode::Accurate,
+            SeekTo::Time {
+                time: Time::from(position as f64 / 1000.0),
+                track_id: Some(self.track_id),
+            },
+        )?;
+        self.skip = seeked.required_ts.saturating_sub(seeked.actual_ts);
+        self.decoder.reset();
+
+        Ok(())
+    }
+
+    /// Decodes the next packet of the track, `None` once the file has ended
+    pub(super) fn next(&mut self) -> Result<Option<Decoded<'_>>> {
+        let (start, spec, position) =
+            loop {
+                let packet = match self.format.next_packet() {
+                    Ok(packet) => packet,
+                    Err(SymphoniaError::IoError(err)) if err.kind() == ErrorKind::UnexpectedEof => {
+                        return Ok(None)
+                    }
+                    Err(err) => return Err(err.into()),
+                };
+                if packet.track_id() != self.track_id {
+                    continue;
+                }
+
+                let decoded = match self.decoder.decode(&packet) {
+                    Ok(decoded) => decoded,
+                    Err(SymphoniaError::DecodeError(err)) => {
+                        warn!("Skipping corrupt packet: {err}");
+                        continue;
+                    }
+                    Err(err) => return Err(err.into()),
+                };
+
+                let spec = *decoded.spec();
+                let frames = decoded.frames() as u64;
+                // the sample buffer counts samples across every channel, the decoded one frames
+                if self.buffer.as_ref().is_none_or(|buffer| {
+                    buffer.capacity() < decoded.capacity() * spec.channels.count()
+                }) {
+                    self.buffer = Some(SampleBuffer::new(decoded.capacity() as u64, spec));
+                }
+                if let Some(buffer) = &mut self.buffer {
+                    buffer.copy_interleaved_ref(decoded);
+                }
+
+                let skip = self.skip.min(frames);
+                self.skip -= skip;
+                if skip == frames {
+                    continue;
+                }
+
+                let channels = spec.channels.count();
+                let position = self
+                    .time_base
+                    .map(|time_base| {
+                        let time = time_base.calc_time(packet.ts() + skip);
+                        time.seconds * 1000 + (time.frac * 1000.0) as u64
+                    })
+                    .unwrap_or_default();
+                let spec = AudioSpec {
+                    rate: spec.rate,
+                    channels,
+                };
+
+                break (skip as usize * channels, spec, position);
+            };
+
+        let samples = self
+            .buffer
+            .as_ref()
+            .map(|buffer| &buffer.samples()[start..])
+            .unwrap_or_default();
+
+        Ok(Some(Decoded {
+            samples,
+            spec,
+            position,
+        }))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use symphonia::core::codecs::{CODEC_TYPE_MP3, CODEC_TYPE_OPUS};
+
+    use super::*;
+
+    #[test]
+    fn registers_opus_alongside_symphonias_codecs() {
+        assert!(codecs().get_codec(CODEC_TYPE_OPUS).is_some());
+        assert!(codecs().get_codec(CODEC_TYPE_MP3).is_some());
+    }
+}
@@ -0,0 +1,265 @@
+use super::{AudioSpec, Processing};
+
+// quieter than about -45dBFS counts as silence
+const SILENCE_THRESHOLD: f32 = 0.0056;
+// milliseconds of each silence that are kept so sentences still have room to breathe
+const SILENCE_KEPT: u32 = 400;
+const SILENCE_RELEASE: f32 = 20.0;
+
+// about -24dBFS, anything louder is turned down by the ratio
+const COMPRESSOR_THRESHOLD: f32 = 0.063;
+const COMPRESSOR_RATIO: f32 = 3.0;
+// about +9dB, brings quiet narration back up after compressing
+const COMPRESSOR_MAKEUP: f32 = 2.8;
+const COMPRESSOR_ATTACK: f32 = 5.0;
+const COMPRESSOR_RELEASE: f32 = 150.0;
+
+/// Per sample smoothing coefficient for an envelope settling over `length` milliseconds
+fn coefficient(length: f32, rate: u32) -> f32 {
+    (-1.0 / (length / 1000.0 * rate as f32)).exp()
+}
+
+/// Loudest sample of a frame, channels are treated together so the stereo image is kept
+fn peak(frame: &[f32]) -> f32 {
+    frame
+        .iter()
+        .fold(0.0, |peak: f32, sample| peak.max(sample.abs()))
+}
+
+#[derive(Default)]
+struct SkipSilence {
+    release: f32,
+    kept: usize,
+    envelope: f32,
+    // frames of silence in a row so far
+    silent: usize,
+}
+
+impl SkipSilence {
+    fn configure(&mut self, spec: AudioSpec) {
+        self.release = coefficient(SILENCE_RELEASE, spec.rate);
+        self.kept = (spec.rate * SILENCE_KEPT / 1000) as usize;
+        self.reset();
+    }
+
+    fn reset(&mut self) {
+        self.envelope = 0.0;
+        self.silent = 0;
+    }
+
+    fn process(&mut self, samples: &[f32], channels: usize, out: &mut Vec<f32>) {
+        for frame in samples.chunks(channels) {
+            let peak = peak(frame);
+            // rises straight away so the start of a word is never lost
+            self.envelope = peak.max(self.envelope * self.release);
+
+            if self.envelope < SILENCE_THRESHOLD {
+                self.silent += 1;
+            } else {
+                self.silent = 0;
+            }
+            if self.silent <= self.kept {
+                out.extend_from_slice(frame);
+            }
+        }
+    }
+}
+
+#[derive(Default)]
+struct Compressor {
+    attack: f32,
+    release: f32,
+    envelope: f32,
+}
+
+impl Compressor {
+    fn configure(&mut self, spec: AudioSpec) {
+        self.attack = coefficient(COMPRESSOR_ATTACK, spec.rate);
+        self.release = coefficient(COMPRESSOR_RELEASE, spec.rate);
+        self.reset();
+    }
+
+    fn reset(&mut self) {
+        self.envelope = 0.0;
+    }
+
+    fn process(&mut self, samples: &mut [f32], channels: usize) {
+        for frame in samples.chunks_mut(channels) {
+            let peak = peak(frame);
+            let coefficient = if peak > self.envelope {
+                self.attack
+            } else {
+                self.release
+            };
+            self.envelope = peak + (self.envelope - peak) * coefficient;
+
+            let reduction = if self.envelope > COMPRESSOR_THRESHOLD {
+                let over = self.envelope / COMPRESSOR_THRESHOLD;
+                over.powf(1.0 / COMPRESSOR_RATIO) / over
+            } else {
+                1.0
+            };
+            let gain = reduction * COMPRESSOR_MAKEUP;
+            for sample in frame {
+                *sample = (*sample * gain).clamp(-1.0, 1.0);
+            }
+        }
+    }
+}
+
+/// Runs whichever `Processing` is turned on, skipping silence happens before time stretching
+/// and compressing after it
+#[derive(Default)]
+pub(super) struct Dsp {
+    processing: Processing,
+    spec: Option<AudioSpec>,
+    silence: SkipSilence,
+    compressor: Compressor,
+}
+
+impl Dsp {
+    pub(super) fn set_processing(&mut self, processing: Processing) {
+        self.processing = processing;
+        self.reset();
+    }
+
+    pub(super) fn skips_silence(&self) -> bool {
+        self.processing.skip_silence
+    }
+
+    /// Forgets how loud things were, for seeks and track changes
+    pub(super) fn reset(&mut self) {
+        self.silence.reset();
+        self.compressor.reset();
+    }
+
+    fn configure(&mut self, spec: AudioSpec) {
+        if self.spec != Some(spec) {
+            self.spec = Some(spec);
+            self.silence.configure(spec);
+            self.compressor.configure(spec);
+        }
+    }
+
+    /// Copies `samples` to `out` with long silences cut short
+    pub(super) fn skip_silence(&mut self, samples: &[f32], spec: AudioSpec, out: &mut Vec<f32>) {
+        out.clear();
+        self.configure(spec);
+        self.silence.process(samples, spec.channels, out);
+    }
+
+    pub(super) fn compress(&mut self, samples: &mut [f32], spec: AudioSpec) {
+        if !self.processing.boost {
+            return;
+        }
+        self.configure(spec);
+        self.compressor.process(samples, spec.channels);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::f32::consts::TAU;
+
+    use super::*;
+
+    const SPEC: AudioSpec = AudioSpec {
+        rate: 8000,
+        channels: 2,
+    };
+
+    /// How many frames make up `length` milliseconds
+    fn frames(length: u32) -> usize {
+        (SPEC.rate * length / 1000) as usize
+    }
+
+    fn tone(length: u32, amplitude: f32) -> Vec<f32> {
+        (0..frames(length))
+            .map(|frame| amplitude * (TAU * 440.0 * frame as f32 / SPEC.rate as f32).sin())
+            .flat_map(|sample| [sample, sample])
+            .collect()
+    }
+
+    fn silence(length: u32) -> Vec<f32> {
+        vec![0.0; frames(length) * SPEC.channels]
+    }
+
+    fn skip_silence(samples: &[f32]) -> Vec<f32> {
+        let mut silence = SkipSilence::default();
+        silence.configure(SPEC);
+        let mut out = Vec::new();
+        // in blocks the size a decoder hands over, the state has to carry between them
+        for block in samples.chunks(1024) {
+            silence.process(block, SPEC.channels, &mut out);
+        }
+        out
+    }
+
+    #[test]
+    fn cuts_long_silence_down_to_what_is_kept() {
+        let (first, second) = (tone(1000, 0.5), tone(1000, 0.5));
+        let input = [first.clone(), silence(3000), second.clone()].concat();
+        let out = skip_silence(&input);
+
+        // the envelope takes a moment to fall below the threshold before counting starts
+        let gap = out.len() / SPEC.channels - frames(2000);
+        assert!(gap >= frames(SILENCE_KEPT), "gap of {gap} frames");
+        assert!(gap <= frames(SILENCE_KEPT + 100), "gap of {gap} frames");
+        assert_eq!(out[..first.len()], first);
+        assert_eq!(out[out.len() - second.len()..], second);
+    }
+
+    #[test]
+    fn leaves_short_silence_alone() {
+        let input = [tone(500, 0.5), silence(SILENCE_KEPT), tone(500, 0.5)].concat();
+        assert_eq!(skip_silence(&input), input);
+    }
+
+    #[test]
+    fn keeps_quiet_speech() {
+        // just above the threshold is still speech
+        let input = tone(2000, SILENCE_THRESHOLD * 1.5);
+        assert_eq!(skip_silence(&input).len(), input.len());
+    }
+
+    fn compress(samples: &[f32]) -> Vec<f32> {
+        let mut compressor = Compressor::default();
+        compressor.configure(SPEC);
+        let mut out = samples.to_vec();
+        compressor.process(&mut out, SPEC.channels);
+        out
+    }
+
+    fn peak_after(samples: &[f32], length: u32) -> f32 {
+        peak(&samples[frames(length) * SPEC.channels..])
+    }
+
+    #[test]
+    fn lowers_peaks_above_the_threshold() {
+        let input = tone(1000, 0.9);
+        let out = compress(&input);
+
+        // once the attack has settled
+        let peak = peak_after(&out, 50);
+        assert!(peak < 0.9 / 1.5, "peak of {peak}");
+        assert!(peak > COMPRESSOR_THRESHOLD, "peak of {peak}");
+        assert!(out.iter().all(|sample| sample.abs() <= 1.0));
+    }
+
+    #[test]
+    fn only_adds_makeup_gain_below_the_threshold() {
+        let input = tone(1000, COMPRESSOR_THRESHOLD / 2.0);
+        let out = compress(&input);
+
+        for (sample, compressed) in input.iter().zip(&out) {
+            assert!((sample * COMPRESSOR_MAKEUP - compressed).abs() < 1e-6);
+        }
+    }
+
+    #[test]
+    fn narrows_the_gap_between_loud_and_quiet() {
+        let loud = peak_after(&compress(&tone(1000, 0.8)), 50);
+        let quiet = peak_after(&compress(&tone(1000, 0.1)), 50);
+        assert!(loud / quiet < 0.8 / 0.1 / 1.5, "{loud} against {quiet}");
+    }
+}
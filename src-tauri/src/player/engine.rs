@@ -0,0 +1,465 @@
+use std::sync::{
+    mpsc::{Receiver, TryRecvError},
+    Arc,
+};
+
+use log::{debug, error};
+
+use super::{
+    decoder::Decoder, dsp::Dsp, stretch::TimeStretch, BoxedOutput, Clock, PlaybackState,
+    PlayerSource, PlayerTrack, Processing, Result, TrackPosition,
+};
+
+pub(super) enum Command {
+    Load(Box<[PlayerTrack]>, TrackPosition),
+    Play,
+    Pause,
+    Seek(u64),
+    SetSpeed(f64),
+    SetVolume(f32),
+    SetProcessing(Processing),
+    Stop,
+}
+
+struct Engine {
+    output: BoxedOutput,
+    tracks: Box<[PlayerTrack]>,
+    // where each track starts on the combined timeline
+    offsets: Box<[u64]>,
+    current: Option<(usize, Decoder)>,
+    clock: Arc<Clock>,
+    stretch: TimeStretch,
+    stretched: Vec<f32>,
+    dsp: Dsp,
+    trimmed: Vec<f32>,
+    volume: f32,
+    // the volume last played at, changes are ramped over a packet to avoid clicks
+    gain: f32,
+}
+
+pub(super) fn run(output: BoxedOutput, commands: Receiver<Command>, clock: Arc<Clock>) {
+    let mut engine = Engine {
+        output,
+        tracks: Box::new([]),
+        offsets: Box::new([]),
+        current: None,
+        clock,
+        stretch: TimeStretch::default(),
+        stretched: Vec::new(),
+        dsp: Dsp::default(),
+        trimmed: Vec::new(),
+        volume: 1.0,
+        gain: 1.0,
+    };
+
+    loop {
+        // commands always win over decoding so seeks and pauses feel immediate
+        let command = match engine.clock.state() {
+            PlaybackState::Playing => match commands.try_recv() {
+                Ok(command) => Some(command),
+                Err(TryRecvError::Empty) => None,
+                Err(TryRecvError::Disconnected) => break,
+            },
+            _ => match commands.recv() {
+                Ok(command) => Some(command),
+                Err(_) => break,
+            },
+        };
+
+        let result = match command {
+            Some(command) => engine.handle(command),
+            None => engine.step(),
+        };
+        if let Err(err) = result {
+            error!("Playback failed: {err}");
+            let playing = engine.clock.state() == PlaybackState::Playing;
+            engine.stop();
+            // the server may only have moved, the player can be loaded again from elsewhere
+            if engine.streams() {
+                engine.clock.set_lost_stream(playing);
+            }
+        }
+    }
+    debug!("Player engine shut down");
+}
+
+impl Engine {
+    fn handle(&mut self, command: Command) -> Result<()> {
+        match command {
+            Command::Load(tracks, position) => {
+                self.stop();
+                self.offsets = tracks
+                    .iter()
+                    .scan(0, |offset, track| {
+                        let start = *offset;
+                        *offset += track.duration;
+                        Some(start)
+                    })
+                    .collect();
+                self.tracks = tracks;
+                let start = self
+                    .offsets
+                    .get(position.track)
+                    .map(|offset| offset + position.offset)
+                    .unwrap_or_default();
+                if !self.tracks.is_empty() && start >= self.duration() {
+                    // a book left at the end stays finished, so playing it starts over
+                    self.finish();
+                } else {
+                    self.seek(start)?;
+                    self.clock.set_state(PlaybackState::Paused);
+                }
+            }
+            Command::Play => {
+                if self.current.is_none() {
+                    // a finished book starts over
+                    let position = match self.clock.state() {
+                        PlaybackState::Finished => 0,
+                        _ => self.clock.position(),
+                    };
+                    self.seek(position)?;
+                }
+                if self.current.is_some() {
+                    self.output.resume()?;
+                    self.clock.set_state(PlaybackState::Playing);
+                }
+            }
+            Command::Pause => {
+                self.output.pause()?;
+                if self.clock.state() == PlaybackState::Playing {
+                    self.clock.set_state(PlaybackState::Paused);
+                }
+            }
+            Command::Seek(position) => self.seek(position)?,
+            Command::SetSpeed(speed) => {
+                debug!("Playing at {speed}x");
+                self.stretch.set_speed(speed);
+                self.output.clear();
+            }
+            Command::SetVolume(volume) => self.volume = volume,
+            Command::SetProcessing(processing) => {
+                debug!("Processing with {processing:?}");
+                self.dsp.set_processing(processing);
+            }
+            Command::Stop => {
+                self.stop();
+                self.clock.set_position(0, 0, 0);
+            }
+        }
+
+        Ok(())
+    }
+
+    fn streams(&self) -> bool {
+        self.tracks
+            .iter()
+            .any(|track| matches!(track.source, PlayerSource::Stream(_)))
+    }
+
+    fn duration(&self) -> u64 {
+        self.tracks.iter().map(|track| track.duration).sum()
+    }
+
+    fn seek(&mut self, position: u64) -> Result<()> {
+        if self.tracks.is_empty() {
+            return Ok(());
+        }
+        let position = position.min(self.duration());
+        let index = self
+            .offsets
+            .iter()
+            .rposition(|offset| *offset <= position)
+            .unwrap_or_default();
+        let relative = position - self.offsets[index];
+
+        match &mut self.current {
+            Some((current, decoder)) if *current == index => decoder.seek(relative)?,
+            _ => self.open(index, relative)?,
+        }
+        self.output.clear();
+        self.stretch.reset();
+        self.dsp.reset();
+        self.clock.set_position(position, index, relative);
+
+        Ok(())
+    }
+
+    fn open(&mut self, index: usize, position: u64) -> Result<()> {
+        let mut decoder = Decoder::open(&self.tracks[index].source)?;
+        if position > 0 {
+            decoder.seek(position)?;
+        }
+        self.current = Some((index, decoder));
+
+        Ok(())
+    }
+
+    /// Plays a single packet, moving on to the next track when one runs out
+    fn step(&mut self) -> Result<()> {
+        let Some((index, decoder)) = &mut self.current else {
+            self.clock.set_state(PlaybackState::Stopped);
+            return Ok(());
+        };
+
+        match decoder.next()? {
+            Some(decoded) => {
+                let samples = if self.dsp.skips_silence() {
+                    self.dsp
+                        .skip_silence(decoded.samples, decoded.spec, &mut self.trimmed);
+                    &self.trimmed
+                } else {
+                    decoded.samples
+                };
+                self.stretch
+                    .process(samples, decoded.spec, &mut self.stretched);
+                self.dsp.compress(&mut self.stretched, decoded.spec);
+                apply_gain(
+                    &mut self.stretched,
+                    decoded.spec.channels,
+                    self.gain,
+                    self.volume,
+                );
+                self.gain = self.volume;
+                self.output.write(&self.stretched, decoded.spec)?;
+
+                // what is being heard lags behind what was just decoded
+                let frames = decoded.samples.len() / decoded.spec.channels;
+                let end = decoded.position + frames as u64 * 1000 / decoded.spec.rate as u64;
+                let behind = (self.output.delay() as f64 * self.stretch.speed()) as u64
+                    + self.stretch.latency();
+                let offset = end.saturating_sub(behind);
+                self.clock
+                    .set_position(self.offsets[*index] + offset, *index, offset);
+            }
+            None if *index + 1 < self.tracks.len() => {
+                let next = *index + 1;
+                debug!("Moving on to track {next}");
+                self.open(next, 0)?;
+            }
+            None => {
+                debug!("Reached the end of the book");
+                self.finish();
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Parks at the very end of the last track
+    fn finish(&mut self) {
+        let last = self.tracks.len().saturating_sub(1);
+        self.current = None;
+        let end = self.tracks.get(last).map(|track| track.duration);
+        self.clock
+            .set_position(self.duration(), last, end.unwrap_or_default());
+        self.clock.set_state(PlaybackState::Finished);
+    }
+
+    fn stop(&mut self) {
+        self.current = None;
+        self.output.clear();
+        self.clock.set_state(PlaybackState::Stopped);
+    }
+}
+
+/// Scales `samples` from `from` to `to`, moving evenly across the frames
+fn apply_gain(samples: &mut [f32], channels: usize, from: f32, to: f32) {
+    if from == 1.0 && to == 1.0 {
+        return;
+    }
+    let frames = (samples.len() / channels).max(1);
+    for (index, frame) in samples.chunks_mut(channels).enumerate() {
+        let gain = from + (to - from) * index as f32 / frames as f32;
+        for sample in frame {
+            *sample *= gain;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        env, fs,
+        path::PathBuf,
+        thread,
+        time::{Duration, Instant},
+    };
+
+    use crate::player::{NullOutput, PlaybackState, Player, PlayerSource, PlayerTrack};
+
+    use super::*;
+
+    const RATE: u32 = 8000;
+
+    /// A mono 16 bit wav of a quiet tone lasting `length` milliseconds
+    fn wav(name: &str, length: u32) -> PathBuf {
+        let samples = (0..RATE * length / 1000)
+            .flat_map(|frame| (((frame % 40) as i16 - 20) * 200).to_le_bytes())
+            .collect::<Vec<_>>();
+        let mut data = Vec::new();
+        data.extend(b"RIFF");
+        data.extend((36 + samples.len() as u32).to_le_bytes());
+        data.extend(b"WAVEfmt ");
+        data.extend(16u32.to_le_bytes());
+        data.extend(1u16.to_le_bytes()); // pcm
+        data.extend(1u16.to_le_bytes()); // channels
+        data.extend(RATE.to_le_bytes());
+        data.extend((RATE * 2).to_le_bytes());
+        data.extend(2u16.to_le_bytes());
+        data.extend(16u16.to_le_bytes());
+        data.extend(b"data");
+        data.extend((samples.len() as u32).to_le_bytes());
+        data.extend(samples);
+
+        let path = env::temp_dir().join(format!("engine-{}-{name}.wav", std::process::id()));
+        fs::write(&path, data).unwrap();
+        path
+    }
+
+    fn player() -> Player {
+        Player::new(|| Box::new(NullOutput::default()))
+    }
+
+    fn tracks(paths: &[&PathBuf], duration: u64) -> Box<[PlayerTrack]> {
+        paths
+            .iter()
+            .map(|path| PlayerTrack {
+                source: PlayerSource::File(path.to_path_buf()),
+                duration,
+            })
+            .collect()
+    }
+
+    fn wait_for(player: &Player, state: PlaybackState) {
+        let started = Instant::now();
+        while player.state() != state {
+            assert!(
+                started.elapsed() < Duration::from_secs(5),
+                "still {:?} waiting for {state:?}",
+                player.state()
+            );
+            thread::sleep(Duration::from_millis(10));
+        }
+    }
+
+    #[test]
+    fn plays_pauses_seeks_and_stops() {
+        let (first, second) = (wav("first", 1000), wav("second", 1000));
+        let player = player();
+
+        player
+            .load(
+                tracks(&[&first, &second], 1000),
+                TrackPosition {
+                    track: 0,
+                    offset: 500,
+                },
+            )
+            .unwrap();
+        wait_for(&player, PlaybackState::Paused);
+        assert_eq!(player.position(), 500);
+
+        player.play().unwrap();
+        wait_for(&player, PlaybackState::Playing);
+        thread::sleep(Duration::from_millis(300));
+        let playing = player.position();
+        // the clock follows what is heard, not how far ahead decoding is
+        assert!((600..1000).contains(&playing), "at {playing}");
+
+        player.pause().unwrap();
+        wait_for(&player, PlaybackState::Paused);
+        let paused = player.position();
+        thread::sleep(Duration::from_millis(200));
+        assert_eq!(player.position(), paused);
+
+        player.seek(1700).unwrap();
+        thread::sleep(Duration::from_millis(50));
+        assert_eq!(player.position(), 1700);
+        assert_eq!(
+            player.track_position(),
+            TrackPosition {
+                track: 1,
+                offset: 700
+            }
+        );
+        assert_eq!(player.state(), PlaybackState::Paused);
+
+        player.stop().unwrap();
+        wait_for(&player, PlaybackState::Stopped);
+        assert_eq!(player.position(), 0);
+
+        fs::remove_file(first).ok();
+        fs::remove_file(second).ok();
+    }
+
+    #[test]
+    fn finishes_and_starts_over() {
+        let path = wav("finish", 1000);
+        let player = player();
+
+        player
+            .load(
+                tracks(&[&path], 1000),
+                TrackPosition {
+                    track: 0,
+                    offset: 800,
+                },
+            )
+            .unwrap();
+        player.play().unwrap();
+        wait_for(&player, PlaybackState::Finished);
+        assert_eq!(player.position(), 1000);
+        assert_eq!(
+            player.track_position(),
+            TrackPosition {
+                track: 0,
+                offset: 1000
+            }
+        );
+
+        player.play().unwrap();
+        wait_for(&player, PlaybackState::Playing);
+        assert!(player.position() < 500, "at {}", player.position());
+
+        fs::remove_file(path).ok();
+    }
+
+    #[test]
+    fn loads_a_book_left_at_the_end_as_finished() {
+        let path = wav("restore", 1000);
+        let player = player();
+
+        player
+            .load(
+                tracks(&[&path], 1000),
+                TrackPosition {
+                    track: 0,
+                    offset: 1000,
+                },
+            )
+            .unwrap();
+        wait_for(&player, PlaybackState::Finished);
+        assert_eq!(player.position(), 1000);
+
+        player.play().unwrap();
+        wait_for(&player, PlaybackState::Playing);
+        assert!(player.position() < 500, "at {}", player.position());
+
+        fs::remove_file(path).ok();
+    }
+
+    #[test]
+    fn stops_when_a_file_is_missing() {
+        let player = player();
+        let missing = env::temp_dir().join("engine-missing.wav");
+
+        player
+            .load(tracks(&[&missing], 1000), TrackPosition::default())
+            .unwrap();
+        player.play().unwrap();
+        thread::sleep(Duration::from_millis(100));
+        assert_eq!(player.state(), PlaybackState::Stopped);
+        // only streams are worth loading again from another connection
+        assert_eq!(player.take_lost_stream(), None);
+    }
+}
@@ -0,0 +1,18 @@
+use derive_more::{Display, Error, From};
+
+pub type Result<T> = core::result::Result<T, Error>;
+
+#[derive(Debug, From, Error, Display)]
+pub enum Error {
+    Io(std::io::Error),
+    RequestFailed(reqwest::Error),
+    Decode(symphonia::core::errors::Error),
+    NoAudioTrack,
+    NoOutputDevice,
+    OutputConfig(cpal::DefaultStreamConfigError),
+    BuildStream(cpal::BuildStreamError),
+    PlayStream(cpal::PlayStreamError),
+    PauseStream(cpal::PauseStreamError),
+    UnsupportedSampleFormat,
+    EngineStopped,
+}
@@ -0,0 +1,107 @@
+use std::sync::Mutex;
+
+use audiopus::{
+    coder::{Decoder as Opus, GenericCtl},
+    packet::Packet as OpusPacket,
+    Channels, MutSignals, SampleRate,
+};
+use symphonia::core::{
+    audio::{AsAudioBufferRef, AudioBuffer, AudioBufferRef, Signal, SignalSpec},
+    codecs::{
+        CodecDescriptor, CodecParameters, Decoder, DecoderOptions, FinalizeResult, CODEC_TYPE_OPUS,
+    },
+    errors::{decode_error, unsupported_error, Result},
+    formats::Packet,
+    support_codec,
+};
+
+// opus always decodes at 48kHz, and a packet holds at most 120ms
+const SAMPLE_RATE: u32 = 48_000;
+const MAX_FRAMES: usize = 5760;
+
+/// Opus through libopus, symphonia has no decoder of its own for it
+pub(super) struct OpusDecoder {
+    params: CodecParameters,
+    // symphonia wants decoders to be Sync, it is only ever reached through `get_mut`
+    opus: Mutex<Opus>,
+    channels: usize,
+    // interleaved output of libopus, before it is split into channels
+    samples: Box<[f32]>,
+    buffer: AudioBuffer<f32>,
+}
+
+impl Decoder for OpusDecoder {
+    fn try_new(params: &CodecParameters, _options: &DecoderOptions) -> Result<Self> {
+        let Some(layout) = params.channels else {
+            return unsupported_error("opus: no channel layout");
+        };
+        // surround needs the multistream decoder
+        let (channels, count) = match layout.count() {
+            1 => (Channels::Mono, 1),
+            2 => (Channels::Stereo, 2),
+            _ => return unsupported_error("opus: only mono and stereo are supported"),
+        };
+        let Ok(opus) = Opus::new(SampleRate::Hz48000, channels) else {
+            return unsupported_error("opus: unable to create a decoder");
+        };
+
+        Ok(Self {
+            params: params.clone(),
+            opus: Mutex::new(opus),
+            channels: count,
+            samples: vec![0.0; MAX_FRAMES * count].into(),
+            buffer: AudioBuffer::new(MAX_FRAMES as u64, SignalSpec::new(SAMPLE_RATE, layout)),
+        })
+    }
+
+    fn supported_codecs() -> &'static [CodecDescriptor] {
+        &[support_codec!(CODEC_TYPE_OPUS, "opus", "Opus")]
+    }
+
+    fn reset(&mut self) {
+        if let Ok(opus) = self.opus.get_mut() {
+            opus.reset_state().ok();
+        }
+    }
+
+    fn codec_params(&self) -> &CodecParameters {
+        &self.params
+    }
+
+    fn decode(&mut self, packet: &Packet) -> Result<AudioBufferRef<'_>> {
+        let (Ok(input), Ok(output)) = (
+            OpusPacket::try_from(packet.buf()),
+            MutSignals::try_from(&mut self.samples[..]),
+        ) else {
+            return decode_error("opus: empty packet");
+        };
+        let Ok(opus) = self.opus.get_mut() else {
+            return decode_error("opus: decoder poisoned");
+        };
+        let Ok(frames) = opus.decode_float(Some(input), output, false) else {
+            return decode_error("opus: corrupt packet");
+        };
+
+        self.buffer.clear();
+        self.buffer.render_reserved(Some(frames));
+        for channel in 0..self.channels {
+            let samples = self.samples.iter().skip(channel).step_by(self.channels);
+            for (sample, decoded) in self.buffer.chan_mut(channel).iter_mut().zip(samples) {
+                *sample = *decoded;
+            }
+        }
+        // the pre-skip and end padding of the stream
+        self.buffer
+            .trim(packet.trim_start() as usize, packet.trim_end() as usize);
+
+        Ok(self.buffer.as_audio_buffer_ref())
+    }
+
+    fn finalize(&mut self) -> FinalizeResult {
+        FinalizeResult::default()
+    }
+
+    fn last_decoded(&self) -> AudioBufferRef<'_> {
+        self.buffer.as_audio_buffer_ref()
+    }
+}
@@ -0,0 +1,82 @@
+mod device;
+
+pub(crate) use device::CpalOutput;
+
+use std::{
+    thread,
+    time::{Duration, Instant},
+};
+
+use super::Result;
+
+// how much audio is queued ahead of the device, also the worst case seek latency
+const BUFFER_LENGTH: Duration = Duration::from_millis(200);
+
+/// Layout of interleaved `f32` samples
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub(crate) struct AudioSpec {
+    pub(crate) rate: u32,
+    pub(crate) channels: usize,
+}
+
+/// Where decoded audio ends up
+pub(crate) trait Output {
+    /// Queues interleaved samples, blocking while the output is full
+    fn write(&mut self, samples: &[f32], spec: AudioSpec) -> Result<()>;
+    fn pause(&mut self) -> Result<()>;
+    fn resume(&mut self) -> Result<()>;
+    /// Drops anything queued that hasn't been played yet
+    fn clear(&mut self);
+    /// Milliseconds of audio queued but not heard yet
+    fn delay(&self) -> u64;
+}
+
+pub(crate) type BoxedOutput = Box<dyn Output>;
+
+/// Discards everything it is given at the speed it would have played, used when there is no
+/// audio device or when testing
+#[derive(Default)]
+pub(crate) struct NullOutput {
+    // when everything written so far would have finished playing
+    finished: Option<Instant>,
+}
+
+impl Output for NullOutput {
+    fn write(&mut self, samples: &[f32], spec: AudioSpec) -> Result<()> {
+        let frames = samples.len() / spec.channels.max(1);
+        let length = Duration::from_secs_f64(frames as f64 / spec.rate.max(1) as f64);
+
+        let now = Instant::now();
+        let finished = self
+            .finished
+            .filter(|finished| *finished > now)
+            .unwrap_or(now)
+            + length;
+        self.finished = Some(finished);
+        if let Some(wait) = finished.checked_duration_since(now + BUFFER_LENGTH) {
+            thread::sleep(wait);
+        }
+
+        Ok(())
+    }
+
+    fn pause(&mut self) -> Result<()> {
+        self.finished = None;
+        Ok(())
+    }
+
+    fn resume(&mut self) -> Result<()> {
+        Ok(())
+    }
+
+    fn clear(&mut self) {
+        self.finished = None;
+    }
+
+    fn delay(&self) -> u64 {
+        self.finished
+            .and_then(|finished| finished.checked_duration_since(Instant::now()))
+            .map(|delay| delay.as_millis() as u64)
+            .unwrap_or_default()
+    }
+}
@@ -0,0 +1,223 @@
+use std::{
+    collections::VecDeque,
+    sync::{Arc, Condvar, Mutex},
+    time::Duration,
+};
+
+use cpal::{
+    traits::{DeviceTrait, HostTrait, StreamTrait},
+    FromSample, SampleFormat, SizedSample, Stream, StreamConfig,
+};
+use log::{debug, warn};
+
+use crate::player::{Error, Result};
+
+use super::{AudioSpec, Output, BUFFER_LENGTH};
+
+const WAIT_INTERVAL: Duration = Duration::from_millis(50);
+
+struct SharedBuffer {
+    samples: Mutex<VecDeque<f32>>,
+    drained: Condvar,
+}
+
+/// Plays through the default output device of the system
+pub(crate) struct CpalOutput {
+    stream: Stream,
+    buffer: Arc<SharedBuffer>,
+    spec: AudioSpec,
+    capacity: usize,
+    resampler: Resampler,
+    converted: Vec<f32>,
+}
+
+impl CpalOutput {
+    pub(crate) fn new() -> Result<Self> {
+        let device = cpal::default_host()
+            .default_output_device()
+            .ok_or(Error::NoOutputDevice)?;
+        let supported = device.default_output_config()?;
+        let config: StreamConfig = supported.config();
+        debug!(
+            "Opening output device {:?} at {config:?}",
+            device.name().unwrap_or_default()
+        );
+
+        let buffer = Arc::new(SharedBuffer {
+            samples: Mutex::new(VecDeque::new()),
+            drained: Condvar::new(),
+        });
+        let stream = match supported.sample_format() {
+            SampleFormat::F32 => build_stream::<f32>(&device, &config, buffer.clone()),
+            SampleFormat::I16 => build_stream::<i16>(&device, &config, buffer.clone()),
+            SampleFormat::U16 => build_stream::<u16>(&device, &config, buffer.clone()),
+            SampleFormat::I32 => build_stream::<i32>(&device, &config, buffer.clone()),
+            _ => Err(Error::UnsupportedSampleFormat),
+        }?;
+        stream.play()?;
+
+        let spec = AudioSpec {
+            rate: config.sample_rate.0,
+            channels: config.channels as usize,
+        };
+        let capacity =
+            (spec.rate as u128 * BUFFER_LENGTH.as_millis() / 1000) as usize * spec.channels;
+
+        Ok(Self {
+            stream,
+            buffer,
+            spec,
+            capacity,
+            resampler: Resampler::default(),
+            converted: Vec::new(),
+        })
+    }
+}
+
+fn build_stream<T>(
+    device: &cpal::Device,
+    config: &StreamConfig,
+    buffer: Arc<SharedBuffer>,
+) -> Result<Stream>
+where
+    T: SizedSample + FromSample<f32>,
+{
+    Ok(device.build_output_stream(
+        config,
+        move |data: &mut [T], _| {
+            // an underrun plays silence rather than blocking the audio thread
+            if let Ok(mut samples) = buffer.samples.lock() {
+                for sample in data.iter_mut() {
+                    *sample = T::from_sample(samples.pop_front().unwrap_or_default());
+                }
+            }
+            buffer.drained.notify_one();
+        },
+        |err| warn!("Audio output error: {err}"),
+        None,
+    )?)
+}
+
+impl Output for CpalOutput {
+    fn write(&mut self, samples: &[f32], spec: AudioSpec) -> Result<()> {
+        self.converted.clear();
+        self.resampler
+            .process(samples, spec, self.spec, &mut self.converted);
+
+        let mut remaining = self.converted.as_slice();
+        while !remaining.is_empty() {
+            let mut queued = self
+                .buffer
+                .samples
+                .lock()
+                .map_err(|_| Error::EngineStopped)?;
+            while queued.len() >= self.capacity {
+                // time out so a stalled device can't hang the engine forever
+                queued = self
+                    .buffer
+                    .drained
+                    .wait_timeout(queued, WAIT_INTERVAL)
+                    .map_err(|_| Error::EngineStopped)?
+                    .0;
+            }
+            let count = (self.capacity - queued.len()).min(remaining.len());
+            queued.extend(&remaining[..count]);
+            remaining = &remaining[count..];
+        }
+
+        Ok(())
+    }
+
+    fn pause(&mut self) -> Result<()> {
+        Ok(self.stream.pause()?)
+    }
+
+    fn resume(&mut self) -> Result<()> {
+        Ok(self.stream.play()?)
+    }
+
+    fn clear(&mut self) {
+        if let Ok(mut queued) = self.buffer.samples.lock() {
+            queued.clear();
+        }
+        self.resampler = Resampler::default();
+    }
+
+    fn delay(&self) -> u64 {
+        let queued = self
+            .buffer
+            .samples
+            .lock()
+            .map(|queued| queued.len())
+            .unwrap_or_default();
+        (queued / self.spec.channels) as u64 * 1000 / self.spec.rate as u64
+    }
+}
+
+/// Linear interpolation between sample rates and a simple up/down mix between channel counts
+#[derive(Default)]
+struct Resampler {
+    input: Option<AudioSpec>,
+    // position in the input between `last` and the next frame
+    position: f64,
+    last: Vec<f32>,
+}
+
+impl Resampler {
+    fn process(&mut self, samples: &[f32], from: AudioSpec, to: AudioSpec, out: &mut Vec<f32>) {
+        if self.input != Some(from) {
+            self.input = Some(from);
+            self.position = 0.0;
+            self.last.clear();
+        }
+
+        let frames = samples
+            .chunks_exact(from.channels)
+            .map(|frame| mix(frame, to.channels));
+        if from.rate == to.rate {
+            frames.for_each(|frame| out.extend(frame));
+            return;
+        }
+
+        let frames = frames.collect::<Vec<_>>();
+        let Some(last_frame) = frames.last().cloned() else {
+            return;
+        };
+        if self.last.is_empty() {
+            self.last = frames[0].clone();
+        }
+
+        // frame 0 is the last frame of the previous call, frame n is frames[n - 1]
+        let step = from.rate as f64 / to.rate as f64;
+        while self.position < frames.len() as f64 {
+            let index = self.position as usize;
+            let fraction = self.position.fract() as f32;
+            let current = match index {
+                0 => &self.last,
+                index => &frames[index - 1],
+            };
+            let next = &frames[index];
+            out.extend(
+                current
+                    .iter()
+                    .zip(next)
+                    .map(|(a, b)| a + (b - a) * fraction),
+            );
+            self.position += step;
+        }
+
+        self.position -= frames.len() as f64;
+        self.last = last_frame;
+    }
+}
+
+fn mix(frame: &[f32], channels: usize) -> Vec<f32> {
+    match (frame.len(), channels) {
+        (from, to) if from == to => frame.to_vec(),
+        (1, to) => vec![frame[0]; to],
+        (from, 1) => vec![frame.iter().sum::<f32>() / from as f32],
+        (_, to) => (0..to)
+            .map(|channel| frame.get(channel).copied().unwrap_or_default())
+            .collect(),
+    }
+}
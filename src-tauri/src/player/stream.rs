@@ -0,0 +1,111 @@
+use std::{
+    io::{self, ErrorKind, Read, Seek, SeekFrom},
+    time::Duration,
+};
+
+use log::debug;
+use reqwest::{
+    blocking::{Client, Response},
+    header::RANGE,
+    StatusCode,
+};
+use symphonia::core::io::MediaSource;
+
+use super::Result;
+
+// only limits how long a single read can stall, not the length of the stream
+const STREAM_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// A remote file read over http, seeking reopens the request with a `Range` header
+pub(super) struct HttpStream {
+    client: Client,
+    url: String,
+    position: u64,
+    length: Option<u64>,
+    response: Option<Response>,
+}
+
+impl HttpStream {
+    pub(super) fn open(url: &str) -> Result<Self> {
+        debug!("Streaming {}", url.split('?').next().unwrap_or_default());
+        let client = Client::builder().timeout(STREAM_TIMEOUT).build()?;
+        let response = client.get(url).send()?.error_for_status()?;
+        let ranges = response
+            .headers()
+            .get("Accept-Ranges")
+            .is_some_and(|value| value.as_bytes() == b"bytes");
+
+        Ok(Self {
+            length: response.content_length().filter(|_| ranges),
+            client,
+            url: url.to_string(),
+            position: 0,
+            response: Some(response),
+        })
+    }
+
+    fn reopen(&mut self) -> io::Result<&mut Response> {
+        let response = self
+            .client
+            .get(&self.url)
+            .header(RANGE, format!("bytes={}-", self.position))
+            .send()
+            .and_then(|response| response.error_for_status())
+            .map_err(io::Error::other)?;
+        if response.status() != StatusCode::PARTIAL_CONTENT {
+            return Err(io::Error::new(
+                ErrorKind::Unsupported,
+                "server ignored the range request",
+            ));
+        }
+
+        Ok(self.response.insert(response))
+    }
+}
+
+impl Read for HttpStream {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        if self.length.is_some_and(|length| self.position >= length) {
+            return Ok(0);
+        }
+        let response = match self.response.as_mut() {
+            Some(response) => response,
+            None => self.reopen()?,
+        };
+        let read = response.read(buf)?;
+        self.position += read as u64;
+
+        Ok(read)
+    }
+}
+
+impl Seek for HttpStream {
+    fn seek(&mut self, pos: SeekFrom) -> io::Result<u64> {
+        let position = match pos {
+            SeekFrom::Start(position) => Some(position),
+            SeekFrom::Current(offset) => self.position.checked_add_signed(offset),
+            SeekFrom::End(offset) => self
+                .length
+                .ok_or(ErrorKind::Unsupported)?
+                .checked_add_signed(offset),
+        }
+        .ok_or(ErrorKind::InvalidInput)?;
+
+        if position != self.position {
+            self.position = position;
+            self.response = None;
+        }
+
+        Ok(position)
+    }
+}
+
+impl MediaSource for HttpStream {
+    fn is_seekable(&self) -> bool {
+        self.length.is_some()
+    }
+
+    fn byte_len(&self) -> Option<u64> {
+        self.length
+    }
+}
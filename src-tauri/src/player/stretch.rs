@@ -0,0 +1,251 @@
+use std::f32::consts::PI;
+
+use super::AudioSpec;
+
+// lengths in milliseconds, tuned for speech
+const WINDOW_LENGTH: u32 = 30;
+const SEEK_LENGTH: u32 = 8;
+// only every nth frame is compared when searching, speech doesn't need more
+const SEEK_STRIDE: usize = 4;
+
+/// Changes the speed of audio without changing its pitch using WSOLA, every output hop a
+/// window of input is picked near where the speed says it should come from, at whichever
+/// offset lines up best with how the previous window would have carried on
+pub(super) struct TimeStretch {
+    speed: f64,
+    spec: Option<AudioSpec>,
+    // all in frames
+    hop: usize,
+    seek: usize,
+    window: Box<[f32]>,
+    input: Vec<f32>,
+    // where the next window should come from if nothing needed lining up
+    position: f64,
+    // where the previous window carries on
+    natural: usize,
+    overlap: Vec<f32>,
+}
+
+impl Default for TimeStretch {
+    fn default() -> Self {
+        Self {
+            speed: 1.0,
+            spec: None,
+            hop: 0,
+            seek: 0,
+            window: Box::new([]),
+            input: Vec::new(),
+            position: 0.0,
+            natural: 0,
+            overlap: Vec::new(),
+        }
+    }
+}
+
+impl TimeStretch {
+    pub(super) fn speed(&self) -> f64 {
+        self.speed
+    }
+
+    pub(super) fn set_speed(&mut self, speed: f64) {
+        if speed != self.speed {
+            self.speed = speed;
+            self.reset();
+        }
+    }
+
+    /// Forgets any buffered audio, for seeks and track changes
+    pub(super) fn reset(&mut self) {
+        self.input.clear();
+        self.overlap.clear();
+        self.position = 0.0;
+        self.natural = 0;
+    }
+
+    /// Milliseconds of input that have been taken in but not played out yet
+    pub(super) fn latency(&self) -> u64 {
+        match self.spec {
+            Some(spec) if self.speed != 1.0 => {
+                let buffered = (self.input.len() / spec.channels) as f64 - self.position;
+                (buffered.max(0.0) * 1000.0 / spec.rate as f64) as u64
+            }
+            _ => 0,
+        }
+    }
+
+    pub(super) fn process(&mut self, samples: &[f32], spec: AudioSpec, out: &mut Vec<f32>) {
+        out.clear();
+        if self.spec != Some(spec) {
+            self.configure(spec);
+        }
+        if self.speed == 1.0 {
+            out.extend_from_slice(samples);
+            return;
+        }
+
+        let channels = spec.channels;
+        self.input.extend_from_slice(samples);
+        let frames = self.input.len() / channels;
+
+        loop {
+            let nominal = self.position.round() as usize;
+            let earliest = nominal.saturating_sub(self.seek);
+            let latest = nominal + self.seek;
+            if latest + self.hop * 2 > frames || self.natural + self.hop * 2 > frames {
+                break;
+            }
+
+            let start = if self.overlap.is_empty() {
+                nominal
+            } else {
+                self.best_offset(earliest, latest)
+            };
+
+            // first half of the window finishes the previous one, the second half waits
+            for frame in 0..self.hop {
+                let weight = self.window[frame];
+                for channel in 0..channels {
+                    let sample = self.input[(start + frame) * channels + channel] * weight;
+                    let overlap = self
+                        .overlap
+                        .get(frame * channels + channel)
+                        .copied()
+                        .unwrap_or_default();
+                    out.push(sample + overlap);
+                }
+            }
+            self.overlap.clear();
+            for frame in self.hop..self.hop * 2 {
+                let weight = self.window[frame];
+                for channel in 0..channels {
+                    self.overlap
+                        .push(self.input[(start + frame) * channels + channel] * weight);
+                }
+            }
+
+            self.natural = start + self.hop;
+            self.position += self.hop as f64 * self.speed;
+        }
+
+        // drop whatever no future window can reach
+        let keep = (self.position as usize)
+            .saturating_sub(self.seek)
+            .min(self.natural);
+        if keep > 0 {
+            self.input.drain(..keep * channels);
+            self.position -= keep as f64;
+            self.natural -= keep;
+        }
+    }
+
+    fn configure(&mut self, spec: AudioSpec) {
+        self.spec = Some(spec);
+        self.hop = (spec.rate * WINDOW_LENGTH / 1000 / 2) as usize;
+        self.seek = (spec.rate * SEEK_LENGTH / 1000) as usize;
+        // a hann window overlapped by half sums to one
+        let length = self.hop * 2;
+        self.window = (0..length)
+            .map(|index| 0.5 - 0.5 * (2.0 * PI * index as f32 / length as f32).cos())
+            .collect();
+        self.reset();
+    }
+
+    /// The start between `earliest` and `latest` most like the natural continuation
+    fn best_offset(&self, earliest: usize, latest: usize) -> usize {
+        let channels = self.spec.map(|spec| spec.channels).unwrap_or(1);
+        let mono = |frame: usize| {
+            self.input[frame * channels..(frame + 1) * channels]
+                .iter()
+                .sum::<f32>()
+        };
+
+        let mut best = (f32::MIN, earliest);
+        for start in earliest..=latest {
+            let (mut correlation, mut energy) = (0.0, f32::EPSILON);
+            for frame in (0..self.hop).step_by(SEEK_STRIDE) {
+                let candidate = mono(start + frame);
+                correlation += candidate * mono(self.natural + frame);
+                energy += candidate * candidate;
+            }
+            // normalised so loud candidates don't win just for being loud
+            let similarity = correlation / energy.sqrt();
+            if similarity > best.0 {
+                best = (similarity, start);
+            }
+        }
+
+        best.1
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::f32::consts::TAU;
+
+    use super::*;
+
+    const SPEC: AudioSpec = AudioSpec {
+        rate: 8000,
+        channels: 2,
+    };
+
+    /// How many frames make up `length` milliseconds
+    fn frames(length: u32) -> usize {
+        (SPEC.rate * length / 1000) as usize
+    }
+
+    fn tone(length: u32) -> Vec<f32> {
+        (0..frames(length))
+            .map(|frame| 0.5 * (TAU * 440.0 * frame as f32 / SPEC.rate as f32).sin())
+            .flat_map(|sample| [sample, sample])
+            .collect()
+    }
+
+    fn stretch(samples: &[f32], speed: f64) -> Vec<f32> {
+        let mut stretch = TimeStretch::default();
+        stretch.set_speed(speed);
+        let (mut out, mut block_out) = (Vec::new(), Vec::new());
+        // in blocks the size a decoder hands over, the state has to carry between them
+        for block in samples.chunks(1024) {
+            stretch.process(block, SPEC, &mut block_out);
+            out.extend_from_slice(&block_out);
+        }
+        out
+    }
+
+    /// Sign changes per frame of the first channel, follows the pitch of a tone
+    fn crossing_rate(samples: &[f32]) -> f64 {
+        let left = samples
+            .iter()
+            .step_by(SPEC.channels)
+            .copied()
+            .collect::<Vec<_>>();
+        let crossings = left
+            .windows(2)
+            .filter(|pair| (pair[0] < 0.0) != (pair[1] < 0.0))
+            .count();
+        crossings as f64 / left.len() as f64
+    }
+
+    #[test]
+    fn changes_length_but_not_pitch() {
+        let input = tone(4000);
+        for speed in [0.5, 1.0, 3.0] {
+            let out = stretch(&input, speed);
+
+            // whatever is still buffered at the end is missing from the output
+            let expected = frames(4000) as f64 / speed;
+            let length = (out.len() / SPEC.channels) as f64;
+            assert!(
+                (length - expected).abs() <= frames(100) as f64,
+                "{length} frames at {speed}x, expected {expected}"
+            );
+
+            let (rate, expected) = (crossing_rate(&out), crossing_rate(&input));
+            assert!(
+                (rate / expected - 1.0).abs() < 0.02,
+                "crossing rate {rate} at {speed}x, expected {expected}"
+            );
+        }
+    }
+}
@@ -1,52 +1,193 @@
+use std::{
+    env,
+    io::Write,
+    sync::OnceLock,
+    time::{Duration, Instant},
+};
+
+use async_trait::async_trait;
+use futures_util::stream::{FuturesUnordered, StreamExt};
 use log::{debug, warn};
-use rayon::iter::{IntoParallelRefIterator, ParallelIterator};
+#[cfg(feature = "app")]
+use reqwest::header::CONTENT_TYPE;
+use reqwest::{
+    header::{HeaderMap, HeaderValue, RANGE},
+    IntoUrl, RequestBuilder, StatusCode,
+};
 use serde_json::Value;
 
 use super::{
-    resources::{Album, Library, PlexConnections, PlexResource},
-    Error, PlexPin, Result,
+    resources::{Album, AlbumPage, Library, PlexConnections, PlexResource, Track},
+    Error, PlexPin, PlexServer, Result,
 };
+#[cfg(feature = "app")]
+use super::{Image, Timeline};
 
 pub(super) type BoxedClient = Box<dyn PlexClient + Sync + Send>;
 
+const TOKEN_HEADER: &str = "X-Plex-Token";
+
+// by author then title, added at keeps albums that share both in the same order every time
+const ALBUM_SORT: &str = "artist.titleSort,album.titleSort,album.addedAt";
+
+// plex wants this on play state changes for items in a library
+#[cfg(feature = "app")]
+const LIBRARY_IDENTIFIER: &str = "com.plexapp.plugins.library";
+
+// the default client timeout is far too short for audiobook sized files, anything that
+// takes longer than this gets resumed with a new ranged request
+const DOWNLOAD_TIMEOUT: Duration = Duration::from_secs(5 * 60);
+
+#[async_trait]
 pub(super) trait PlexClient {
-    fn find_working_connection<'b>(
+    async fn find_working_connection<'b>(
         &self,
         resource: &'b PlexResource,
     ) -> Result<&'b PlexConnections>;
-    fn albums(&self, key: &str, uri: &str) -> Result<Vec<Album>>;
-    fn libraries(&self, uri: &str) -> Result<Vec<Library>>;
-    fn resources(&self) -> Result<Vec<PlexResource>>;
-    fn check_pin(&self, id: u64) -> Result<PlexPin>;
-    fn generate_pin(&self) -> Result<PlexPin>;
+    async fn ping(&self, uri: &str, token: Option<&str>) -> Result<Duration>;
+    async fn albums(
+        &self,
+        server: &PlexServer,
+        key: &str,
+        start: usize,
+        size: usize,
+    ) -> Result<AlbumPage>;
+    async fn albums_by_key(&self, server: &PlexServer, keys: &[&str]) -> Result<Vec<Album>>;
+    async fn tracks(&self, server: &PlexServer, key: &str) -> Result<Vec<Track>>;
+    async fn download(
+        &self,
+        server: &PlexServer,
+        part_key: &str,
+        offset: u64,
+        writer: &mut (dyn Write + Send),
+    ) -> Result<u64>;
+    #[cfg(feature = "app")]
+    async fn thumb(&self, server: &PlexServer, path: &str) -> Result<Image>;
+    #[cfg(feature = "app")]
+    async fn timeline(&self, server: &PlexServer, timeline: &Timeline) -> Result<()>;
+    #[cfg(feature = "app")]
+    async fn scrobble(&self, server: &PlexServer, key: &str) -> Result<()>;
+    #[cfg(feature = "app")]
+    async fn unscrobble(&self, server: &PlexServer, key: &str) -> Result<()>;
+    async fn libraries(&self, server: &PlexServer) -> Result<Vec<Library>>;
+    async fn resources(&self, token: &str) -> Result<Vec<PlexResource>>;
+    async fn check_pin(&self, id: u64) -> Result<PlexPin>;
+    async fn generate_pin(&self) -> Result<PlexPin>;
+}
+
+/// The one http client every request goes through so connections to plex are pooled
+fn shared_client() -> Result<reqwest::Client> {
+    static CLIENT: OnceLock<reqwest::Client> = OnceLock::new();
+    if let Some(client) = CLIENT.get() {
+        return Ok(client.clone());
+    }
+
+    debug!("Creating plex client with default headers");
+    let mut headers = HeaderMap::new();
+    headers.insert("Accept", HeaderValue::from_static("application/json"));
+    headers.insert("X-Plex-Provides", HeaderValue::from_static("player"));
+    headers.insert("X-Plex-Platform", HeaderValue::from_static(env::consts::OS));
+    headers.insert(
+        "X-Plex-Platform-Version",
+        HeaderValue::from_static(env::consts::ARCH),
+    );
+    headers.insert(
+        "X-Plex-Client-Name",
+        HeaderValue::from_static(env!("CARGO_PKG_NAME")),
+    );
+    headers.insert(
+        "X-Plex-Version",
+        HeaderValue::from_static(env!("CARGO_PKG_VERSION")),
+    );
+    headers.insert(
+        "X-Plex-Product",
+        HeaderValue::from_static(env!("CARGO_PKG_NAME")),
+    );
+    // headers.insert("X-Plex-Device",
+    //     HeaderValue::from_str(self.device.as_ref())?,
+
+    // );
+    // headers.insert("X-Plex-Device-Name",
+    //     HeaderValue::from_str(self.device_name.as_ref())?,
+    // );
+
+    let client = reqwest::Client::builder()
+        .default_headers(headers)
+        .timeout(Duration::from_secs(5))
+        .build()?;
+    Ok(CLIENT.get_or_init(|| client).clone())
 }
 
-impl PlexClient for reqwest::blocking::Client {
-    fn generate_pin(&self) -> Result<PlexPin> {
+/// The shared client with the headers identifying this install, tokens are added per request
+/// as each server has its own
+pub(super) struct PlexHttp {
+    client: reqwest::Client,
+    identity: HeaderMap,
+}
+
+impl PlexHttp {
+    pub(super) fn new(client_ident: &str, session_token: &str) -> Result<Self> {
+        let mut identity = HeaderMap::new();
+        identity.insert(
+            "X-Plex-Client-Identifier",
+            HeaderValue::from_str(client_ident)?,
+        );
+        identity.insert(
+            "X-Plex-Session-Identifier",
+            HeaderValue::from_str(session_token)?,
+        );
+
+        Ok(Self {
+            client: shared_client()?,
+            identity,
+        })
+    }
+
+    fn get(&self, uri: impl IntoUrl, token: Option<&str>) -> RequestBuilder {
+        let request = self.client.get(uri).headers(self.identity.clone());
+        match token {
+            Some(token) => request.header(TOKEN_HEADER, token),
+            None => request,
+        }
+    }
+}
+
+#[async_trait]
+impl PlexClient for PlexHttp {
+    async fn generate_pin(&self) -> Result<PlexPin> {
         let uri = "https://plex.tv/api/v2/pins";
         debug!("Generating pin using {uri}");
-        Ok(self.post(uri).send()?.json()?)
+        Ok(self
+            .client
+            .post(uri)
+            .headers(self.identity.clone())
+            .send()
+            .await?
+            .json()
+            .await?)
     }
 
-    fn check_pin(&self, id: u64) -> Result<PlexPin> {
+    async fn check_pin(&self, id: u64) -> Result<PlexPin> {
         let uri = format!("https://plex.tv/api/v2/pins/{}", id);
         debug!("Checking pin using {uri}");
-        Ok(self.get(uri).send()?.json()?)
+        Ok(self.get(uri, None).send().await?.json().await?)
     }
 
-    fn resources(&self) -> Result<Vec<PlexResource>> {
+    async fn resources(&self, token: &str) -> Result<Vec<PlexResource>> {
         let uri = "https://plex.tv/api/v2/resources";
         debug!("Retrieving resources using {uri}");
-        Ok(self.get(uri).send()?.json()?)
+        Ok(self.get(uri, Some(token)).send().await?.json().await?)
     }
 
-    fn libraries(&self, uri: &str) -> Result<Vec<Library>> {
-        let uri = format!("{uri}/library/sections/");
+    async fn libraries(&self, server: &PlexServer) -> Result<Vec<Library>> {
+        let uri = format!("{}/library/sections/", server.uri);
         debug!("Retrieving libraries using {uri}");
         Ok(serde_json::from_value(
-            self.get(uri)
-                .send()?
-                .json::<Value>()?
+            self.get(uri, Some(&server.token))
+                .send()
+                .await?
+                .json::<Value>()
+                .await?
                 .get("MediaContainer")
                 .ok_or(Error::MediaContainerNotFound)?
                 .get("Directory")
@@ -55,19 +196,27 @@ impl PlexClient for reqwest::blocking::Client {
         )?)
     }
 
-    fn albums(&self, uri: &str, key: &str) -> Result<Vec<Album>> {
-        let uri = format!("{uri}/library/sections/{key}/all");
-        debug!("Retrieving albums using {uri}");
+    async fn albums(
+        &self,
+        server: &PlexServer,
+        key: &str,
+        start: usize,
+        size: usize,
+    ) -> Result<AlbumPage> {
+        let uri = format!("{}/library/sections/{key}/all", server.uri);
+        debug!("Retrieving albums {start}..{} using {uri}", start + size);
 
         let data = serde_json::from_value(
-            self.get(uri)
-                .query(&[("type", "9")]) // only retrieve albums
-                .send()?
-                .json::<Value>()?
+            self.get(uri, Some(&server.token))
+                .query(&[("type", "9"), ("sort", ALBUM_SORT)]) // only retrieve albums
+                .header("X-Plex-Container-Start", start)
+                .header("X-Plex-Container-Size", size)
+                .send()
+                .await?
+                .json::<Value>()
+                .await?
                 .get("MediaContainer")
                 .ok_or(Error::MediaContainerNotFound)?
-                .get("Metadata")
-                .ok_or(Error::LibraryMetadataNotFound)?
                 .to_owned(),
         );
 
@@ -80,15 +229,212 @@ impl PlexClient for reqwest::blocking::Client {
         }
     }
 
-    fn find_working_connection<'b>(
+    async fn albums_by_key(&self, server: &PlexServer, keys: &[&str]) -> Result<Vec<Album>> {
+        // plex hands back every item in one go when the keys are joined up
+        let uri = format!("{}/library/metadata/{}", server.uri, keys.join(","));
+        debug!("Retrieving albums using {uri}");
+
+        Ok(serde_json::from_value(
+            self.get(uri, Some(&server.token))
+                .send()
+                .await?
+                .json::<Value>()
+                .await?
+                .get("MediaContainer")
+                .ok_or(Error::MediaContainerNotFound)?
+                .get("Metadata")
+                .ok_or(Error::LibraryMetadataNotFound)?
+                .to_owned(),
+        )?)
+    }
+
+    async fn tracks(&self, server: &PlexServer, key: &str) -> Result<Vec<Track>> {
+        let uri = format!("{}/library/metadata/{key}/children", server.uri);
+        debug!("Retrieving tracks using {uri}");
+
+        Ok(serde_json::from_value(
+            self.get(uri, Some(&server.token))
+                .query(&[("includeChapters", "1")])
+                .send()
+                .await?
+                .json::<Value>()
+                .await?
+                .get("MediaContainer")
+                .ok_or(Error::MediaContainerNotFound)?
+                .get("Metadata")
+                .ok_or(Error::LibraryMetadataNotFound)?
+                .to_owned(),
+        )?)
+    }
+
+    async fn download(
+        &self,
+        server: &PlexServer,
+        part_key: &str,
+        offset: u64,
+        writer: &mut (dyn Write + Send),
+    ) -> Result<u64> {
+        let uri = format!("{}{part_key}", server.uri);
+        debug!("Downloading part using {uri} from byte {offset}");
+
+        let mut request = self.get(uri, Some(&server.token)).timeout(DOWNLOAD_TIMEOUT);
+        if offset > 0 {
+            request = request.header(RANGE, format!("bytes={offset}-"));
+        }
+        let response = request.send().await?;
+        if offset > 0 && response.status() == StatusCode::RANGE_NOT_SATISFIABLE {
+            return Err(Error::RangeNotSatisfiable);
+        }
+        let mut response = response.error_for_status()?;
+
+        if offset > 0 && response.status() != StatusCode::PARTIAL_CONTENT {
+            return Err(Error::RangeNotSupported);
+        }
+
+        let mut written = 0;
+        while let Some(chunk) = response.chunk().await? {
+            writer.write_all(&chunk)?;
+            written += chunk.len() as u64;
+        }
+
+        Ok(written)
+    }
+
+    #[cfg(feature = "app")]
+    async fn thumb(&self, server: &PlexServer, path: &str) -> Result<Image> {
+        let uri = format!("{}{path}", server.uri);
+        debug!("Retrieving thumb using {uri}");
+
+        let response = self
+            .get(uri, Some(&server.token))
+            .send()
+            .await?
+            .error_for_status()?;
+        let content_type = response
+            .headers()
+            .get(CONTENT_TYPE)
+            .and_then(|content_type| content_type.to_str().ok())
+            .unwrap_or("image/jpeg")
+            .into();
+
+        Ok(Image {
+            content_type,
+            data: response.bytes().await?.to_vec(),
+        })
+    }
+
+    #[cfg(feature = "app")]
+    async fn timeline(&self, server: &PlexServer, timeline: &Timeline) -> Result<()> {
+        let uri = format!("{}/:/timeline", server.uri);
+        debug!(
+            "Reporting {} {} at {} using {uri}",
+            timeline.rating_key, timeline.state, timeline.time
+        );
+
+        self.get(uri, Some(&server.token))
+            .query(&[
+                ("ratingKey", timeline.rating_key.to_string()),
+                ("key", format!("/library/metadata/{}", timeline.rating_key)),
+                ("state", timeline.state.to_string()),
+                ("time", timeline.time.to_string()),
+                ("duration", timeline.duration.to_string()),
+                ("identifier", LIBRARY_IDENTIFIER.to_string()),
+            ])
+            .send()
+            .await?
+            .error_for_status()?;
+
+        Ok(())
+    }
+
+    #[cfg(feature = "app")]
+    async fn scrobble(&self, server: &PlexServer, key: &str) -> Result<()> {
+        let uri = format!("{}/:/scrobble", server.uri);
+        debug!("Scrobbling {key} using {uri}");
+
+        self.get(uri, Some(&server.token))
+            .query(&[("key", key), ("identifier", LIBRARY_IDENTIFIER)])
+            .send()
+            .await?
+            .error_for_status()?;
+
+        Ok(())
+    }
+
+    #[cfg(feature = "app")]
+    async fn unscrobble(&self, server: &PlexServer, key: &str) -> Result<()> {
+        let uri = format!("{}/:/unscrobble", server.uri);
+        debug!("Unscrobbling {key} using {uri}");
+
+        self.get(uri, Some(&server.token))
+            .query(&[("key", key), ("identifier", LIBRARY_IDENTIFIER)])
+            .send()
+            .await?
+            .error_for_status()?;
+
+        Ok(())
+    }
+
+    /// Probes every connection at once and keeps the best kind that answers, fastest first
+    async fn find_working_connection<'b>(
         &self,
         resource: &'b PlexResource,
     ) -> Result<&'b PlexConnections> {
-        resource
-            .connections_ref()
-            .par_iter()
-            .find_any(|conn| self.get(conn.uri_ref()).send().is_ok())
-            .ok_or(Error::NoValidConnections)
+        let token = resource.access_token_ref();
+        let connections = resource.connections_ref();
+        let mut pending = connections
+            .iter()
+            .map(|conn| conn.kind())
+            .collect::<Vec<_>>();
+        let mut probes = connections
+            .iter()
+            .map(|conn| async move { (conn, self.ping(conn.uri_ref(), token).await) })
+            .collect::<FuturesUnordered<_>>();
+
+        let mut best: Option<(&PlexConnections, Duration)> = None;
+        while let Some((conn, latency)) = probes.next().await {
+            if let Some(index) = pending.iter().position(|kind| *kind == conn.kind()) {
+                pending.swap_remove(index);
+            }
+            match latency {
+                Ok(latency) => {
+                    debug!(
+                        "{} {} connection at {} answered in {latency:?}",
+                        conn.kind(),
+                        conn.protocol_ref(),
+                        conn.address_ref()
+                    );
+                    if best.is_none_or(|(best, best_latency)| {
+                        (conn.kind(), latency) < (best.kind(), best_latency)
+                    }) {
+                        best = Some((conn, latency));
+                    }
+                }
+                Err(err) => debug!("{} did not answer: {err}", conn.uri_ref()),
+            }
+
+            // nothing still waiting on an answer could be better than what we have
+            if let Some((best, _)) = best {
+                if pending.iter().all(|kind| *kind >= best.kind()) {
+                    break;
+                }
+            }
+        }
+
+        let (conn, latency) = best.ok_or(Error::NoValidConnections)?;
+        debug!(
+            "Using {} connection {} ({latency:?})",
+            conn.kind(),
+            conn.uri_ref()
+        );
+        Ok(conn)
+    }
+
+    /// Time for the server to answer, any answer means it can be reached
+    async fn ping(&self, uri: &str, token: Option<&str>) -> Result<Duration> {
+        let started = Instant::now();
+        self.get(uri, token).send().await?;
+        Ok(started.elapsed())
     }
 }
 
@@ -97,32 +443,81 @@ pub(crate) mod mock {
     use super::*;
     pub(crate) struct MockPlexClient;
 
+    #[async_trait]
     impl PlexClient for MockPlexClient {
-        fn generate_pin(&self) -> Result<PlexPin> {
+        async fn generate_pin(&self) -> Result<PlexPin> {
             Ok(PlexPin::default())
         }
 
-        fn check_pin(&self, _id: u64) -> Result<PlexPin> {
+        async fn check_pin(&self, _id: u64) -> Result<PlexPin> {
             Ok(PlexPin::authed_pin())
         }
 
-        fn resources(&self) -> Result<Vec<PlexResource>> {
+        async fn resources(&self, _token: &str) -> Result<Vec<PlexResource>> {
+            todo!()
+        }
+
+        async fn libraries(&self, _server: &PlexServer) -> Result<Vec<Library>> {
+            todo!()
+        }
+
+        async fn albums(
+            &self,
+            _server: &PlexServer,
+            _key: &str,
+            _start: usize,
+            _size: usize,
+        ) -> Result<AlbumPage> {
+            todo!()
+        }
+
+        async fn albums_by_key(&self, _server: &PlexServer, _keys: &[&str]) -> Result<Vec<Album>> {
+            todo!()
+        }
+
+        async fn tracks(&self, _server: &PlexServer, _key: &str) -> Result<Vec<Track>> {
+            todo!()
+        }
+
+        async fn download(
+            &self,
+            _server: &PlexServer,
+            _part_key: &str,
+            _offset: u64,
+            _writer: &mut (dyn Write + Send),
+        ) -> Result<u64> {
+            todo!()
+        }
+
+        #[cfg(feature = "app")]
+        async fn thumb(&self, _server: &PlexServer, _path: &str) -> Result<Image> {
+            todo!()
+        }
+
+        #[cfg(feature = "app")]
+        async fn timeline(&self, _server: &PlexServer, _timeline: &Timeline) -> Result<()> {
             todo!()
         }
 
-        fn libraries(&self, _uri: &str) -> Result<Vec<Library>> {
+        #[cfg(feature = "app")]
+        async fn scrobble(&self, _server: &PlexServer, _key: &str) -> Result<()> {
             todo!()
         }
 
-        fn albums(&self, _key: &str, _uri: &str) -> Result<Vec<Album>> {
+        #[cfg(feature = "app")]
+        async fn unscrobble(&self, _server: &PlexServer, _key: &str) -> Result<()> {
             todo!()
         }
 
-        fn find_working_connection<'b>(
+        async fn find_working_connection<'b>(
             &self,
             _resource: &'b PlexResource,
         ) -> Result<&'b PlexConnections> {
             todo!()
         }
+
+        async fn ping(&self, _uri: &str, _token: Option<&str>) -> Result<Duration> {
+            todo!()
+        }
     }
 }
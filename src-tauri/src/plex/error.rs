@@ -9,22 +9,29 @@ pub enum Error {
     InvalidHeader(reqwest::header::InvalidHeaderValue),
     RequestFailed(reqwest::Error),
     InvalidJson(serde_json::Error),
+    Io(std::io::Error),
     WaitingOnPin,
     NoServerSelected,
     NoLibrarySelected,
     InvalidSeverName,
     NotAuthenticated,
-    NoResourcesFound,
     NoAlbumFound,
     InvalidLibraryName,
     NoValidConnections,
     MediaContainerNotFound,
     LibraryDirectoryNotFound,
     LibraryMetadataNotFound,
-    NoAlbumsFound,
-    NoLibrariesFound,
-    NoThumbnailFound,
     FailedToLockState,
+    RangeNotSupported,
+    /// Asked to resume from the end of a part, it is already complete
+    RangeNotSatisfiable,
+}
+
+impl Error {
+    /// The server couldn't be reached at all, rather than answering with an error
+    pub(crate) fn is_connection_lost(&self) -> bool {
+        matches!(self, Self::RequestFailed(err) if err.is_connect() || err.is_timeout())
+    }
 }
 
 impl<T> From<PoisonError<T>> for Error {
@@ -1,30 +1,44 @@
-use std::{
-    collections::HashMap,
-    env,
-    sync::{Arc, RwLock},
-    time::Duration,
-};
+use std::{collections::HashMap, io::Write, ops::Range, sync::Arc};
 
 use derive_more::Display;
-use log::debug;
+use log::{debug, info, warn};
 use rayon::iter::{IntoParallelIterator, IntoParallelRefIterator, ParallelIterator};
-use reqwest::header::{HeaderMap, HeaderValue};
 use serde::{Deserialize, Serialize};
 use uuid::Uuid;
 
+#[cfg(feature = "app")]
+use crate::chapters::Chapter;
+use crate::player::PlaylistItem;
+
 use super::{
-    client::BoxedClient,
-    resources::{Album, Library, PlexResource},
+    client::{BoxedClient, PlexHttp},
+    resources::{Album, AlbumPage, Library, PlexResource, Track, TrackPart},
     Error, Result,
 };
 
-#[derive(Serialize, Deserialize)]
+#[derive(Serialize, Deserialize, Clone)]
 struct SelectedConnection {
     name: Box<str>,
     uri: Arc<str>,
+    /// The servers access token, older stores fall back on the users token
+    #[serde(default)]
+    token: Option<Arc<str>>,
+}
+
+/// A server to send requests to along with the token it accepts
+#[derive(Clone)]
+pub(crate) struct PlexServer {
+    pub(super) uri: Arc<str>,
+    pub(super) token: Arc<str>,
+}
+
+#[cfg(feature = "app")]
+pub(crate) struct Image {
+    pub(crate) content_type: Box<str>,
+    pub(crate) data: Vec<u8>,
 }
 
-#[derive(Serialize, Deserialize)]
+#[derive(Serialize, Deserialize, Clone)]
 pub struct PlexData {
     client_ident: Box<str>,
     user_token: Option<Arc<str>>,
@@ -46,54 +60,120 @@ fn default_session() -> Box<str> {
     Uuid::new_v4().to_string().into()
 }
 
-#[derive(Display)]
+/// Cheap to clone, requests are made on a clone so the state doesn't have to stay locked while
+/// plex answers and the clone is put back once they are done
+#[derive(Display, Clone)]
 #[display(fmt = "{}", "serde_json::to_string(&self.data).unwrap()")]
 pub(crate) struct Plex {
     data: PlexData,
-    client: Arc<RwLock<BoxedClient>>,
+    client: Arc<BoxedClient>,
 
-    // Generated on initialization, may move these to a local store for caching
-    resources: HashMap<Arc<str>, PlexResource>,
-    libraries: HashMap<Arc<str>, Library>,
+    // Filled by `refresh`, may move these to a local store for caching
+    resources: Arc<HashMap<Arc<str>, PlexResource>>,
+    libraries: Arc<HashMap<Arc<str>, Library>>,
+    // Filled a page at a time as the library is looked through
     albums: Arc<HashMap<Arc<str>, Album>>,
+    album_order: Arc<AlbumOrder>,
+}
+
+/// Albums are paged through this many at a time when all of them are needed
+const ALBUM_PAGE_SIZE: usize = 100;
+
+/// Where the albums seen so far sit in the selected library's order
+#[derive(Default, Clone)]
+struct AlbumOrder {
+    /// How many albums the library has, known once a page has been fetched
+    total: Option<usize>,
+    keys: Vec<Option<Arc<str>>>,
+}
+
+impl AlbumOrder {
+    fn range(&self, start: usize, size: usize) -> Range<usize> {
+        let end = self.total.unwrap_or(usize::MAX).min(start + size);
+        start.min(end)..end
+    }
+
+    fn has_page(&self, start: usize, size: usize) -> bool {
+        self.total.is_some()
+            && self.keys[self.range(start, size)]
+                .iter()
+                .all(|key| key.is_some())
+    }
+
+    #[cfg(feature = "app")]
+    fn page(&self, start: usize, size: usize) -> impl Iterator<Item = &Arc<str>> {
+        self.keys[self.range(start, size)].iter().flatten()
+    }
+
+    fn insert(&mut self, start: usize, total: usize, keys: impl Iterator<Item = Arc<str>>) {
+        // the library changed on the server, anything from before is out of place
+        if self.total.is_some_and(|known| known != total) {
+            self.keys.clear();
+        }
+        self.total = Some(total);
+        self.keys.resize(total, None);
+        for (slot, key) in self.keys.iter_mut().skip(start).zip(keys) {
+            *slot = Some(key);
+        }
+    }
+
+    /// Fills in the pages `other` has that this doesn't
+    #[cfg(feature = "app")]
+    fn merge(&mut self, other: &Self) {
+        if self.total != other.total {
+            if other.total.is_some() {
+                *self = other.clone();
+            }
+            return;
+        }
+        for (slot, key) in self.keys.iter_mut().zip(other.keys.iter()) {
+            if slot.is_none() {
+                slot.clone_from(key);
+            }
+        }
+    }
 }
 
 impl Plex {
-    fn refresh(&mut self) -> Result<()> {
-        let client = self.client.read()?;
-        self.resources = self.data.get_resources(&client).unwrap_or_default();
-        self.libraries = self.data.get_libraries(&client).unwrap_or_default();
-        self.albums = Arc::new(self.data.get_albums(&client).unwrap_or_default());
+    /// Fetches everything that is cached, nothing is fetched when loaded from the store
+    pub(crate) async fn refresh(&mut self) -> Result<()> {
+        if !self.has_user() {
+            return Ok(());
+        }
+        let client = self.client.clone();
+        self.resources = Arc::new(self.data.get_resources(&client).await.unwrap_or_default());
+        // the servers own token may have changed since it was stored
+        self.update_server_token();
+        if let Err(err) = self.check_connection().await {
+            warn!("Lost the connection to the selected server: {err}");
+        }
+        self.libraries = Arc::new(self.fetch_libraries().await.unwrap_or_default());
+        self.reset_albums();
 
         Ok(())
     }
 
-    pub(crate) fn create_login_pin(&self) -> Result<PlexPin> {
+    pub(crate) async fn create_login_pin(&self) -> Result<PlexPin> {
         debug!("Generating login pin");
 
-        let client = self.client.read()?;
-        let pin = client.generate_pin()?;
+        let pin = self.client.generate_pin().await?;
 
         debug!("Created pin {:#?}", pin);
 
         Ok(pin)
     }
 
-    fn refresh_client(&self) -> Result<()> {
-        let mut client = self.client.write()?;
-        *client = self.data.create_client()?;
+    fn refresh_client(&mut self) -> Result<()> {
+        self.client = Arc::new(self.data.create_client()?);
         Ok(())
     }
 
-    pub(crate) fn check_pin(&mut self, pin: &PlexPin) -> Result<()> {
+    pub(crate) async fn check_pin(&mut self, pin: &PlexPin) -> Result<()> {
         debug!("Checking login pin status");
-        let client = self.client.read()?;
-        let checked_pin = client.check_pin(pin.id)?;
+        let checked_pin = self.client.check_pin(pin.id).await?;
         self.data.user_token = checked_pin.auth_token;
-        drop(client);
         if self.data.user_token.is_some() {
-            self.refresh_client()?;
-            self.refresh()?;
+            self.refresh().await?;
             Ok(())
         } else {
             Err(Error::WaitingOnPin)
@@ -108,26 +188,122 @@ impl Plex {
             .collect()
     }
 
-    pub(crate) fn select_server(&mut self, server: &str) -> Result<()> {
+    pub(crate) async fn select_server(&mut self, server: &str) -> Result<()> {
         debug!("selecting resource");
-        let resource = self.resources.get(server).ok_or(Error::InvalidSeverName)?;
+        self.connect(server).await?;
+
+        self.libraries = Arc::new(self.fetch_libraries().await.unwrap_or_default());
+        self.reset_albums();
 
-        let client = self.client.read()?;
+        Ok(())
+    }
 
-        let selected = client
+    /// Picks the best connection that answers for `server`
+    async fn connect(&mut self, server: &str) -> Result<()> {
+        let resource = self.resources.get(server).ok_or(Error::InvalidSeverName)?;
+        let selected = self
+            .client
             .find_working_connection(resource)
+            .await
             .map(|conn| SelectedConnection {
                 name: server.into(),
                 uri: conn.clone_uri(),
+                token: resource.access_token_clone(),
             })?;
 
         self.data.selected_connection = Some(selected);
+        Ok(())
+    }
 
-        self.libraries = self.data.get_libraries(&client).unwrap_or_default();
-        self.albums = Arc::new(self.data.get_albums(&client).unwrap_or_default());
+    /// Probes the servers connections again when the one in use has stopped answering, a
+    /// server seen from home and then away will only answer on its remote address
+    pub(crate) async fn check_connection(&mut self) -> Result<()> {
+        let Ok(server) = self.data.server() else {
+            return Ok(());
+        };
+        match self.client.ping(&server.uri, Some(&server.token)).await {
+            Ok(_) => Ok(()),
+            Err(err) => self.failover(err).await,
+        }
+    }
 
-        Ok(())
+    /// Moves to another connection to the selected server after `err` from the one in use
+    async fn failover(&mut self, err: Error) -> Result<()> {
+        let Some(name) = self.get_selected_server().map(Box::<str>::from) else {
+            return Err(err);
+        };
+        info!("{name} stopped answering ({err}), looking for another connection");
+
+        // nothing was fetched if we started out offline
+        if self.resources.is_empty() {
+            let client = self.client.clone();
+            self.resources = Arc::new(self.data.get_resources(&client).await?);
+            self.update_server_token();
+        }
+        self.connect(&name).await
+    }
+
+    /// Libraries on the selected server
+    async fn fetch_libraries(&mut self) -> Result<HashMap<Arc<str>, Library>> {
+        let client = self.client.clone();
+        match self.data.get_libraries(&client).await {
+            Err(err) if err.is_connection_lost() => {
+                self.failover(err).await?;
+                self.data.get_libraries(&client).await
+            }
+            libraries => libraries,
+        }
     }
+
+    /// Picks up the connection `from` moved to when the selected one stopped answering, true
+    /// when there was anything to pick up
+    #[cfg(feature = "app")]
+    pub(crate) fn adopt_connection(&mut self, from: &Plex) -> bool {
+        match (
+            self.data.selected_connection.as_mut(),
+            from.data.selected_connection.as_ref(),
+        ) {
+            (Some(selected), Some(found))
+                if selected.name == found.name && selected.uri != found.uri =>
+            {
+                *selected = found.clone();
+                true
+            }
+            _ => false,
+        }
+    }
+
+    /// Picks up the user `from` signed in as, with the servers and libraries it found for them
+    #[cfg(feature = "app")]
+    pub(crate) fn adopt_signin(&mut self, from: &Plex) {
+        self.data.user_token.clone_from(&from.data.user_token);
+        self.data
+            .selected_connection
+            .clone_from(&from.data.selected_connection);
+        self.resources = from.resources.clone();
+        self.libraries = from.libraries.clone();
+        self.reset_albums();
+    }
+
+    /// Picks up the server `from` selected and the libraries on it
+    #[cfg(feature = "app")]
+    pub(crate) fn adopt_server(&mut self, from: &Plex) {
+        self.data
+            .selected_connection
+            .clone_from(&from.data.selected_connection);
+        self.libraries = from.libraries.clone();
+        self.reset_albums();
+    }
+
+    /// Picks up the library `from` selected
+    #[cfg(feature = "app")]
+    pub(crate) fn adopt_library(&mut self, from: &Plex) {
+        self.data
+            .selected_library
+            .clone_from(&from.data.selected_library);
+        self.reset_albums();
+    }
+
     pub(crate) fn get_libraries(&self) -> Box<[&str]> {
         self.libraries
             .par_iter()
@@ -135,7 +311,7 @@ impl Plex {
             .collect()
     }
 
-    pub(crate) fn select_library(&mut self, server: &str) -> Result<()> {
+    pub(crate) async fn select_library(&mut self, server: &str) -> Result<()> {
         debug!("selecting library");
         let library = self
             .libraries
@@ -143,16 +319,121 @@ impl Plex {
             .ok_or(Error::InvalidLibraryName)?;
 
         self.data.selected_library = Some(library.clone());
+        self.reset_albums();
 
-        let client = self.client.read()?;
-        self.albums = Arc::new(self.data.get_albums(&client).unwrap_or_default());
+        Ok(())
+    }
+
+    /// Albums are fetched again as they are asked for
+    fn reset_albums(&mut self) {
+        self.albums = Default::default();
+        self.album_order = Default::default();
+    }
+
+    /// Fetches albums `start..start + size` of the selected library unless they already were
+    pub(crate) async fn fetch_album_page(&mut self, start: usize, size: usize) -> Result<()> {
+        if self.album_order.has_page(start, size) {
+            return Ok(());
+        }
+
+        let client = self.client.clone();
+        let page = match self.data.get_album_page(&client, start, size).await {
+            Err(err) if err.is_connection_lost() => {
+                self.failover(err).await?;
+                self.data.get_album_page(&client, start, size).await?
+            }
+            page => page?,
+        };
+        self.add_albums(start, page);
+        Ok(())
+    }
+
+    /// Pages through the whole of the selected library
+    pub(crate) async fn fetch_all_albums(&mut self) -> Result<()> {
+        let mut start = 0;
+        while self.album_order.total.is_none_or(|total| start < total) {
+            self.fetch_album_page(start, ALBUM_PAGE_SIZE).await?;
+            start += ALBUM_PAGE_SIZE;
+        }
 
         Ok(())
     }
-    pub(crate) fn get_albums(&self) -> Result<Box<[&Album]>> {
+
+    /// Albums `start..start + size` in the selected library's order, as far as they are fetched
+    #[cfg(feature = "app")]
+    pub(crate) fn get_album_page(&self, start: usize, size: usize) -> Box<[&Album]> {
+        debug!("get albums {start}..{}", start + size);
+        if self.album_order.total.is_none() {
+            return Box::new([]);
+        }
+
+        self.album_order
+            .page(start, size)
+            .filter_map(|key| self.albums.get(key))
+            .collect()
+    }
+
+    /// Every album fetched so far in the selected library's order
+    pub(crate) fn get_albums(&self) -> Box<[&Album]> {
         debug!("get albums");
 
-        Ok(self.albums.values().collect())
+        self.album_order
+            .keys
+            .iter()
+            .flatten()
+            .filter_map(|key| self.albums.get(key))
+            .collect()
+    }
+
+    /// Makes sure `keys` can be found with `get_album`, for books opened before that haven't
+    /// come up in the library yet
+    pub(crate) async fn load_albums(&mut self, keys: &[&str]) -> Result<()> {
+        let missing = keys
+            .iter()
+            .copied()
+            .filter(|key| !self.albums.contains_key(*key))
+            .collect::<Vec<_>>();
+        if missing.is_empty() {
+            return Ok(());
+        }
+
+        debug!("load albums: {missing:?}");
+        let client = self.client.clone();
+        let albums = match client.albums_by_key(&self.data.server()?, &missing).await {
+            Err(err) if err.is_connection_lost() => {
+                self.failover(err).await?;
+                client.albums_by_key(&self.data.server()?, &missing).await?
+            }
+            albums => albums?,
+        };
+        Arc::make_mut(&mut self.albums)
+            .extend(albums.into_iter().map(|album| album.into_key_val()));
+        Ok(())
+    }
+
+    fn add_albums(&mut self, start: usize, page: AlbumPage) {
+        let total = page.total(start);
+        let albums = page.into_albums();
+        Arc::make_mut(&mut self.album_order).insert(
+            start,
+            total,
+            albums.iter().map(|album| album.key_clone()),
+        );
+        Arc::make_mut(&mut self.albums)
+            .extend(albums.into_iter().map(|album| album.into_key_val()));
+    }
+
+    /// Picks up albums `from` fetched while it was away from the state
+    #[cfg(feature = "app")]
+    pub(crate) fn adopt_albums(&mut self, from: &Plex) {
+        if self.get_selected_library() != from.get_selected_library() {
+            return;
+        }
+        let albums = Arc::make_mut(&mut self.albums);
+        for (key, album) in from.albums.iter() {
+            albums.entry(key.clone()).or_insert_with(|| album.clone());
+        }
+        Arc::make_mut(&mut self.album_order).merge(&from.album_order);
     }
 
     pub(crate) fn get_album(&self, key: &str) -> Result<&Album> {
@@ -161,6 +442,132 @@ impl Plex {
         self.albums.get(key).ok_or(Error::NoAlbumFound)
     }
 
+    pub(crate) async fn get_tracks(&mut self, album_key: &str) -> Result<Vec<Track>> {
+        debug!("get tracks: {album_key}");
+
+        let client = self.client.clone();
+        match self.data.get_tracks(&client, album_key).await {
+            Err(err) if err.is_connection_lost() => {
+                self.failover(err).await?;
+                self.data.get_tracks(&client, album_key).await
+            }
+            tracks => tracks,
+        }
+    }
+
+    /// Where the server thinks the album is up to, from the most recently played track
+    #[cfg(feature = "app")]
+    pub(crate) async fn get_view_offset(&mut self, album_key: &str) -> Result<Option<ViewOffset>> {
+        debug!("get view offset: {album_key}");
+        if !self.get_album(album_key)?.has_been_viewed() {
+            return Ok(None);
+        }
+
+        let tracks = self.get_tracks(album_key).await?;
+        let Some((index, track)) = tracks
+            .iter()
+            .enumerate()
+            .filter(|(_, track)| track.last_viewed_at().is_some())
+            .max_by_key(|(_, track)| track.last_viewed_at())
+        else {
+            return Ok(None);
+        };
+        let viewed_at = track.last_viewed_at().unwrap_or_default() * 1000;
+
+        // a track that was listened to the end has no offset, so pick up at the next one
+        let view = match (track.view_offset(), tracks.get(index + 1)) {
+            (Some(offset), _) => Some((track, offset)),
+            (None, Some(next)) if track.played() => Some((next, 0)),
+            (None, _) => None,
+        };
+
+        Ok(view.map(|(track, offset)| ViewOffset {
+            rating_key: track.key_clone(),
+            offset,
+            viewed_at,
+        }))
+    }
+
+    /// Chapters for the whole album laid out one track after another
+    #[cfg(feature = "app")]
+    pub(crate) async fn get_chapters(&mut self, album_key: &str) -> Result<Box<[Chapter]>> {
+        debug!("get chapters: {album_key}");
+        let mut offset = 0;
+
+        Ok(self
+            .get_tracks(album_key)
+            .await?
+            .iter()
+            .flat_map(|track| {
+                let chapters = track.chapters(offset);
+                offset += track.duration();
+                chapters
+            })
+            .collect())
+    }
+
+    /// Every file of the album in playback order
+    pub(crate) async fn get_playlist(&mut self, album_key: &str) -> Result<Box<[PlaylistItem]>> {
+        debug!("get playlist: {album_key}");
+
+        Ok(self
+            .get_tracks(album_key)
+            .await?
+            .iter()
+            .flat_map(|track| track.parts().map(move |part| (track, part)))
+            .enumerate()
+            .map(|(index, (track, part))| PlaylistItem {
+                rating_key: track.key_clone(),
+                part_key: part.key_ref().into(),
+                file_name: part.file_name(index).into(),
+                duration: part.duration().unwrap_or_else(|| track.duration()),
+            })
+            .collect())
+    }
+
+    /// Album art and other images from the selected server
+    #[cfg(feature = "app")]
+    pub(crate) async fn get_thumb(&mut self, path: &str) -> Result<Image> {
+        debug!("get thumb: {path}");
+
+        let client = self.client.clone();
+        match client.thumb(&self.data.server()?, path).await {
+            Err(err) if err.is_connection_lost() => {
+                self.failover(err).await?;
+                client.thumb(&self.data.server()?, path).await
+            }
+            image => image,
+        }
+    }
+
+    /// Picks up the token for the selected server from its resource
+    fn update_server_token(&mut self) {
+        let Some(selected) = self.data.selected_connection.as_mut() else {
+            return;
+        };
+        if let Some(token) = self
+            .resources
+            .get(selected.name.as_ref())
+            .and_then(|resource| resource.access_token_clone())
+        {
+            selected.token = Some(token);
+        }
+    }
+
+    pub(crate) fn downloader(&self) -> Result<PlexDownloader> {
+        // nothing can be downloaded without a server
+        self.data.server()?;
+        Ok(PlexDownloader { plex: self.clone() })
+    }
+
+    #[cfg(feature = "app")]
+    pub(crate) fn reporter(&self) -> Result<PlexReporter> {
+        Ok(PlexReporter {
+            client: self.client.clone(),
+            server: self.data.server()?,
+        })
+    }
+
     pub(crate) fn signout(&mut self) -> Result<()> {
         debug!("Removing plex");
 
@@ -174,21 +581,12 @@ impl Plex {
         self.data.user_token.is_some()
     }
 
-    pub(crate) fn authenticated_thumb(&self, thumb: &str) -> Result<String> {
-        let base_uri = self
-            .data
-            .selected_connection
-            .as_ref()
-            .ok_or(Error::NoServerSelected)?
-            .uri
-            .as_ref();
-        let token = self
-            .data
-            .user_token
-            .as_ref()
-            .ok_or(Error::NotAuthenticated)?;
-        let token = format!("?X-Plex-Token={token}");
-        Ok(format!("{base_uri}{thumb}{token}"))
+    /// Adds the servers token to a path on the selected server, for media streams and anything
+    /// outside the app that can't go through the client
+    #[cfg(feature = "app")]
+    pub(crate) fn authenticated_uri(&self, path: &str) -> Result<String> {
+        let PlexServer { uri, token } = self.data.server()?;
+        Ok(format!("{uri}{path}?X-Plex-Token={token}"))
     }
 
     pub(crate) fn get_selected_server(&self) -> Option<&str> {
@@ -198,6 +596,7 @@ impl Plex {
             .map(|server| server.name.as_ref())
     }
 
+    #[cfg(feature = "app")]
     pub(crate) fn reset_server_selection(&mut self) {
         debug!("reseting selected resource");
         self.data.selected_connection = None;
@@ -218,24 +617,15 @@ impl Plex {
 
 impl From<PlexData> for Plex {
     fn from(data: PlexData) -> Self {
-        let client = data.create_client().unwrap();
-        let (resources, libraries, albums) = if data.user_token.is_some() {
-            let resources = data.get_resources(&client).unwrap_or_default();
-            let libraries = data.get_libraries(&client).unwrap_or_default();
-            let albums = data.get_albums(&client).unwrap_or_default();
-
-            (resources, libraries, Arc::new(albums))
-        } else {
-            (HashMap::new(), HashMap::new(), Arc::new(HashMap::new()))
-        };
-        let client = Arc::new(RwLock::new(client));
+        let client = Arc::new(data.create_client().unwrap());
 
         Self {
             data,
             client,
-            resources,
-            libraries,
-            albums,
+            resources: Arc::default(),
+            libraries: Arc::default(),
+            albums: Arc::default(),
+            album_order: Arc::default(),
         }
     }
 }
@@ -284,72 +674,46 @@ impl PlexData {
         Ok(Box::new(self.__create_client()?))
     }
 
-    #[cfg(test)]
-    fn create_client(&self) -> Result<BoxedClient> {
-        use super::client::mock::MockPlexClient;
-        Ok(Box::new(MockPlexClient))
-    }
-
-    #[cfg(all(debug_assertions, not(test)))]
+    #[cfg(debug_assertions)]
     fn create_client(&self) -> Result<BoxedClient> {
         use super::client::mock::MockPlexClient;
+        use std::env;
 
-        let client: BoxedClient = match env::var("USE_MOCK_PLEX") {
-            Ok(val) => match val.trim().to_lowercase().as_str() {
-                "f" | "0" | "false" => Box::new(self.__create_client()?),
-                _ => Box::new(MockPlexClient),
-            },
-            Err(_) => Box::new(self.__create_client()?),
+        // tests stay off the network unless asked otherwise
+        let use_mock = match env::var("USE_MOCK_PLEX") {
+            Ok(val) => !matches!(val.trim().to_lowercase().as_str(), "f" | "0" | "false"),
+            Err(_) => cfg!(test),
+        };
+        let client: BoxedClient = if use_mock {
+            Box::new(MockPlexClient)
+        } else {
+            Box::new(self.__create_client()?)
         };
 
         Ok(client)
     }
 
-    fn __create_client(&self) -> Result<reqwest::blocking::Client> {
-        debug!("Creating plex client with default headers");
-        let mut headers = HeaderMap::new();
-        headers.insert("Accept", HeaderValue::from_static("application/json"));
-        headers.insert("X-Plex-Provides", HeaderValue::from_static("player"));
-        headers.insert("X-Plex-Platform", HeaderValue::from_static(env::consts::OS));
-        headers.insert(
-            "X-Plex-Platform-Version",
-            HeaderValue::from_static(env::consts::ARCH),
-        );
-        headers.insert(
-            "X-Plex-Client-Name",
-            HeaderValue::from_static(env!("CARGO_PKG_NAME")),
-        );
-        headers.insert(
-            "X-Plex-Client-Identifier",
-            HeaderValue::from_str(self.client_ident.as_ref())?,
-        );
-        headers.insert(
-            "X-Plex-Version",
-            HeaderValue::from_static(env!("CARGO_PKG_VERSION")),
-        );
-        headers.insert(
-            "X-Plex-Product",
-            HeaderValue::from_static(env!("CARGO_PKG_NAME")),
-        );
-        headers.insert(
-            "X-Plex-Session-Identifier",
-            HeaderValue::from_str(self.session_token.as_ref())?,
-        );
-        // headers.insert("X-Plex-Device",
-        //     HeaderValue::from_str(self.device.as_ref())?,
-
-        // );
-        // headers.insert("X-Plex-Device-Name",
-        //     HeaderValue::from_str(self.device_name.as_ref())?,
-        // );
-        if let Some(token) = &self.user_token {
-            headers.insert("X-Plex-Token", HeaderValue::from_str(token.as_ref())?);
-        }
+    fn __create_client(&self) -> Result<PlexHttp> {
+        debug!("Creating plex client for this session");
+        PlexHttp::new(&self.client_ident, &self.session_token)
+    }
+
+    /// The selected server and the token to use with it
+    fn server(&self) -> Result<PlexServer> {
+        let connection = self
+            .selected_connection
+            .as_ref()
+            .ok_or(Error::NoServerSelected)?;
+        let token = connection
+            .token
+            .as_ref()
+            .or(self.user_token.as_ref())
+            .ok_or(Error::NotAuthenticated)?;
 
-        Ok(reqwest::blocking::Client::builder()
-            .default_headers(headers)
-            .timeout(Duration::from_secs(5))
-            .build()?)
+        Ok(PlexServer {
+            uri: connection.uri.clone(),
+            token: token.clone(),
+        })
     }
 
     fn signout(&mut self) {
@@ -361,9 +725,10 @@ impl PlexData {
         self.session_token = default_session();
     }
 
-    fn get_resources(&self, client: &BoxedClient) -> Result<HashMap<Arc<str>, PlexResource>> {
+    async fn get_resources(&self, client: &BoxedClient) -> Result<HashMap<Arc<str>, PlexResource>> {
         debug!("refreshing resources");
-        let resources = client.resources()?;
+        let token = self.user_token.as_ref().ok_or(Error::NotAuthenticated)?;
+        let resources = client.resources(token).await?;
 
         Ok(resources
             .into_par_iter()
@@ -371,13 +736,9 @@ impl PlexData {
             .collect())
     }
 
-    fn get_libraries(&self, client: &BoxedClient) -> Result<HashMap<Arc<str>, Library>> {
+    async fn get_libraries(&self, client: &BoxedClient) -> Result<HashMap<Arc<str>, Library>> {
         debug!("refreshing libraries");
-        let server = self
-            .selected_connection
-            .as_ref()
-            .ok_or(Error::NoServerSelected)?;
-        let libraries = client.libraries(&server.uri)?;
+        let libraries = client.libraries(&self.server()?).await?;
 
         Ok(libraries
             .into_par_iter()
@@ -385,24 +746,132 @@ impl PlexData {
             .collect())
     }
 
-    fn get_albums(&self, client: &BoxedClient) -> Result<HashMap<Arc<str>, Album>> {
-        debug!("refreshing albums");
-        let server = self
-            .selected_connection
-            .as_ref()
-            .ok_or(Error::NoServerSelected)?;
+    async fn get_album_page(
+        &self,
+        client: &BoxedClient,
+        start: usize,
+        size: usize,
+    ) -> Result<AlbumPage> {
+        debug!("refreshing albums {start}..{}", start + size);
+        let server = self.server()?;
         let library = self
             .selected_library
             .as_ref()
             .ok_or(Error::NoLibrarySelected)?;
 
-        let albums = client.albums(&server.uri, library.key_ref())?;
-        debug!("found {} albums", albums.len());
-        Ok(albums
-            .into_par_iter()
-            .map(|album| album.into_key_val())
+        let page = client
+            .albums(&server, library.key_ref(), start, size)
+            .await?;
+        debug!("library has {} albums", page.total(start));
+        Ok(page)
+    }
+
+    async fn get_tracks(&self, client: &BoxedClient, album_key: &str) -> Result<Vec<Track>> {
+        debug!("refreshing tracks for {album_key}");
+        let mut tracks = client.tracks(&self.server()?, album_key).await?;
+        debug!("found {} tracks", tracks.len());
+        tracks.sort_by_key(|track| track.index());
+        Ok(tracks)
+    }
+}
+
+/// Handle to the selected server that can be used without holding onto `Plex`, it keeps its own
+/// copy so it can move to another connection in the middle of a download
+#[derive(Clone)]
+pub(crate) struct PlexDownloader {
+    plex: Plex,
+}
+
+impl PlexDownloader {
+    pub(crate) async fn get_parts(&mut self, album_key: &str) -> Result<Box<[TrackPart]>> {
+        debug!("get parts: {album_key}");
+        let tracks = self.plex.get_tracks(album_key).await?;
+        debug!("found {} tracks", tracks.len());
+
+        Ok(tracks
+            .iter()
+            .flat_map(|track| track.parts().cloned())
             .collect())
     }
+
+    pub(crate) async fn download_part(
+        &self,
+        part: &TrackPart,
+        offset: u64,
+        writer: &mut (dyn Write + Send),
+    ) -> Result<u64> {
+        self.plex
+            .client
+            .download(&self.plex.data.server()?, part.key_ref(), offset, writer)
+            .await
+    }
+
+    /// Moves to another connection after a part lost this one, the part is then resumed from
+    /// wherever it got to
+    pub(crate) async fn reconnect(&mut self) -> Result<()> {
+        self.plex.check_connection().await
+    }
+
+    /// Where the connection ended up, to be kept once the download is done
+    #[cfg(feature = "app")]
+    pub(crate) fn plex(&self) -> &Plex {
+        &self.plex
+    }
+}
+
+#[cfg(feature = "app")]
+#[derive(Display, Clone, Copy, PartialEq, Debug)]
+pub(crate) enum TimelineState {
+    #[display(fmt = "playing")]
+    Playing,
+    #[display(fmt = "paused")]
+    Paused,
+    #[display(fmt = "stopped")]
+    Stopped,
+}
+
+/// A position the server has for an album, `viewed_at` is unix time in milliseconds
+#[cfg(feature = "app")]
+#[derive(Clone, Debug)]
+pub(crate) struct ViewOffset {
+    pub(crate) rating_key: Arc<str>,
+    pub(crate) offset: u64,
+    pub(crate) viewed_at: u64,
+}
+
+/// Where a track is up to, plex stores `time` as the tracks `viewOffset`
+#[cfg(feature = "app")]
+#[derive(Clone, Debug)]
+pub(crate) struct Timeline {
+    pub(crate) rating_key: Arc<str>,
+    pub(crate) state: TimelineState,
+    pub(crate) time: u64,
+    pub(crate) duration: u64,
+}
+
+/// Keeps the servers play state in step with ours, also usable without holding onto `Plex`
+#[cfg(feature = "app")]
+#[derive(Clone)]
+pub(crate) struct PlexReporter {
+    client: Arc<BoxedClient>,
+    server: PlexServer,
+}
+
+#[cfg(feature = "app")]
+impl PlexReporter {
+    pub(crate) async fn timeline(&self, timeline: &Timeline) -> Result<()> {
+        self.client.timeline(&self.server, timeline).await
+    }
+
+    /// Marks `key` and everything under it as played
+    pub(crate) async fn scrobble(&self, key: &str) -> Result<()> {
+        self.client.scrobble(&self.server, key).await
+    }
+
+    /// Marks `key` and everything under it as unplayed
+    pub(crate) async fn unscrobble(&self, key: &str) -> Result<()> {
+        self.client.unscrobble(&self.server, key).await
+    }
 }
 
 #[derive(Deserialize, Debug, Clone, Default)]
@@ -1,7 +1,9 @@
 mod album;
 mod connections;
 mod library;
+mod track;
 
 pub(super) use album::*;
 pub(super) use connections::*;
 pub(super) use library::*;
+pub(super) use track::*;
@@ -14,6 +14,9 @@ pub(crate) struct Album {
     parent_rating_key: Option<Arc<str>>,
     year: Option<u64>,
     index: u64,
+    view_offset: Option<u64>,
+    last_viewed_at: Option<u64>,
+    view_count: Option<u64>,
 }
 
 impl Album {
@@ -32,6 +35,7 @@ impl Album {
             .unwrap_or_default()
     }
 
+    #[cfg(feature = "app")]
     pub(crate) fn summary_ref(&self) -> &str {
         self.summary.as_ref()
     }
@@ -40,6 +44,7 @@ impl Album {
         self.title.as_ref()
     }
 
+    #[cfg(feature = "app")]
     pub(crate) fn thumb_ref(&self) -> &str {
         self.thumb
             .as_ref()
@@ -47,7 +52,36 @@ impl Album {
             .unwrap_or_default()
     }
 
+    /// Whether anyone has listened to any of the album on the server
+    #[cfg(feature = "app")]
+    pub(crate) fn has_been_viewed(&self) -> bool {
+        self.last_viewed_at.is_some()
+            || self.view_offset.is_some()
+            || self.view_count.is_some_and(|count| count > 0)
+    }
+
     pub(crate) fn key_clone(&self) -> Arc<str> {
         self.rating_key.clone()
     }
 }
+
+/// One page of a library's albums, read from the `MediaContainer`
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub(crate) struct AlbumPage {
+    /// Left out when the page is empty
+    #[serde(default, rename = "Metadata")]
+    albums: Vec<Album>,
+    total_size: Option<usize>,
+}
+
+impl AlbumPage {
+    /// How many albums the whole library has, a server that didn't page sent all of them
+    pub(crate) fn total(&self, start: usize) -> usize {
+        self.total_size.unwrap_or(start + self.albums.len())
+    }
+
+    pub(crate) fn into_albums(self) -> Vec<Album> {
+        self.albums
+    }
+}
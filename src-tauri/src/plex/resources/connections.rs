@@ -1,11 +1,14 @@
 use std::sync::Arc;
 
+use derive_more::Display;
 use serde::{Deserialize, Serialize};
 
 #[derive(Deserialize, Serialize)]
 #[serde(rename_all = "camelCase")]
 pub(crate) struct PlexResource {
     name: Arc<str>,
+    /// Token for this server, shared servers don't accept the users own
+    access_token: Option<Arc<str>>,
     connections: Box<[PlexConnections]>,
 }
 
@@ -13,12 +16,39 @@ pub(crate) struct PlexResource {
 #[serde(rename_all = "camelCase")]
 pub(crate) struct PlexConnections {
     uri: Arc<str>,
+    #[serde(default)]
+    protocol: Arc<str>,
+    #[serde(default)]
+    address: Arc<str>,
+    /// On the same network as us
+    #[serde(default)]
+    local: bool,
+    /// Goes through plex's relay, slow and bandwidth limited
+    #[serde(default)]
+    relay: bool,
+}
+
+/// How a connection reaches the server, ordered from most to least preferred
+#[derive(Display, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Debug)]
+pub(crate) enum ConnectionKind {
+    #[display(fmt = "local")]
+    Local,
+    #[display(fmt = "remote")]
+    Remote,
+    #[display(fmt = "relay")]
+    Relay,
 }
 
 impl PlexResource {
     pub(crate) fn into_key_val(self) -> (Arc<str>, Self) {
         (self.name.clone(), self)
     }
+    pub(crate) fn access_token_ref(&self) -> Option<&str> {
+        self.access_token.as_deref()
+    }
+    pub(crate) fn access_token_clone(&self) -> Option<Arc<str>> {
+        self.access_token.clone()
+    }
     pub(crate) fn connections_ref(&self) -> &[PlexConnections] {
         self.connections.as_ref()
     }
@@ -31,4 +61,19 @@ impl PlexConnections {
     pub(crate) fn uri_ref(&self) -> &str {
         self.uri.as_ref()
     }
+    pub(crate) fn protocol_ref(&self) -> &str {
+        self.protocol.as_ref()
+    }
+    pub(crate) fn address_ref(&self) -> &str {
+        self.address.as_ref()
+    }
+    pub(crate) fn kind(&self) -> ConnectionKind {
+        if self.relay {
+            ConnectionKind::Relay
+        } else if self.local {
+            ConnectionKind::Local
+        } else {
+            ConnectionKind::Remote
+        }
+    }
 }
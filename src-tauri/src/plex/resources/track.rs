@@ -0,0 +1,179 @@
+use std::sync::Arc;
+
+use serde::{Deserialize, Serialize};
+
+#[cfg(feature = "app")]
+use crate::chapters::Chapter;
+
+#[derive(Deserialize, Serialize, Clone)]
+#[serde(rename_all = "camelCase")]
+pub(crate) struct Track {
+    rating_key: Arc<str>,
+    title: Arc<str>,
+    index: Option<u64>,
+    duration: Option<u64>,
+    view_offset: Option<u64>,
+    last_viewed_at: Option<u64>,
+    view_count: Option<u64>,
+    #[serde(rename = "Media", default)]
+    media: Box<[TrackMedia]>,
+    #[serde(rename = "Chapter", default)]
+    chapters: Box<[TrackChapter]>,
+}
+
+#[derive(Deserialize, Serialize, Clone)]
+#[serde(rename_all = "camelCase")]
+pub(crate) struct TrackChapter {
+    tag: Option<Arc<str>>,
+    start_time_offset: u64,
+    end_time_offset: u64,
+}
+
+#[derive(Deserialize, Serialize, Clone)]
+#[serde(rename_all = "camelCase")]
+pub(crate) struct TrackMedia {
+    duration: Option<u64>,
+    container: Option<Arc<str>>,
+    audio_codec: Option<Arc<str>>,
+    #[serde(rename = "Part", default)]
+    parts: Box<[TrackPart]>,
+}
+
+#[derive(Deserialize, Serialize, Clone)]
+#[serde(rename_all = "camelCase")]
+pub(crate) struct TrackPart {
+    id: u64,
+    key: Arc<str>,
+    duration: Option<u64>,
+    file: Option<Arc<str>>,
+    container: Option<Arc<str>>,
+    size: Option<u64>,
+}
+
+impl Track {
+    pub(crate) fn key_clone(&self) -> Arc<str> {
+        self.rating_key.clone()
+    }
+
+    pub(crate) fn index(&self) -> u64 {
+        self.index.unwrap_or_default()
+    }
+
+    /// Milliseconds into the track the server has it paused at
+    #[cfg(feature = "app")]
+    pub(crate) fn view_offset(&self) -> Option<u64> {
+        self.view_offset
+    }
+
+    /// Unix time in seconds
+    #[cfg(feature = "app")]
+    pub(crate) fn last_viewed_at(&self) -> Option<u64> {
+        self.last_viewed_at
+    }
+
+    #[cfg(feature = "app")]
+    pub(crate) fn played(&self) -> bool {
+        self.view_count.is_some_and(|count| count > 0)
+    }
+
+    /// Duration in milliseconds
+    pub(crate) fn duration(&self) -> u64 {
+        self.duration
+            .or_else(|| self.media.iter().find_map(|media| media.duration))
+            .unwrap_or_default()
+    }
+
+    /// Chapters plex found in the track, or the track itself as a single chapter,
+    /// shifted by `offset` milliseconds
+    #[cfg(feature = "app")]
+    pub(crate) fn chapters(&self, offset: u64) -> Vec<Chapter> {
+        if self.chapters.is_empty() {
+            return vec![Chapter {
+                title: self.title.clone(),
+                start: offset,
+                end: offset + self.duration(),
+            }];
+        }
+
+        self.chapters
+            .iter()
+            .map(|chapter| Chapter {
+                title: chapter.tag.clone().unwrap_or_else(|| self.title.clone()),
+                start: offset + chapter.start_time_offset,
+                end: offset + chapter.end_time_offset,
+            })
+            .collect()
+    }
+
+    pub(crate) fn parts(&self) -> impl Iterator<Item = &TrackPart> {
+        self.media.iter().flat_map(|media| media.parts.iter())
+    }
+}
+
+impl TrackPart {
+    pub(crate) fn key_ref(&self) -> &str {
+        self.key.as_ref()
+    }
+
+    /// Duration in milliseconds
+    pub(crate) fn duration(&self) -> Option<u64> {
+        self.duration
+    }
+
+    pub(crate) fn size(&self) -> Option<u64> {
+        self.size
+    }
+
+    /// Name used for the part on disk, prefers the servers file name. Prefixed with `index`,
+    /// where the part falls in the book, so parts named the same on different discs stay apart
+    pub(crate) fn file_name(&self, index: usize) -> String {
+        let name = self
+            .file
+            .as_ref()
+            .and_then(|file| file.rsplit(['/', '\\']).next()) // server could be windows
+            .filter(|name| !matches!(*name, "" | "." | ".."))
+            .map(|name| name.to_string())
+            .unwrap_or_else(|| {
+                let container = self
+                    .container
+                    .as_ref()
+                    .map(|val| val.as_ref())
+                    .filter(|container| !container.contains(['/', '\\', '.']))
+                    .unwrap_or("bin");
+                format!("{}.{container}", self.id)
+            });
+
+        format!("{index:03}-{name}")
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::json;
+
+    use super::*;
+
+    fn part(file: &str) -> TrackPart {
+        serde_json::from_value(json!({
+            "id": 7,
+            "key": "/library/parts/7/file.mp3",
+            "file": file,
+            "container": "mp3",
+        }))
+        .unwrap()
+    }
+
+    #[test]
+    fn keeps_parts_with_the_same_name_apart() {
+        assert_eq!(part("/books/CD1/01.mp3").file_name(0), "000-01.mp3");
+        assert_eq!(part("/books/CD2/01.mp3").file_name(1), "001-01.mp3");
+        assert_eq!(part("D:\\books\\CD2\\01.mp3").file_name(2), "002-01.mp3");
+    }
+
+    #[test]
+    fn never_names_a_part_outside_the_book() {
+        for file in ["/books/..", "/books/.", "/books/"] {
+            assert_eq!(part(file).file_name(3), "003-7.mp3");
+        }
+    }
+}
@@ -0,0 +1,282 @@
+mod error;
+
+pub use error::*;
+
+use std::{
+    io::{self, Read},
+    sync::Arc,
+    thread,
+    time::Duration,
+};
+
+use log::{debug, info, warn};
+use serde::{de::DeserializeOwned, Deserialize, Serialize};
+use serde_json::{json, Value};
+use subtle::ConstantTimeEq;
+use tauri::{async_runtime, AppHandle, Emitter, Manager};
+use tiny_http::{Header, Method, Request, Response, Server};
+
+use crate::{
+    handlers,
+    player::PlaybackState,
+    state::{AppState, RemoteSettings, TOGGLE_PLAYING_EVENT},
+};
+
+// a server that was just stopped takes a moment to let go of its port
+const BIND_ATTEMPTS: usize = 5;
+const BIND_RETRY: Duration = Duration::from_millis(50);
+
+// bytes of request body read at most
+const MAX_BODY: u64 = 4 * 1024;
+
+/// The running server, it shuts down when this is dropped
+pub(crate) struct Remote {
+    server: Arc<Server>,
+    address: Arc<str>,
+    port: u16,
+}
+
+impl Remote {
+    pub(crate) fn start(app: AppHandle, settings: &RemoteSettings) -> Result<Self> {
+        let address = (settings.address.as_ref(), settings.port);
+        let mut server = Server::http(address);
+        for _ in 1..BIND_ATTEMPTS {
+            if server.is_ok() {
+                break;
+            }
+            thread::sleep(BIND_RETRY);
+            server = Server::http(address);
+        }
+        let server = Arc::new(server.map_err(io::Error::other)?);
+        info!(
+            "Serving the remote api on {}:{}",
+            settings.address, settings.port
+        );
+
+        let worker = server.clone();
+        thread::spawn(move || {
+            for request in worker.incoming_requests() {
+                handle(&app, request);
+            }
+            debug!("Remote api shut down");
+        });
+
+        Ok(Self {
+            server,
+            address: settings.address.clone(),
+            port: settings.port,
+        })
+    }
+
+    /// Whether the server is already listening where `settings` asks for
+    pub(crate) fn serves(&self, settings: &RemoteSettings) -> bool {
+        self.address == settings.address && self.port == settings.port
+    }
+}
+
+impl Drop for Remote {
+    fn drop(&mut self) {
+        self.server.unblock();
+    }
+}
+
+fn handle(app: &AppHandle, mut request: Request) {
+    debug!("Remote {} {}", request.method(), request.url());
+    let result = authorize(app, &request).and_then(|_| route(app, &mut request));
+    let (status, body) = match result {
+        Ok(body) => (200, body),
+        Err(err) => {
+            warn!("Remote request failed: {err}");
+            (err.status(), json!({ "error": err.to_string() }))
+        }
+    };
+
+    let mut response = Response::from_string(body.to_string()).with_status_code(status);
+    if let Ok(header) = Header::from_bytes("Content-Type", "application/json") {
+        response.add_header(header);
+    }
+    request.respond(response).ok();
+}
+
+/// The token is read for every request so it can be changed without restarting the server
+fn authorize(app: &AppHandle, request: &Request) -> Result<()> {
+    let state = app.state::<AppState>();
+    let state = state.lock()?;
+    let token = state.settings.remote.token.as_ref();
+
+    // compared in constant time so the token can't be guessed a byte at a time
+    let authorized = request.headers().iter().any(|header| {
+        header.field.equiv("Authorization")
+            && header
+                .value
+                .as_str()
+                .strip_prefix("Bearer ")
+                .is_some_and(|sent| bool::from(sent.as_bytes().ct_eq(token.as_bytes())))
+    });
+    if !authorized {
+        return Err(Error::Unauthorized);
+    }
+
+    Ok(())
+}
+
+fn route(app: &AppHandle, request: &mut Request) -> Result<Value> {
+    let path = request
+        .url()
+        .split('?')
+        .next()
+        .unwrap_or_default()
+        .to_owned();
+
+    match (request.method(), path.as_str()) {
+        (Method::Get, "/api/status") => status(app),
+        (Method::Get, "/api/library") => library(app),
+        (Method::Post, "/api/play") => {
+            if playback(app)? != PlaybackState::Playing {
+                handlers::player_play(app.state())?;
+                app.emit(TOGGLE_PLAYING_EVENT, ()).ok();
+            }
+            status(app)
+        }
+        (Method::Post, "/api/pause") => {
+            if playback(app)? == PlaybackState::Playing {
+                handlers::player_pause(app.state())?;
+                app.emit(TOGGLE_PLAYING_EVENT, ()).ok();
+            }
+            status(app)
+        }
+        (Method::Post, "/api/seek") => {
+            let Seek { position } = body(request)?;
+            handlers::player_seek(app.state(), &position.to_string())?;
+            status(app)
+        }
+        (Method::Post, "/api/speed") => {
+            let Speed { speed } = body(request)?;
+            handlers::player_speed(app.state(), &speed.to_string())?;
+            status(app)
+        }
+        (Method::Post, "/api/sleep-timer") => {
+            let SleepTimer { timer } = body(request)?;
+            handlers::player_sleep_timer(app.state(), &timer, app.clone())?;
+            status(app)
+        }
+        _ => Err(Error::NotFound),
+    }
+}
+
+/// Every body the api takes is a few small fields, anything longer is cut off and fails to parse
+fn body<T: DeserializeOwned>(request: &mut Request) -> Result<T> {
+    Ok(serde_json::from_reader(request.as_reader().take(MAX_BODY))?)
+}
+
+/// Milliseconds into the book
+#[derive(Deserialize)]
+struct Seek {
+    position: u64,
+}
+
+#[derive(Deserialize)]
+struct Speed {
+    speed: f64,
+}
+
+/// Minutes, `chapter` or `off`, the same as the sleep timer in the player
+#[derive(Deserialize)]
+struct SleepTimer {
+    timer: String,
+}
+
+fn playback(app: &AppHandle) -> Result<PlaybackState> {
+    let state = app.state::<AppState>();
+    let state = state.lock()?;
+
+    Ok(state.player.state())
+}
+
+#[derive(Serialize)]
+struct Status<'a> {
+    book: Option<BookStatus<'a>>,
+    state: &'a str,
+    speed: f64,
+    /// Milliseconds of listening left on the sleep timer
+    sleep_timer: Option<u64>,
+}
+
+/// Times are in milliseconds from the start of the book
+#[derive(Serialize)]
+struct BookStatus<'a> {
+    key: &'a str,
+    title: &'a str,
+    author: &'a str,
+    chapter: &'a str,
+    position: u64,
+    duration: u64,
+}
+
+fn status(app: &AppHandle) -> Result<Value> {
+    let state = app.state::<AppState>();
+    let state = state.lock()?;
+
+    let position = state.player.position();
+    let speed = state.speed();
+    let book = state
+        .current_book
+        .as_ref()
+        .and_then(|current| state.books.get(current))
+        .map(|book| {
+            let album = state.settings.plex.get_album(&book.album_key).ok();
+            BookStatus {
+                key: &book.album_key,
+                title: album.map(|album| album.title_ref()).unwrap_or_default(),
+                author: album.map(|album| album.parent_ref()).unwrap_or_default(),
+                chapter: book
+                    .chapter_at(position)
+                    .map(|chapter| chapter.title.as_ref())
+                    .unwrap_or_default(),
+                position,
+                duration: book.duration(),
+            }
+        });
+
+    Ok(serde_json::to_value(Status {
+        book,
+        state: match state.player.state() {
+            PlaybackState::Stopped => "stopped",
+            PlaybackState::Paused => "paused",
+            PlaybackState::Playing => "playing",
+            PlaybackState::Finished => "finished",
+        },
+        speed,
+        sleep_timer: state
+            .sleep_timer
+            .as_ref()
+            .map(|timer| timer.remaining(position, speed).as_millis() as u64),
+    })?)
+}
+
+#[derive(Serialize)]
+struct LibraryItem<'a> {
+    key: &'a str,
+    title: &'a str,
+    author: &'a str,
+}
+
+/// Pages through the whole library with the state unlocked, then keeps what was fetched
+fn library(app: &AppHandle) -> Result<Value> {
+    let state = app.state::<AppState>();
+    let mut plex = state.lock()?.settings.plex.clone();
+    async_runtime::block_on(plex.fetch_all_albums()).map_err(handlers::Error::from)?;
+    state.lock()?.adopt_plex(&plex);
+
+    let library: Vec<_> = plex
+        .get_albums()
+        .iter()
+        .map(|album| LibraryItem {
+            key: album.key_ref(),
+            title: album.title_ref(),
+            author: album.parent_ref(),
+        })
+        .collect();
+
+    Ok(serde_json::to_value(library)?)
+}
@@ -0,0 +1,36 @@
+use std::sync::PoisonError;
+
+use derive_more::{Display, Error, From};
+
+use crate::handlers;
+
+pub type Result<T> = core::result::Result<T, Error>;
+
+#[derive(Debug, From, Error, Display)]
+pub enum Error {
+    Io(std::io::Error),
+    InvalidJson(serde_json::Error),
+    Command(handlers::Error),
+    Unauthorized,
+    NotFound,
+    FailedToLockState,
+}
+
+impl Error {
+    pub(super) fn status(&self) -> u16 {
+        match self {
+            Self::InvalidJson(_) => 400,
+            Self::Unauthorized => 401,
+            Self::NotFound => 404,
+            Self::Command(handlers::Error::NoChange) => 409,
+            Self::Command(_) => 422,
+            Self::Io(_) | Self::FailedToLockState => 500,
+        }
+    }
+}
+
+impl<T> From<PoisonError<T>> for Error {
+    fn from(_err: PoisonError<T>) -> Self {
+        Self::FailedToLockState
+    }
+}
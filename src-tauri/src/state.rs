@@ -1,37 +1,78 @@
+mod bookmarks;
 mod books;
+mod downloads;
 mod error;
+#[cfg(feature = "app")]
+mod playback;
 mod settings;
+mod sleep;
+mod storage;
 
 pub use error::*;
 
+pub(crate) use bookmarks::*;
 pub(crate) use books::*;
-use log::info;
+pub(crate) use downloads::*;
+#[cfg(feature = "app")]
+use log::{debug, info, warn};
+#[cfg(feature = "app")]
+pub(crate) use playback::{SLEEP_TIMER_EVENT, TOGGLE_PLAYING_EVENT};
 pub(crate) use settings::*;
+pub(crate) use sleep::*;
+pub(crate) use storage::*;
 
+#[cfg(feature = "app")]
 use std::{
     collections::HashMap,
+    path::PathBuf,
     sync::{Arc, Mutex},
 };
 
-use tauri::{App, Manager, Wry};
+#[cfg(feature = "app")]
+use tauri::{async_runtime, App, AppHandle, Manager, Wry};
+#[cfg(feature = "app")]
 use tauri_plugin_store::{Store, StoreBuilder};
 
-use crate::plex::PlexPin;
+#[cfg(feature = "app")]
+use crate::{
+    player::{PlaybackState, Player},
+    plex::{Plex, PlexPin},
+    remote::Remote,
+};
 
+#[cfg(feature = "app")]
 pub(crate) type AppState = Mutex<InnerAppState>;
+#[cfg(feature = "app")]
 pub(crate) struct InnerAppState {
     pub(crate) settings: AppSettings,
     pub(crate) current_book: Option<Arc<str>>, // could potentially just be the key
     pub(crate) store: Store<Wry>,
     pub(crate) plex_pin: Option<PlexPin>,
     pub(crate) books: HashMap<Arc<str>, Book>,
+    pub(crate) downloads: Downloads,
+    pub(crate) download_dir: PathBuf,
+    pub(crate) player: Player,
+    pub(crate) sleep_timer: Option<SleepTimer>,
+    pub(crate) remote: Option<Remote>,
 }
 
+#[cfg(feature = "app")]
 impl InnerAppState {
     pub(crate) fn save_settings(&mut self) {
         self.settings.save(&mut self.store)
     }
 
+    /// Keeps what a clone of plex picked up while the state was unlocked, the connection it
+    /// failed over to and any albums it fetched
+    pub(crate) fn adopt_plex(&mut self, plex: &Plex) -> bool {
+        self.settings.plex.adopt_albums(plex);
+        let moved = self.settings.plex.adopt_connection(plex);
+        if moved {
+            self.save_settings();
+        }
+        moved
+    }
+
     pub(crate) fn save_books(&mut self) {
         self.books.save(&mut self.store).ok();
     }
@@ -42,6 +83,10 @@ impl InnerAppState {
         }
     }
 
+    pub(crate) fn save_downloads(&mut self) {
+        self.downloads.save(&mut self.store).ok();
+    }
+
     pub(crate) fn save_current_book(&mut self) {
         self.store
             .insert(
@@ -51,26 +96,139 @@ impl InnerAppState {
             .ok();
         self.store.save().ok();
     }
+
+    /// Speed the current book plays at
+    pub(crate) fn speed(&self) -> f64 {
+        self.current_book
+            .as_ref()
+            .and_then(|current| self.books.get(current))
+            .and_then(|book| book.speed)
+            .unwrap_or(self.settings.playback_speed)
+    }
+
+    /// Starts the sleep timer over when the player is used, if the settings want that
+    pub(crate) fn extend_sleep_timer(&mut self) {
+        if !self.settings.sleep.extend_on_activity {
+            return;
+        }
+        let position = self.player.position();
+        let book = self
+            .current_book
+            .as_ref()
+            .and_then(|current| self.books.get(current));
+        if let (Some(timer), Some(book)) = (&mut self.sleep_timer, book) {
+            timer.extend(book, position);
+        }
+    }
+
+    /// Starts or stops the remote api to match the settings, moving it if its address changed
+    pub(crate) fn restart_remote(&mut self, app: AppHandle) {
+        let settings = &self.settings.remote;
+        if !settings.enabled {
+            self.remote = None;
+            return;
+        }
+        if self
+            .remote
+            .as_ref()
+            .is_some_and(|remote| remote.serves(settings))
+        {
+            return;
+        }
+
+        self.remote = None;
+        match Remote::start(app, &self.settings.remote) {
+            Ok(remote) => self.remote = Some(remote),
+            Err(err) => warn!("Unable to start the remote api: {err}"),
+        }
+    }
+
+    /// Stores where the player is in the current book, if it has moved since the last time
+    pub(crate) fn checkpoint(&mut self) {
+        if self.player.state() == PlaybackState::Stopped {
+            return; // nothing loaded, or it failed and the clock can't be trusted
+        }
+        let Some(current) = self.current_book.clone() else {
+            return;
+        };
+        let position = self.player.track_position();
+
+        let moved = match self.books.get_mut(&current) {
+            Some(book) if book.progress != position => {
+                book.set_progress(position);
+                true
+            }
+            _ => false,
+        };
+        if moved {
+            debug!("Checkpoint {current} at {position:?}");
+            self.save_book(&current);
+        }
+    }
 }
 
 pub(crate) const BIN: &str = "store.bin";
+pub(crate) const DOWNLOAD_DIR: &str = "books";
 
+#[cfg(feature = "app")]
 pub(crate) fn setup_state(app: &mut App) -> core::result::Result<(), Box<dyn std::error::Error>> {
     info!("Loading stored data");
     let mut store = StoreBuilder::new(BIN).build(app.handle().clone());
 
     store.load().ok();
-    let settings = AppSettings::from_store(&mut store);
-    let current_book = None; // Book::get_current(&store); // TODO create player on startup
-    let books = Book::get_all_books(&mut store);
+    let mut settings = AppSettings::from_store(&mut store);
+    // nothing else is running yet so there is no lock to hold up
+    async_runtime::block_on(settings.plex.refresh())?;
+    let current_book = Book::get_current(&store);
+    let download_dir = app.path().app_data_dir()?.join(DOWNLOAD_DIR);
+    let mut books = Book::get_all_books(&mut store, &download_dir);
+    let keys = books.keys().map(|key| key.as_ref()).collect::<Vec<_>>();
+    if let Err(err) = async_runtime::block_on(settings.plex.load_albums(&keys)) {
+        warn!("Unable to load albums for stored books: {err}");
+    }
+    let (mut downloads, download_receiver) = Downloads::from_store(&store);
+    downloads.resume_partial(&download_dir);
+
+    let player = Player::default();
+    if let Some(book) = current_book.as_ref().and_then(|key| books.get_mut(key)) {
+        info!("Reopening {} at {:?}", book.album_key, book.progress);
+        book.state = ReadingState::Paused;
+        if book.playlist.is_empty() {
+            book.playlist = async_runtime::block_on(settings.plex.get_playlist(&book.album_key))
+                .unwrap_or_default();
+        }
+        player.set_speed(book.speed.unwrap_or(settings.playback_speed))?;
+        player.set_processing(book.processing)?;
+        match book.player_tracks(&settings.plex) {
+            Ok(tracks) => player.load(tracks, book.progress)?,
+            Err(err) => warn!("Unable to reopen {}: {err}", book.album_key),
+        }
+    }
+    // plex may have moved to another connection while loading
+    settings.save(&mut store);
+    downloads.save(&mut store).ok();
 
     app.manage(Mutex::new(InnerAppState {
         settings,
         current_book,
         store,
         books,
+        downloads,
+        download_dir,
         plex_pin: None,
+        player,
+        sleep_timer: None,
+        remote: None,
     }));
+    downloads::spawn_worker(app.handle().clone(), download_receiver);
+    playback::spawn_worker(app.handle().clone());
+    #[cfg(target_os = "linux")]
+    crate::mpris::spawn_worker(app.handle().clone());
+
+    let state = app.state::<AppState>();
+    if let Ok(mut state) = state.lock() {
+        state.restart_remote(app.handle().clone());
+    }
 
     Ok(())
 }
@@ -0,0 +1,70 @@
+use std::sync::Arc;
+
+use serde::{Deserialize, Serialize};
+
+#[cfg(feature = "app")]
+use super::{books::now, Book};
+
+/// A saved spot in a book, `position` is milliseconds from the start
+#[derive(Serialize, Deserialize, Clone)]
+pub(crate) struct Bookmark {
+    pub(crate) id: u64,
+    pub(crate) position: u64,
+    /// Title of the chapter the bookmark is in, empty for books without chapters
+    pub(crate) chapter: Arc<str>,
+    /// Unix time in milliseconds
+    pub(crate) created: u64,
+    pub(crate) note: Option<Arc<str>>,
+}
+
+#[cfg(feature = "app")]
+impl Book {
+    /// Marks `position`, returning the id of the new bookmark
+    pub(crate) fn add_bookmark(&mut self, position: u64, note: Option<&str>) -> u64 {
+        let id = self
+            .bookmarks
+            .iter()
+            .map(|bookmark| bookmark.id + 1)
+            .max()
+            .unwrap_or_default();
+        let chapter = self
+            .chapter_at(position)
+            .map(|chapter| chapter.title.clone())
+            .unwrap_or_default();
+
+        self.bookmarks.push(Bookmark {
+            id,
+            position,
+            chapter,
+            created: now(),
+            note: note.filter(|note| !note.is_empty()).map(Into::into),
+        });
+        self.bookmarks.sort_by_key(|bookmark| bookmark.position);
+
+        id
+    }
+
+    pub(crate) fn bookmark(&self, id: u64) -> Option<&Bookmark> {
+        self.bookmarks.iter().find(|bookmark| bookmark.id == id)
+    }
+
+    /// Replaces the note, an empty one removes it
+    pub(crate) fn edit_bookmark(&mut self, id: u64, note: &str) -> Option<&Bookmark> {
+        let bookmark = self
+            .bookmarks
+            .iter_mut()
+            .find(|bookmark| bookmark.id == id)?;
+        bookmark.note = Some(note).filter(|note| !note.is_empty()).map(Into::into);
+
+        Some(bookmark)
+    }
+
+    pub(crate) fn remove_bookmark(&mut self, id: u64) -> Option<Bookmark> {
+        let index = self
+            .bookmarks
+            .iter()
+            .position(|bookmark| bookmark.id == id)?;
+
+        Some(self.bookmarks.remove(index))
+    }
+}
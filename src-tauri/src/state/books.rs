@@ -1,15 +1,30 @@
 use std::{
     collections::{hash_map::Entry, HashMap},
+    fs,
+    path::Path,
     sync::Arc,
 };
+#[cfg(feature = "app")]
+use std::{
+    io::ErrorKind,
+    time::{SystemTime, UNIX_EPOCH},
+};
 
-use log::debug;
+use log::{debug, info, warn};
 use rayon::iter::{IntoParallelRefIterator, ParallelIterator};
-use serde::{Deserialize, Serialize};
-use tauri::Wry;
-use tauri_plugin_store::Store;
+use serde::{Deserialize, Deserializer, Serialize};
+
+use crate::{
+    chapters::Chapter,
+    player::{PlaylistItem, Processing, TrackPosition},
+};
+#[cfg(feature = "app")]
+use crate::{
+    player::{PlaybackState, PlayerSource, PlayerTrack},
+    plex::{Plex, Timeline, TimelineState, ViewOffset},
+};
 
-use super::{Error, Result};
+use super::{is_incomplete, Bookmark, Error, Result, Storage};
 
 #[derive(Serialize, Deserialize, Clone)]
 pub(crate) enum ReadingState {
@@ -21,8 +36,48 @@ pub(crate) enum ReadingState {
 pub(crate) struct Book {
     pub(crate) album_key: Arc<str>,
     pub(crate) state: ReadingState,
-    pub(crate) progress: f64,
+    // older stores kept an unused f64 here
+    #[serde(default, deserialize_with = "or_default")]
+    pub(crate) progress: TrackPosition,
+    /// When `progress` last moved, unix time in milliseconds
+    #[serde(default)]
+    progress_updated: u64,
     downloaded: Option<Arc<str>>,
+    #[serde(default)]
+    pub(crate) chapters: Box<[Chapter]>,
+    #[serde(default)]
+    pub(crate) playlist: Box<[PlaylistItem]>,
+    /// Overrides the default playback speed from the settings
+    #[serde(default)]
+    pub(crate) speed: Option<f64>,
+    #[serde(default)]
+    pub(crate) bookmarks: Vec<Bookmark>,
+    #[serde(default)]
+    pub(crate) processing: Processing,
+}
+
+// positions closer than this are treated as the same spot
+#[cfg(feature = "app")]
+const PROGRESS_TOLERANCE: u64 = 10_000;
+// going back further into a chapter than this restarts it rather than going to the one before
+#[cfg(feature = "app")]
+const RESTART_CHAPTER: u64 = 3_000;
+
+/// Unix time in milliseconds
+#[cfg(feature = "app")]
+pub(super) fn now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|time| time.as_millis() as u64)
+        .unwrap_or_default()
+}
+
+fn or_default<'de, D, T>(deserializer: D) -> core::result::Result<T, D::Error>
+where
+    D: Deserializer<'de>,
+    T: Deserialize<'de> + Default,
+{
+    Ok(T::deserialize(deserializer).unwrap_or_default())
 }
 
 impl Book {
@@ -30,13 +85,21 @@ impl Book {
         Book {
             album_key,
             state: ReadingState::Paused,
-            progress: 0f64,
+            progress: TrackPosition::default(),
+            progress_updated: 0,
             downloaded: None,
+            chapters: Box::new([]),
+            playlist: Box::new([]),
+            speed: None,
+            bookmarks: Vec::new(),
+            processing: Processing::default(),
         }
     }
 
+    #[cfg(feature = "app")]
     pub(super) const CURRENT_BOOK_STORE: &'static str = "current-book";
-    pub(super) fn _get_current(store: &Store<Wry>) -> Option<Arc<str>> {
+    #[cfg(feature = "app")]
+    pub(super) fn get_current(store: &impl Storage) -> Option<Arc<str>> {
         debug!("Loading {} store", Self::CURRENT_BOOK_STORE);
         let book = if let Some(book) = store.get(Self::CURRENT_BOOK_STORE) {
             serde_json::from_value(book.to_owned()).map_err(|err| err.into())
@@ -47,24 +110,25 @@ impl Book {
         book.ok()
     }
 
-    pub(super) fn from_key(store: &Store<Wry>, key: &str) -> Result<Self> {
+    pub(super) fn from_key(store: &impl Storage, key: &str) -> Result<Self> {
         let store_key = format!("book:{key}");
         debug!("Loading {store_key} store");
-        if let Some(book) = store.get(store_key) {
+        if let Some(book) = store.get(&store_key) {
             serde_json::from_value(book.to_owned()).map_err(|err| err.into())
         } else {
             Err(Error::StoreEmpty)
         }
     }
 
-    pub(crate) fn save(&self, store: &mut Store<Wry>) -> Result<()> {
+    #[cfg(feature = "app")]
+    pub(crate) fn save(&self, store: &mut impl Storage) -> Result<()> {
         self.__save(store)?;
         store.save()?;
 
         Ok(())
     }
 
-    fn __save(&self, store: &mut Store<Wry>) -> Result<()> {
+    fn __save(&self, store: &mut impl Storage) -> Result<()> {
         let key = self.album_key.as_ref();
         let store_key = format!("book:{key}");
         debug!("saving {store_key} store");
@@ -74,7 +138,10 @@ impl Book {
     }
 
     const ALL_BOOKS_STORE: &'static str = "all-books";
-    pub(super) fn get_all_books(store: &mut Store<Wry>) -> HashMap<Arc<str>, Self> {
+    pub(crate) fn get_all_books(
+        store: &mut (impl Storage + Sync),
+        download_dir: &Path,
+    ) -> HashMap<Arc<str>, Self> {
         debug!("Loading all books");
         let books = if let Some(books) = store.get(Self::ALL_BOOKS_STORE) {
             serde_json::from_value::<Box<[Arc<str>]>>(books.to_owned()).map_err(|err| err.into())
@@ -88,7 +155,7 @@ impl Book {
             Box::new([])
         };
 
-        let books = books
+        let mut books = books
             .par_iter()
             .filter_map(|book_key| {
                 if let Ok(book) = Self::from_key(store, book_key.as_ref()) {
@@ -98,51 +165,259 @@ impl Book {
                 }
             })
             .collect::<HashMap<Arc<str>, Self>>();
+        Self::reconcile_downloads(&mut books, download_dir);
         books.save(store).ok();
         books
     }
 
-    pub(crate) fn download(&mut self) -> Result<()> {
-        let files = vec!["todo"]; // TODO get files
+    /// Makes sure the store agrees with what is actually on disk
+    fn reconcile_downloads(books: &mut HashMap<Arc<str>, Self>, download_dir: &Path) {
+        debug!("Reconciling downloads in {download_dir:?}");
+        for book in books.values_mut() {
+            if let Some(location) = &book.downloaded {
+                if !Path::new(location.as_ref()).is_dir() {
+                    warn!("Downloaded files for {} are missing", book.album_key);
+                    book.downloaded = None;
+                }
+            }
+        }
 
-        match files.len().cmp(&1usize) {
-            std::cmp::Ordering::Less => {
-                // throw error
+        let Ok(entries) = fs::read_dir(download_dir) else {
+            return;
+        };
+        for entry in entries.flatten() {
+            let location = entry.path();
+            if !location.is_dir() {
+                continue;
             }
-            std::cmp::Ordering::Equal => {
-                let location = self.album_key.clone();
-                let _file = &files.first().unwrap();
-                // TODO: download the file to the location;
-                self.downloaded = Some(location);
+            let key: Arc<str> = entry.file_name().to_string_lossy().into();
+            let known = books
+                .get(&key)
+                .is_some_and(|book| book.downloaded.is_some());
+            if known {
+                continue;
             }
-            std::cmp::Ordering::Greater => {
-                let base_folder = self.album_key.clone();
-                for _file in files {
-                    // TODO: download each file to the base_folder
-                }
-                self.downloaded = Some(base_folder);
+
+            if is_incomplete(&location) {
+                info!("Found partial download for {key}, leaving it for the download queue");
+            } else {
+                info!("Adopting orphaned download for {key}");
+                books
+                    .entry(key.clone())
+                    .or_insert_with(|| Book::new(key))
+                    .set_downloaded(&location);
             }
         }
-        Ok(())
     }
 
+    pub(crate) fn is_downloaded(&self) -> bool {
+        self.downloaded.is_some()
+    }
+
+    pub(crate) fn set_downloaded(&mut self, location: &Path) {
+        self.downloaded = Some(location.to_string_lossy().into());
+    }
+
+    /// Milliseconds from the start of the book to `progress`
+    pub(crate) fn position(&self) -> u64 {
+        self.position_of(&self.progress)
+    }
+
+    pub(crate) fn position_of(&self, position: &TrackPosition) -> u64 {
+        self.playlist
+            .iter()
+            .take(position.track)
+            .map(|item| item.duration)
+            .sum::<u64>()
+            + position.offset
+    }
+
+    /// Milliseconds across the whole playlist
+    pub(crate) fn duration(&self) -> u64 {
+        self.playlist.iter().map(|item| item.duration).sum()
+    }
+
+    /// The chapter playing at `position` milliseconds into the book
+    pub(crate) fn chapter_at(&self, position: u64) -> Option<&Chapter> {
+        self.chapters
+            .iter()
+            .rfind(|chapter| chapter.start <= position)
+            .or(self.chapters.first())
+    }
+
+    /// Where `position` milliseconds into the book lands in the playlist
+    #[cfg(feature = "app")]
+    pub(crate) fn track_position_at(&self, position: u64) -> TrackPosition {
+        let mut offset = position;
+        for (track, item) in self.playlist.iter().enumerate() {
+            if offset < item.duration || track + 1 == self.playlist.len() {
+                return TrackPosition { track, offset };
+            }
+            offset -= item.duration;
+        }
+
+        TrackPosition::default()
+    }
+
+    /// Start of the chapter at `position`, or the one before it when `position` is within
+    /// `RESTART_CHAPTER` of the start
+    #[cfg(feature = "app")]
+    pub(crate) fn previous_chapter(&self, position: u64) -> u64 {
+        let Some(index) = self
+            .chapters
+            .iter()
+            .rposition(|chapter| chapter.start <= position)
+        else {
+            return 0;
+        };
+        let current = &self.chapters[index];
+        if position - current.start > RESTART_CHAPTER || index == 0 {
+            return current.start;
+        }
+
+        self.chapters[index - 1].start
+    }
+
+    /// Start of the chapter after `position`, `None` in the last chapter
+    #[cfg(feature = "app")]
+    pub(crate) fn next_chapter(&self, position: u64) -> Option<u64> {
+        self.chapters
+            .iter()
+            .find(|chapter| chapter.start > position)
+            .map(|chapter| chapter.start)
+    }
+
+    #[cfg(feature = "app")]
+    pub(crate) fn set_progress(&mut self, position: TrackPosition) {
+        self.progress = position;
+        self.progress_updated = now();
+    }
+
+    /// Maps a servers track offset onto the playlist
+    #[cfg(feature = "app")]
+    pub(crate) fn server_progress(&self, view: &ViewOffset) -> Option<TrackPosition> {
+        let mut parts = self
+            .playlist
+            .iter()
+            .enumerate()
+            .filter(|(_, item)| item.rating_key == view.rating_key)
+            .peekable();
+
+        let mut offset = view.offset;
+        while let Some((index, item)) = parts.next() {
+            if offset < item.duration || parts.peek().is_none() {
+                // the server can be a little past the end of what we have
+                return Some(TrackPosition {
+                    track: index,
+                    offset: offset.min(item.duration),
+                });
+            }
+            offset -= item.duration;
+        }
+
+        None
+    }
+
+    /// Takes the servers position if the book has never been played here, otherwise returns
+    /// it when it is newer than ours and somewhere else in the book
+    #[cfg(feature = "app")]
+    pub(crate) fn reconcile_progress(&mut self, view: &ViewOffset) -> Option<TrackPosition> {
+        let position = self.server_progress(view)?;
+        let distance = self
+            .position_of(&position)
+            .abs_diff(self.position_of(&self.progress));
+        if distance < PROGRESS_TOLERANCE || view.viewed_at <= self.progress_updated {
+            return None;
+        }
+
+        if self.progress_updated == 0 {
+            info!("Picking up {} where the server left it", self.album_key);
+            self.set_progress(position);
+            return None;
+        }
+        Some(position)
+    }
+
+    /// The plex track `progress` is in, with time and duration relative to that track
+    #[cfg(feature = "app")]
+    pub(crate) fn timeline(&self, state: PlaybackState) -> Option<Timeline> {
+        let current = self.playlist.get(self.progress.track)?;
+        if current.rating_key.is_empty() {
+            return None; // playlist saved before tracks were recorded
+        }
+
+        // a track can be split over several parts
+        let parts = self
+            .playlist
+            .iter()
+            .enumerate()
+            .filter(|(_, item)| item.rating_key == current.rating_key);
+        let (mut time, mut duration) = (self.progress.offset, 0);
+        for (index, item) in parts {
+            if index < self.progress.track {
+                time += item.duration;
+            }
+            duration += item.duration;
+        }
+
+        Some(Timeline {
+            rating_key: current.rating_key.clone(),
+            state: match state {
+                PlaybackState::Playing => TimelineState::Playing,
+                PlaybackState::Paused => TimelineState::Paused,
+                PlaybackState::Stopped | PlaybackState::Finished => TimelineState::Stopped,
+            },
+            time,
+            duration,
+        })
+    }
+
+    /// What the player should load, downloaded files are preferred over streaming from plex
+    #[cfg(feature = "app")]
+    pub(crate) fn player_tracks(&self, plex: &Plex) -> Result<Box<[PlayerTrack]>> {
+        self.playlist
+            .iter()
+            .map(|item| {
+                let local = self
+                    .downloaded
+                    .as_ref()
+                    .map(|location| Path::new(location.as_ref()).join(item.file_name.as_ref()))
+                    .filter(|path| path.is_file());
+                let source = match local {
+                    Some(path) => PlayerSource::File(path),
+                    None => PlayerSource::Stream(plex.authenticated_uri(&item.part_key)?),
+                };
+
+                Ok(PlayerTrack {
+                    source,
+                    duration: item.duration,
+                })
+            })
+            .collect()
+    }
+
+    #[cfg(feature = "app")]
     pub(crate) fn remove_download(&mut self) -> Result<()> {
-        let _location = self.downloaded.as_ref().ok_or(Error::BookNotDownloaded)?;
-        // TODO: delete location
+        let location = self.downloaded.as_ref().ok_or(Error::BookNotDownloaded)?;
+        debug!("Removing {location}");
+        match fs::remove_dir_all(location.as_ref()) {
+            Err(err) if err.kind() != ErrorKind::NotFound => return Err(err.into()),
+            _ => (),
+        }
         self.downloaded = None;
         Ok(())
     }
 }
 
 pub(crate) trait Books {
-    fn save(&self, store: &mut Store<Wry>) -> Result<()>;
+    fn save(&self, store: &mut impl Storage) -> Result<()>;
     fn get_book_or_insert(&mut self, album_key: Arc<str>) -> Result<(&mut Book, bool)>;
-    fn download_book(&mut self, album_key: Arc<str>) -> Result<bool>;
+    #[cfg(feature = "app")]
     fn remove_download(&mut self, key: &str) -> Result<()>;
 }
 
 impl Books for HashMap<Arc<str>, Book> {
-    fn save(&self, store: &mut Store<Wry>) -> Result<()> {
+    fn save(&self, store: &mut impl Storage) -> Result<()> {
         for book in self.values() {
             book.__save(store).ok();
         }
@@ -172,16 +447,110 @@ impl Books for HashMap<Arc<str>, Book> {
         Ok((book, new_key))
     }
 
-    fn download_book(&mut self, album_key: Arc<str>) -> Result<bool> {
-        let (book, new_key) = self.get_book_or_insert(album_key)?;
-
-        book.download()?;
-        Ok(new_key)
-    }
-
+    #[cfg(feature = "app")]
     fn remove_download(&mut self, key: &str) -> Result<()> {
         let book = self.get_mut(key).ok_or(Error::NoBookFound)?;
 
         book.remove_download()
     }
 }
+
+#[cfg(all(test, feature = "app"))]
+mod tests {
+    use super::*;
+
+    /// A book of two tracks, the second split over two parts
+    fn book() -> Book {
+        let item = |rating_key: &str, duration| PlaylistItem {
+            rating_key: rating_key.into(),
+            part_key: "".into(),
+            file_name: "".into(),
+            duration,
+        };
+        let mut book = Book::new("1".into());
+        book.playlist = Box::new([item("10", 60_000), item("11", 30_000), item("11", 30_000)]);
+        book
+    }
+
+    fn view(rating_key: &str, offset: u64, viewed_at: u64) -> ViewOffset {
+        ViewOffset {
+            rating_key: rating_key.into(),
+            offset,
+            viewed_at,
+        }
+    }
+
+    #[test]
+    fn finds_the_part_of_a_server_offset() {
+        let position = book().server_progress(&view("11", 45_000, 0));
+        assert_eq!(
+            position,
+            Some(TrackPosition {
+                track: 2,
+                offset: 15_000
+            })
+        );
+    }
+
+    #[test]
+    fn keeps_a_server_offset_beyond_the_last_track_in_the_book() {
+        let book = book();
+        let position = book.server_progress(&view("11", 90_000, 0)).unwrap();
+        assert_eq!(
+            position,
+            TrackPosition {
+                track: 2,
+                offset: 30_000
+            }
+        );
+        assert_eq!(book.position_of(&position), book.duration());
+    }
+
+    #[test]
+    fn keeps_local_progress_that_is_newer() {
+        let mut book = book();
+        let local = TrackPosition {
+            track: 2,
+            offset: 20_000,
+        };
+        book.set_progress(local);
+
+        // the server is further along but hasn't heard from us since
+        assert_eq!(book.reconcile_progress(&view("10", 0, 1_000)), None);
+        assert_eq!(book.progress, local);
+    }
+
+    #[test]
+    fn offers_server_progress_that_is_newer() {
+        let mut book = book();
+        let local = TrackPosition {
+            track: 0,
+            offset: 5_000,
+        };
+        book.set_progress(local);
+
+        let server = book.reconcile_progress(&view("11", 10_000, now() + 1_000));
+        assert_eq!(
+            server,
+            Some(TrackPosition {
+                track: 1,
+                offset: 10_000
+            })
+        );
+        // it's up to the listener whether to jump there
+        assert_eq!(book.progress, local);
+    }
+
+    #[test]
+    fn takes_server_progress_for_a_book_never_played_here() {
+        let mut book = book();
+        assert_eq!(book.reconcile_progress(&view("11", 10_000, 1_000)), None);
+        assert_eq!(
+            book.progress,
+            TrackPosition {
+                track: 1,
+                offset: 10_000
+            }
+        );
+    }
+}
@@ -0,0 +1,555 @@
+use std::{
+    collections::HashMap,
+    fs::{self, File, OpenOptions},
+    io::{self, BufWriter, Seek, SeekFrom, Write},
+    path::{Path, PathBuf},
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc,
+    },
+    time::{Duration, Instant},
+};
+#[cfg(feature = "app")]
+use std::{
+    collections::VecDeque,
+    sync::mpsc::{self, Receiver, Sender},
+    thread,
+};
+
+use log::{debug, warn};
+#[cfg(feature = "app")]
+use log::{error, info};
+use serde::{Deserialize, Serialize};
+#[cfg(feature = "app")]
+use tauri::{async_runtime, AppHandle, Emitter, Manager};
+use tokio::time;
+
+use crate::{
+    chapters::{self, Chapter},
+    plex::{self, Plex, PlexDownloader},
+};
+
+#[cfg(feature = "app")]
+use super::{AppState, Books, Storage};
+use super::{Error, Result};
+
+#[cfg(feature = "app")]
+pub(crate) const UPDATE_DOWNLOADED_EVENT: &str = "update-downloaded";
+#[cfg(feature = "app")]
+pub(crate) const DOWNLOAD_PROGRESS_EVENT: &str = "download-progress";
+const PROGRESS_INTERVAL: Duration = Duration::from_millis(250);
+const JOURNAL_INTERVAL: u64 = 8 * 1024 * 1024;
+const MAX_RETRIES: u32 = 5;
+const RETRY_DELAY: Duration = Duration::from_secs(2);
+
+/// The persisted part of the download manager
+#[cfg(feature = "app")]
+#[derive(Serialize, Deserialize, Default)]
+struct DownloadQueue {
+    keys: VecDeque<Arc<str>>,
+    paused: bool,
+}
+
+#[cfg(feature = "app")]
+pub(crate) struct Downloads {
+    queue: DownloadQueue,
+    current: Option<(Arc<str>, Arc<AtomicBool>)>,
+    notify: Sender<()>,
+}
+
+#[derive(Serialize, Clone)]
+pub(crate) struct DownloadProgress {
+    pub(crate) key: Arc<str>,
+    pub(crate) downloaded: u64,
+    pub(crate) total: u64,
+}
+
+struct DownloadJob {
+    key: Arc<str>,
+    location: PathBuf,
+    downloader: PlexDownloader,
+    cancelled: Arc<AtomicBool>,
+}
+
+#[cfg(feature = "app")]
+impl Downloads {
+    const STORE: &'static str = "download-queue";
+    pub(super) fn from_store(store: &impl Storage) -> (Self, Receiver<()>) {
+        debug!("Loading {} store", Self::STORE);
+        let queue = if let Some(queue) = store.get(Self::STORE) {
+            serde_json::from_value(queue.to_owned()).map_err(|err| err.into())
+        } else {
+            Err(Error::StoreEmpty)
+        };
+
+        let queue = queue.unwrap_or_else(|_: Error| DownloadQueue::default());
+        let (notify, receiver) = mpsc::channel();
+
+        // pick up anything left over from the last session
+        notify.send(()).ok();
+
+        (
+            Self {
+                queue,
+                current: None,
+                notify,
+            },
+            receiver,
+        )
+    }
+
+    pub(super) fn save(&self, store: &mut impl Storage) -> Result<()> {
+        debug!("Saving to {} store", Self::STORE);
+        store.insert(
+            Self::STORE.to_string(),
+            serde_json::to_value(&self.queue).unwrap_or_default(),
+        )?;
+        store.save()?;
+
+        Ok(())
+    }
+
+    pub(crate) fn enqueue(&mut self, key: Arc<str>) {
+        if !self.queue.keys.contains(&key) {
+            debug!("queueing download of {key}");
+            self.queue.keys.push_back(key);
+        }
+        self.notify.send(()).ok();
+    }
+
+    /// Stops downloading `key` to `location`, anything it left behind is removed
+    pub(crate) fn cancel(&mut self, key: &str, location: &Path) {
+        debug!("cancelling download of {key}");
+        self.queue.keys.retain(|queued| queued.as_ref() != key);
+        match &self.current {
+            // the worker cleans up once it has let go of the files
+            Some((current, cancelled)) if current.as_ref() == key => {
+                cancelled.store(true, Ordering::Relaxed);
+            }
+            _ if is_incomplete(location) => {
+                fs::remove_dir_all(location).ok();
+            }
+            _ => (),
+        }
+    }
+
+    /// Queues downloads an earlier session left partway through, so they are either finished
+    /// or cancelled rather than left on disk
+    pub(super) fn resume_partial(&mut self, download_dir: &Path) {
+        let Ok(entries) = fs::read_dir(download_dir) else {
+            return;
+        };
+        for entry in entries.flatten() {
+            let location = entry.path();
+            if location.is_dir() && is_incomplete(&location) {
+                self.enqueue(entry.file_name().to_string_lossy().into());
+            }
+        }
+    }
+
+    pub(crate) fn pause(&mut self) {
+        debug!("pausing downloads");
+        self.queue.paused = true;
+        if let Some((_, cancelled)) = &self.current {
+            cancelled.store(true, Ordering::Relaxed);
+        }
+    }
+
+    pub(crate) fn resume(&mut self) {
+        debug!("resuming downloads");
+        self.queue.paused = false;
+        self.notify.send(()).ok();
+    }
+
+    pub(crate) fn is_queued(&self, key: &str) -> bool {
+        self.queue.keys.iter().any(|queued| queued.as_ref() == key)
+    }
+}
+
+#[cfg(feature = "app")]
+pub(super) fn spawn_worker(app: AppHandle, receiver: Receiver<()>) {
+    thread::spawn(move || {
+        // any notification just means there might be more work to do
+        while receiver.recv().is_ok() {
+            loop {
+                match next_job(&app) {
+                    Ok(Some(mut job)) => {
+                        let result = async_runtime::block_on(download(&mut job, &|progress| {
+                            app.emit(DOWNLOAD_PROGRESS_EVENT, progress.clone()).ok();
+                        }));
+                        if let Err(err) = finish_job(&app, job, result) {
+                            error!("Failed to finish download: {err:?}");
+                        }
+                    }
+                    Ok(None) => break,
+                    Err(err) => {
+                        warn!("Unable to start download: {err:?}");
+                        break;
+                    }
+                }
+            }
+        }
+    });
+}
+
+#[cfg(feature = "app")]
+fn next_job(app: &AppHandle) -> Result<Option<DownloadJob>> {
+    let state = app.state::<AppState>();
+    let mut state = state.lock()?;
+
+    let downloads = &state.downloads;
+    if downloads.queue.paused || downloads.current.is_some() {
+        return Ok(None);
+    }
+    let Some(key) = downloads.queue.keys.front().cloned() else {
+        return Ok(None);
+    };
+
+    let job = DownloadJob {
+        location: state.download_dir.join(key.as_ref()),
+        downloader: state.settings.plex.downloader()?,
+        cancelled: Arc::new(AtomicBool::new(false)),
+        key,
+    };
+    state.downloads.current = Some((job.key.clone(), job.cancelled.clone()));
+
+    Ok(Some(job))
+}
+
+#[cfg(feature = "app")]
+fn finish_job(app: &AppHandle, job: DownloadJob, result: Result<Box<[Chapter]>>) -> Result<()> {
+    let state = app.state::<AppState>();
+    let mut state = state.lock()?;
+    state.downloads.current = None;
+    state.adopt_plex(job.downloader.plex());
+
+    match result {
+        Ok(chapters) => {
+            info!("Finished downloading {}", job.key);
+            state.downloads.queue.keys.retain(|key| key != &job.key);
+            // a partial download picked up from disk may not have a book yet
+            let (book, new_book) = state.books.get_book_or_insert(job.key.clone())?;
+            book.set_downloaded(&job.location);
+            if !chapters.is_empty() {
+                book.chapters = chapters;
+            }
+            if new_book {
+                state.save_books();
+            } else {
+                state.save_book(&job.key);
+            }
+        }
+        Err(_) if job.cancelled.load(Ordering::Relaxed) => {
+            if state.downloads.queue.paused && state.downloads.is_queued(&job.key) {
+                debug!("Paused download of {}", job.key);
+            } else {
+                debug!("Cancelled download of {}", job.key);
+                fs::remove_dir_all(&job.location).ok();
+            }
+        }
+        Err(err) => {
+            warn!("Failed to download {}: {err:?}", job.key);
+            state.downloads.queue.keys.retain(|key| key != &job.key);
+        }
+    }
+    state.save_downloads();
+
+    app.emit(UPDATE_DOWNLOADED_EVENT, ()).ok();
+    Ok(())
+}
+
+/// Downloads `key` to `location` straight away rather than through the queue, for the cli
+pub(crate) async fn download_now(
+    plex: &Plex,
+    key: Arc<str>,
+    location: PathBuf,
+    report: &(dyn Fn(&DownloadProgress) + Sync),
+) -> Result<Box<[Chapter]>> {
+    let mut job = DownloadJob {
+        key,
+        location,
+        downloader: plex.downloader()?,
+        cancelled: Arc::new(AtomicBool::new(false)),
+    };
+
+    download(&mut job, report).await
+}
+
+async fn download(
+    job: &mut DownloadJob,
+    report: &(dyn Fn(&DownloadProgress) + Sync),
+) -> Result<Box<[Chapter]>> {
+    let parts = job.downloader.get_parts(&job.key).await?;
+    if parts.is_empty() {
+        return Err(Error::NoFilesFound);
+    }
+
+    fs::create_dir_all(&job.location)?;
+
+    let mut writer = ProgressWriter {
+        report,
+        part: None,
+        cancelled: &job.cancelled,
+        journal: DownloadJournal::load(&job.location),
+        progress: DownloadProgress {
+            key: job.key.clone(),
+            downloaded: 0,
+            total: parts.iter().filter_map(|part| part.size()).sum(),
+        },
+        last_emit: Instant::now(),
+        completed: 0,
+        unjournaled: 0,
+    };
+
+    let mut paths = Vec::with_capacity(parts.len());
+    for (index, part) in parts.iter().enumerate() {
+        let name = part.file_name(index);
+        let path = job.location.join(&name);
+        if let Ok(metadata) = fs::metadata(&path) {
+            if part.size().unwrap_or(metadata.len()) == metadata.len() {
+                debug!("already downloaded {path:?}");
+                writer.completed += metadata.len();
+                paths.push((path, part.duration().unwrap_or_default()));
+                continue;
+            }
+        }
+
+        let part_path = job.location.join(format!("{name}.part"));
+        let mut failures = 0;
+        loop {
+            let offset = writer.open(&name, &part_path)?;
+            if part.size().is_some_and(|size| size == offset) {
+                writer.close()?;
+                break;
+            }
+
+            debug!("downloading {} to {part_path:?}", part.key_ref());
+            let result = job
+                .downloader
+                .download_part(part, offset, &mut writer)
+                .await;
+            let downloaded = writer.close()?;
+
+            match result {
+                Ok(_) => break,
+                Err(err) if job.cancelled.load(Ordering::Relaxed) => return Err(err.into()),
+                // picks up from the same spot on whichever connection still answers
+                Err(err) if err.is_connection_lost() => {
+                    warn!("lost the connection while downloading {name}: {err}");
+                    // a timeout on a long part still moved it along
+                    failures = if downloaded > offset { 0 } else { failures + 1 };
+                    if failures >= MAX_RETRIES {
+                        return Err(err.into());
+                    }
+                    if let Err(err) = job.downloader.reconnect().await {
+                        warn!("unable to reconnect to plex: {err}");
+                        time::sleep(RETRY_DELAY * failures).await;
+                    }
+                }
+                // nothing past where the part file ends, the size is checked below
+                Err(plex::Error::RangeNotSatisfiable) => {
+                    debug!("{name} was already complete");
+                    break;
+                }
+                Err(plex::Error::RangeNotSupported) => {
+                    warn!("server ignored range request, restarting {name}");
+                    writer.journal.remove(&name);
+                    fs::remove_file(&part_path)?;
+                }
+                Err(err) => {
+                    // only give up when we stop making progress
+                    failures = if downloaded > offset { 0 } else { failures + 1 };
+                    if failures >= MAX_RETRIES {
+                        return Err(err.into());
+                    }
+                    warn!("download of {name} interrupted at byte {downloaded}, retrying: {err:?}");
+                    time::sleep(RETRY_DELAY * failures).await;
+                }
+            }
+        }
+
+        let size = fs::metadata(&part_path)?.len();
+        if part.size().is_some_and(|expected| expected != size) {
+            warn!("{name} is {size} bytes, expected {:?}", part.size());
+            writer.journal.remove(&name);
+            writer.journal.save().ok();
+            fs::remove_file(&part_path).ok();
+            return Err(Error::SizeMismatch);
+        }
+
+        fs::rename(&part_path, &path)?;
+        writer.completed += size;
+        writer.journal.remove(&name);
+        writer.journal.save()?;
+        debug!("downloaded {size} bytes to {path:?}");
+        paths.push((path, part.duration().unwrap_or_default()));
+    }
+
+    writer.journal.delete();
+    writer.emit();
+
+    // prefer chapters embedded in the files over what plex reports
+    Ok(chapters::from_files(&paths).unwrap_or_else(|err| {
+        debug!("No embedded chapters for {}: {err:?}", job.key);
+        Box::new([])
+    }))
+}
+
+/// Whether the folder holds a download that hasn't finished yet
+pub(super) fn is_incomplete(location: &Path) -> bool {
+    if location.join(DownloadJournal::FILE).exists() {
+        return true;
+    }
+
+    fs::read_dir(location).is_ok_and(|entries| {
+        entries
+            .flatten()
+            .any(|entry| entry.path().extension().is_some_and(|ext| ext == "part"))
+    })
+}
+
+/// Records how many bytes of each `.part` file are known to be safely on disk
+#[derive(Serialize, Deserialize, Default)]
+struct DownloadJournal {
+    #[serde(skip)]
+    path: PathBuf,
+    offsets: HashMap<String, u64>,
+}
+
+impl DownloadJournal {
+    const FILE: &'static str = ".download-journal";
+    fn load(location: &Path) -> Self {
+        let path = location.join(Self::FILE);
+        let journal = fs::read(&path)
+            .ok()
+            .and_then(|journal| serde_json::from_slice(&journal).ok());
+
+        Self {
+            path,
+            ..journal.unwrap_or_default()
+        }
+    }
+
+    fn save(&self) -> Result<()> {
+        fs::write(&self.path, serde_json::to_vec(self)?)?;
+        Ok(())
+    }
+
+    fn delete(&self) {
+        fs::remove_file(&self.path).ok();
+    }
+
+    fn record(&mut self, name: &str, offset: u64) {
+        self.offsets.insert(name.to_string(), offset);
+    }
+
+    fn remove(&mut self, name: &str) {
+        self.offsets.remove(name);
+    }
+
+    /// Anything past the journaled offset may not have been flushed so is thrown away
+    fn resume(&self, name: &str, file: &File) -> io::Result<u64> {
+        let length = file.metadata()?.len();
+        let offset = self.offsets.get(name).copied().unwrap_or(0).min(length);
+        file.set_len(offset)?;
+
+        Ok(offset)
+    }
+}
+
+struct PartFile {
+    name: String,
+    file: BufWriter<File>,
+    offset: u64,
+}
+
+/// Wraps the file being downloaded to report progress and allow cancelling mid transfer
+struct ProgressWriter<'a> {
+    report: &'a (dyn Fn(&DownloadProgress) + Sync),
+    part: Option<PartFile>,
+    cancelled: &'a AtomicBool,
+    journal: DownloadJournal,
+    progress: DownloadProgress,
+    last_emit: Instant,
+    completed: u64,
+    unjournaled: u64,
+}
+
+impl ProgressWriter<'_> {
+    fn emit(&mut self) {
+        self.last_emit = Instant::now();
+        self.progress.downloaded =
+            self.completed + self.part.as_ref().map(|part| part.offset).unwrap_or(0);
+        (self.report)(&self.progress);
+    }
+
+    /// Opens the part file for appending, returning the offset to resume from
+    fn open(&mut self, name: &str, path: &Path) -> Result<u64> {
+        let mut file = OpenOptions::new()
+            .create(true)
+            .truncate(false)
+            .write(true)
+            .open(path)?;
+        let offset = self.journal.resume(name, &file)?;
+        file.seek(SeekFrom::Start(offset))?;
+
+        self.part = Some(PartFile {
+            name: name.to_string(),
+            file: BufWriter::new(file),
+            offset,
+        });
+
+        Ok(offset)
+    }
+
+    /// Flushes and journals the current part file, returning how far it got
+    fn close(&mut self) -> Result<u64> {
+        self.checkpoint()?;
+        let part = self.part.take().ok_or(Error::NoFilesFound)?;
+
+        Ok(part.offset)
+    }
+
+    fn checkpoint(&mut self) -> io::Result<()> {
+        if let Some(part) = self.part.as_mut() {
+            part.file.flush()?;
+            self.journal.record(&part.name, part.offset);
+            self.journal.save().map_err(io::Error::other)?;
+            self.unjournaled = 0;
+        }
+
+        Ok(())
+    }
+}
+
+impl Write for ProgressWriter<'_> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        if self.cancelled.load(Ordering::Relaxed) {
+            return Err(io::Error::other("download cancelled"));
+        }
+
+        let part = self
+            .part
+            .as_mut()
+            .ok_or_else(|| io::Error::other("no file to download to"))?;
+        let written = part.file.write(buf)?;
+        part.offset += written as u64;
+
+        self.unjournaled += written as u64;
+        if self.unjournaled >= JOURNAL_INTERVAL {
+            self.checkpoint()?;
+        }
+        if self.last_emit.elapsed() >= PROGRESS_INTERVAL {
+            self.emit();
+        }
+
+        Ok(written)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        match self.part.as_mut() {
+            Some(part) => part.file.flush(),
+            None => Ok(()),
+        }
+    }
+}
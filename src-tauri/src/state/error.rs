@@ -1,3 +1,5 @@
+use std::sync::PoisonError;
+
 use derive_more::{Display, Error, From};
 
 use crate::plex;
@@ -8,8 +10,23 @@ pub type Result<T> = core::result::Result<T, Error>;
 pub enum Error {
     StoreEmpty,
     InvalidJson(serde_json::Error),
+    #[cfg(feature = "app")]
     StoreFailed(tauri_plugin_store::Error),
     Plex(plex::Error),
+    Io(std::io::Error),
+    #[cfg(feature = "app")]
     NoBookFound,
+    #[cfg(feature = "app")]
+    NoBookmarkFound,
+    #[cfg(feature = "app")]
     BookNotDownloaded,
+    NoFilesFound,
+    SizeMismatch,
+    FailedToLockState,
+}
+
+impl<T> From<PoisonError<T>> for Error {
+    fn from(_err: PoisonError<T>) -> Self {
+        Self::FailedToLockState
+    }
 }
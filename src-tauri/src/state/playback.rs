@@ -0,0 +1,236 @@
+use std::{
+    mem,
+    sync::Arc,
+    thread,
+    time::{Duration, Instant},
+};
+
+use log::{debug, info, warn};
+use tauri::{async_runtime, AppHandle, Emitter, Manager};
+
+use crate::{
+    player::PlaybackState,
+    plex::{self, Plex, PlexReporter, Timeline},
+};
+
+use super::{AppState, InnerAppState, ReadingState, Result, SleepTimer};
+
+pub(crate) const SLEEP_TIMER_EVENT: &str = "sleep-timer";
+pub(crate) const TOGGLE_PLAYING_EVENT: &str = "toggle-playing";
+
+const POLL_INTERVAL: Duration = Duration::from_secs(1);
+const CHECKPOINT_INTERVAL: Duration = Duration::from_secs(5);
+// plex clients send a timeline about this often while playing
+const TIMELINE_INTERVAL: Duration = Duration::from_secs(10);
+
+/// What the worker last did, so plex only hears about changes and the odd heartbeat
+struct LastSync {
+    state: PlaybackState,
+    checkpointed: Instant,
+    reported: Instant,
+    polled: Instant,
+}
+
+enum Report {
+    Timeline(Timeline),
+    Scrobble(Arc<str>),
+    Unscrobble(Arc<str>),
+}
+
+/// Checkpoints the current book and keeps plex up to date with where it is
+pub(super) fn spawn_worker(app: AppHandle) {
+    thread::spawn(move || {
+        let mut last = LastSync {
+            state: PlaybackState::Stopped,
+            checkpointed: Instant::now(),
+            reported: Instant::now(),
+            polled: Instant::now(),
+        };
+
+        loop {
+            thread::sleep(POLL_INTERVAL);
+            if let Some(resume) = lost_stream(&app) {
+                restream(&app, resume);
+            }
+            // anything sent to plex happens after the state is unlocked
+            let reports = match sync(&app, &mut last) {
+                Ok(reports) => reports,
+                Err(_) => break,
+            };
+            if let Some((mut reporter, reports)) = reports {
+                for report in reports {
+                    let mut result = async_runtime::block_on(send(&reporter, &report));
+                    if result.as_ref().is_err_and(|err| err.is_connection_lost()) {
+                        if let Some(reconnected) =
+                            reconnect(&app).and_then(|(plex, _)| plex.reporter().ok())
+                        {
+                            reporter = reconnected;
+                            result = async_runtime::block_on(send(&reporter, &report));
+                        }
+                    }
+                    if let Err(err) = result {
+                        warn!("Unable to update plex: {err}");
+                    }
+                }
+            }
+        }
+    });
+}
+
+fn sync(app: &AppHandle, last: &mut LastSync) -> Result<Option<(PlexReporter, Vec<Report>)>> {
+    let state = app.state::<AppState>();
+    let mut state = state.lock()?;
+    let elapsed = mem::replace(&mut last.polled, Instant::now()).elapsed();
+    count_down(app, &mut state, elapsed);
+    let playback = state.player.state();
+    let changed = playback != last.state;
+
+    if changed || last.checkpointed.elapsed() >= CHECKPOINT_INTERVAL {
+        state.checkpoint();
+        last.checkpointed = Instant::now();
+    }
+
+    let heartbeat =
+        playback == PlaybackState::Playing && last.reported.elapsed() >= TIMELINE_INTERVAL;
+    if !changed && !heartbeat {
+        return Ok(None);
+    }
+    let previous = mem::replace(&mut last.state, playback);
+    last.reported = Instant::now();
+
+    let Some(book) = state
+        .current_book
+        .as_ref()
+        .and_then(|current| state.books.get(current))
+    else {
+        return Ok(None);
+    };
+    let Ok(reporter) = state.settings.plex.reporter() else {
+        return Ok(None);
+    };
+
+    let mut reports = Vec::new();
+    if previous == PlaybackState::Finished && playback == PlaybackState::Playing {
+        // listening again after finishing
+        reports.push(Report::Unscrobble(book.album_key.clone()));
+    }
+    if let Some(timeline) = book.timeline(playback) {
+        reports.push(Report::Timeline(timeline));
+    }
+    if changed && playback == PlaybackState::Finished {
+        reports.push(Report::Scrobble(book.album_key.clone()));
+    }
+
+    Ok(Some((reporter, reports)))
+}
+
+/// Runs the sleep timer, fading the player out towards the end and pausing it once it runs out
+fn count_down(app: &AppHandle, state: &mut InnerAppState, elapsed: Duration) {
+    let playing = state.player.state() == PlaybackState::Playing;
+    let position = state.player.position();
+    let speed = state.speed();
+    let Some(timer) = &mut state.sleep_timer else {
+        return;
+    };
+
+    if playing {
+        timer.tick(elapsed);
+    }
+    let remaining = timer.remaining(position, speed);
+    if !remaining.is_zero() || !playing {
+        let volume = SleepTimer::volume(remaining, state.settings.sleep.fade_out);
+        if let Err(err) = state.player.set_volume(volume) {
+            warn!("Unable to fade out: {err}");
+        }
+        app.emit(SLEEP_TIMER_EVENT, Some(remaining.as_millis() as u64))
+            .ok();
+        return;
+    }
+
+    info!("Sleep timer finished, pausing");
+    state.sleep_timer = None;
+    if let Err(err) = state.player.pause() {
+        warn!("Unable to pause for the sleep timer: {err}");
+    }
+    state.player.set_volume(1.0).ok();
+    if let Some(current) = state.current_book.clone() {
+        if let Some(book) = state.books.get_mut(&current) {
+            book.state = ReadingState::Paused;
+        }
+    }
+    state.checkpoint();
+    app.emit(SLEEP_TIMER_EVENT, None::<u64>).ok();
+    app.emit(TOGGLE_PLAYING_EVENT, ()).ok();
+}
+
+/// Finds another connection to the server once the current one stops answering, the state is
+/// only locked to take plex out and to put the new connection back
+fn reconnect(app: &AppHandle) -> Option<(Plex, bool)> {
+    let state = app.state::<AppState>();
+    let mut plex = state.lock().ok()?.settings.plex.clone();
+    if let Err(err) = async_runtime::block_on(plex.check_connection()) {
+        warn!("Unable to reconnect to plex: {err}");
+        return None;
+    }
+    let moved = state.lock().ok()?.adopt_plex(&plex);
+
+    Some((plex, moved))
+}
+
+fn lost_stream(app: &AppHandle) -> Option<bool> {
+    let state = app.state::<AppState>();
+    let state = state.lock().ok()?;
+    state.player.take_lost_stream()
+}
+
+/// Loads the current book again from the connection plex moved to after its stream failed,
+/// picking up where it stopped
+fn restream(app: &AppHandle, resume: bool) {
+    if !reconnect(app).is_some_and(|(_, moved)| moved) {
+        debug!("Stream failed without the connection to plex changing");
+        return;
+    }
+
+    let state = app.state::<AppState>();
+    let Ok(state) = state.lock() else {
+        return;
+    };
+    let Some(book) = state
+        .current_book
+        .as_ref()
+        .and_then(|current| state.books.get(current))
+    else {
+        return;
+    };
+    let tracks = match book.player_tracks(&state.settings.plex) {
+        Ok(tracks) => tracks,
+        Err(err) => {
+            warn!("Unable to stream {} again: {err}", book.album_key);
+            return;
+        }
+    };
+
+    info!("Streaming {} from the new connection", book.album_key);
+    let position = state.player.track_position();
+    let result = state.player.load(tracks, position).and_then(|_| {
+        if resume {
+            state.player.play()
+        } else {
+            Ok(())
+        }
+    });
+    if let Err(err) = result {
+        warn!("Unable to stream {} again: {err}", book.album_key);
+    }
+}
+
+async fn send(reporter: &PlexReporter, report: &Report) -> plex::Result<()> {
+    match report {
+        Report::Timeline(timeline) => reporter.timeline(timeline).await,
+        Report::Scrobble(key) => {
+            debug!("Finished {key}");
+            reporter.scrobble(key).await
+        }
+        Report::Unscrobble(key) => reporter.unscrobble(key).await,
+    }
+}
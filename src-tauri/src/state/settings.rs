@@ -1,21 +1,96 @@
+use std::sync::Arc;
+
 use derive_more::Display;
 use log::{debug, warn};
 use serde::{Deserialize, Serialize};
-use tauri::Wry;
-use tauri_plugin_store::Store;
+use uuid::Uuid;
 
 use crate::plex::Plex;
 
-use super::Error;
+use super::{Error, SleepSettings, Storage};
 
-#[derive(Serialize, Deserialize, Display, Default)]
+#[derive(Serialize, Deserialize, Display)]
+#[display(fmt = "{}", plex)]
 pub(crate) struct AppSettings {
     pub(crate) plex: Plex,
+    /// Speed for books that haven't had one picked
+    #[serde(default = "default_speed")]
+    pub(crate) playback_speed: f64,
+    #[serde(default)]
+    pub(crate) sleep: SleepSettings,
+    #[serde(default)]
+    pub(crate) skip: SkipSettings,
+    #[serde(default)]
+    pub(crate) remote: RemoteSettings,
+}
+
+fn default_speed() -> f64 {
+    1.0
+}
+
+/// Furthest the rewind and forward buttons can be set to move, in seconds
+#[cfg(feature = "app")]
+pub(crate) const MAX_SKIP: u64 = 600;
+
+/// How far the rewind and forward buttons move, in seconds
+#[derive(Serialize, Deserialize)]
+pub(crate) struct SkipSettings {
+    pub(crate) rewind: u64,
+    pub(crate) forward: u64,
+}
+
+impl Default for SkipSettings {
+    fn default() -> Self {
+        Self {
+            rewind: 15,
+            forward: 30,
+        }
+    }
+}
+
+/// The http api for controlling the player from scripts and other devices, off unless turned on
+#[derive(Serialize, Deserialize, Clone)]
+pub(crate) struct RemoteSettings {
+    pub(crate) enabled: bool,
+    /// Only this machine can reach the default, `0.0.0.0` opens it up to the network
+    pub(crate) address: Arc<str>,
+    pub(crate) port: u16,
+    /// Sent as `Authorization: Bearer <token>` with every request
+    pub(crate) token: Arc<str>,
+}
+
+impl Default for RemoteSettings {
+    fn default() -> Self {
+        Self {
+            enabled: false,
+            address: "127.0.0.1".into(),
+            port: 8437,
+            token: Self::new_token(),
+        }
+    }
+}
+
+impl RemoteSettings {
+    pub(crate) fn new_token() -> Arc<str> {
+        Uuid::new_v4().simple().to_string().into()
+    }
+}
+
+impl Default for AppSettings {
+    fn default() -> Self {
+        Self {
+            plex: Plex::default(),
+            playback_speed: default_speed(),
+            sleep: SleepSettings::default(),
+            skip: SkipSettings::default(),
+            remote: RemoteSettings::default(),
+        }
+    }
 }
 
 impl AppSettings {
     const STORE: &'static str = "settings";
-    pub(super) fn from_store(store: &mut Store<Wry>) -> Self {
+    pub(crate) fn from_store(store: &mut impl Storage) -> Self {
         debug!("Loading {} store", Self::STORE);
         let settings = if let Some(settings) = store.get(Self::STORE) {
             serde_json::from_value(settings.to_owned()).map_err(|err| err.into())
@@ -40,7 +115,7 @@ impl AppSettings {
         }
     }
 
-    pub(super) fn save(&self, store: &mut Store<Wry>) {
+    pub(crate) fn save(&self, store: &mut impl Storage) {
         debug!("Saving to {} store", Self::STORE);
         store.delete(Self::STORE).ok();
         store
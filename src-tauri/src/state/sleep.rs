@@ -0,0 +1,103 @@
+#[cfg(feature = "app")]
+use std::time::Duration;
+
+use serde::{Deserialize, Serialize};
+
+#[cfg(feature = "app")]
+use super::Book;
+
+/// How the sleep timer was set, kept so activity can start it over
+#[cfg(feature = "app")]
+#[derive(Clone, Copy, Debug)]
+pub(crate) enum SleepMode {
+    /// After this much listening
+    Duration(Duration),
+    /// Once the chapter being listened to ends
+    EndOfChapter,
+}
+
+/// Longest the sleep timer can be set for, in minutes
+#[cfg(feature = "app")]
+pub(crate) const MAX_SLEEP: u64 = 24 * 60;
+/// Longest the sleep timer can fade out for, in seconds
+#[cfg(feature = "app")]
+pub(crate) const MAX_FADE_OUT: u64 = 10 * 60;
+
+#[derive(Serialize, Deserialize)]
+pub(crate) struct SleepSettings {
+    /// Seconds before the timer fires that the volume starts fading out
+    pub(crate) fade_out: u64,
+    /// Starts the timer over whenever the player is used
+    pub(crate) extend_on_activity: bool,
+}
+
+impl Default for SleepSettings {
+    fn default() -> Self {
+        Self {
+            fade_out: 30,
+            extend_on_activity: true,
+        }
+    }
+}
+
+/// Pauses the player once enough has been listened to, only counting down while playing
+#[cfg(feature = "app")]
+pub(crate) struct SleepTimer {
+    mode: SleepMode,
+    remaining: Duration,
+    // where in the book the chapter ends, for `SleepMode::EndOfChapter`
+    chapter_end: u64,
+}
+
+#[cfg(feature = "app")]
+impl SleepTimer {
+    /// `position` is where the player is in `book`
+    pub(crate) fn new(mode: SleepMode, book: &Book, position: u64) -> Self {
+        let mut timer = Self {
+            mode,
+            remaining: Duration::ZERO,
+            chapter_end: 0,
+        };
+        timer.extend(book, position);
+        timer
+    }
+
+    /// Starts the timer over, for the end of chapter mode that is whichever chapter is playing now
+    pub(crate) fn extend(&mut self, book: &Book, position: u64) {
+        match self.mode {
+            SleepMode::Duration(length) => self.remaining = length,
+            SleepMode::EndOfChapter => {
+                self.chapter_end = book
+                    .chapter_at(position)
+                    .map(|chapter| chapter.end)
+                    .unwrap_or_else(|| book.duration());
+            }
+        }
+    }
+
+    /// Counts down listening time, chapters count down on their own as the player moves
+    pub(crate) fn tick(&mut self, elapsed: Duration) {
+        if let SleepMode::Duration(_) = self.mode {
+            self.remaining = self.remaining.saturating_sub(elapsed);
+        }
+    }
+
+    /// Listening time left, `position` is where the player is in the book
+    pub(crate) fn remaining(&self, position: u64, speed: f64) -> Duration {
+        match self.mode {
+            SleepMode::Duration(_) => self.remaining,
+            SleepMode::EndOfChapter => {
+                let left = self.chapter_end.saturating_sub(position) as f64 / speed;
+                Duration::from_millis(left as u64)
+            }
+        }
+    }
+
+    /// Volume for the player with `remaining` left, fading out over the last `fade_out` seconds
+    pub(crate) fn volume(remaining: Duration, fade_out: u64) -> f32 {
+        if fade_out == 0 {
+            return 1.0;
+        }
+        (remaining.as_secs_f32() / fade_out as f32).min(1.0)
+    }
+}
@@ -0,0 +1,83 @@
+use std::{collections::HashMap, fs, io::ErrorKind, path::PathBuf};
+
+use log::debug;
+use serde_json::Value;
+#[cfg(feature = "app")]
+use tauri::Wry;
+#[cfg(feature = "app")]
+use tauri_plugin_store::Store;
+
+use super::Result;
+
+/// Key value storage the state is saved to, the app uses the store plugin and the cli a file
+pub(crate) trait Storage {
+    fn get(&self, key: &str) -> Option<&Value>;
+    fn insert(&mut self, key: String, value: Value) -> Result<()>;
+    fn delete(&mut self, key: &str) -> Result<()>;
+    fn save(&self) -> Result<()>;
+}
+
+#[cfg(feature = "app")]
+impl Storage for Store<Wry> {
+    fn get(&self, key: &str) -> Option<&Value> {
+        Store::get(self, key)
+    }
+
+    fn insert(&mut self, key: String, value: Value) -> Result<()> {
+        Ok(Store::insert(self, key, value)?)
+    }
+
+    fn delete(&mut self, key: &str) -> Result<()> {
+        Store::delete(self, key)?;
+        Ok(())
+    }
+
+    fn save(&self) -> Result<()> {
+        Ok(Store::save(self)?)
+    }
+}
+
+/// A store kept in a json file at `path`, laid out the same as the store plugin's so the cli
+/// can be pointed at the app's own store
+pub(crate) struct FileStore {
+    path: PathBuf,
+    values: HashMap<String, Value>,
+}
+
+impl FileStore {
+    /// Loads the store at `path`, starting empty if there is nothing there yet
+    pub(crate) fn open(path: PathBuf) -> Result<Self> {
+        debug!("Opening store at {path:?}");
+        let values = match fs::read(&path) {
+            Ok(values) => serde_json::from_slice(&values)?,
+            Err(err) if err.kind() == ErrorKind::NotFound => HashMap::new(),
+            Err(err) => return Err(err.into()),
+        };
+
+        Ok(Self { path, values })
+    }
+}
+
+impl Storage for FileStore {
+    fn get(&self, key: &str) -> Option<&Value> {
+        self.values.get(key)
+    }
+
+    fn insert(&mut self, key: String, value: Value) -> Result<()> {
+        self.values.insert(key, value);
+        Ok(())
+    }
+
+    fn delete(&mut self, key: &str) -> Result<()> {
+        self.values.remove(key);
+        Ok(())
+    }
+
+    fn save(&self) -> Result<()> {
+        if let Some(parent) = self.path.parent() {
+            fs::create_dir_all(parent)?;
+        }
+        fs::write(&self.path, serde_json::to_vec(&self.values)?)?;
+        Ok(())
+    }
+}
@@ -0,0 +1,14 @@
+REM a sheet covering two files
+TITLE "Book"
+FILE "chapters.mp3" MP3
+  TRACK 01 AUDIO
+    TITLE "Part One"
+    INDEX 01 00:00:00
+  TRACK 02 AUDIO
+    TITLE "Part Two"
+    INDEX 00 00:03:00
+    INDEX 01 00:04:37
+FILE "other.mp3" MP3
+  TRACK 03 AUDIO
+    TITLE "Elsewhere"
+    INDEX 01 00:00:00
@@ -46,6 +46,11 @@ listen("update-settings", (_) => {
   htmx.trigger(htmx.find("body")!, "update-settings", null);
 });
 
+listen("update-bookmarks", (_) => {
+  debug(`update-bookmarks event`);
+  htmx.trigger(htmx.find("body")!, "update-bookmarks", null);
+});
+
 listen("update-player", (_) => {
   debug(`update-player event`);
   let player: HTMLInputElement | null = document.querySelector("#player");
@@ -54,6 +59,44 @@ listen("update-player", (_) => {
   }
 });
 
+listen("toggle-playing", (_) => {
+  debug(`toggle-playing event`);
+  let button: HTMLElement | null = document.querySelector("#player .play-button");
+  if (button) {
+    button.textContent = button.textContent?.trim() === "pause" ? "play" : "pause";
+  }
+});
+
+listen("sleep-timer", (event: any) => {
+  const remaining: number | null = event.payload;
+  let label: HTMLElement | null = document.querySelector("#player .timer-remaining");
+  if (label) {
+    const seconds = Math.ceil((remaining ?? 0) / 1000);
+    label.textContent =
+      remaining === null ? "" : `${Math.floor(seconds / 60)}:${String(seconds % 60).padStart(2, "0")}`;
+  }
+  let timer: HTMLSelectElement | null = document.querySelector("#player .timer");
+  if (timer && remaining === null) {
+    timer.value = "off";
+  }
+});
+
+listen("download-progress", (event: any) => {
+  const { key, downloaded, total } = event.payload;
+  let downloadbtn: HTMLElement | null = document.querySelector("#download-btn");
+  if (downloadbtn && downloadbtn.dataset.key === key) {
+    if (downloaded >= total) {
+      downloadbtn.onclick = () => {
+        (<any>window).delete(key);
+      };
+      downloadbtn.textContent = "Delete";
+    } else {
+      const percent = total > 0 ? Math.floor((downloaded / total) * 100) : 0;
+      downloadbtn.textContent = `Cancel (${percent}%)`;
+    }
+  }
+});
+
 (<any>window).updateServer = async () => {
   debug("updateServer triggered");
   let input: HTMLInputElement = document.querySelector("#server-input")!;
@@ -92,6 +135,12 @@ listen("update-player", (_) => {
     player.classList.remove("shrink");
   }
 };
+(<any>window).togglePlayback = async (button: HTMLElement) => {
+  const playing = button.textContent?.trim() === "pause";
+  debug(`togglePlayback triggered while ${playing ? "playing" : "paused"}`);
+  await invoke(playing ? "player_pause" : "player_play");
+  button.textContent = playing ? "play" : "pause";
+};
 (<any>window).tabButton = async () => {
   (<any>window).closePlayer();
   (<any>window).closeModal();
@@ -105,10 +154,23 @@ listen("update-player", (_) => {
   let downloadbtn: HTMLElement | null = document.querySelector("#download-btn");
   if (downloadbtn) {
     downloadbtn.onclick = () => {
-      (<any>window).delete(key);
+      (<any>window).cancelDownload(key);
+    };
+    downloadbtn.textContent = "Cancel";
+  }
+};
+(<any>window).cancelDownload = async (key: String) => {
+  debug(`cancelDownload triggered with ${key}`);
+  let downloadbtn: HTMLElement | null = document.querySelector("#download-btn");
+  if (downloadbtn) {
+    downloadbtn.onclick = () => {
+      (<any>window).download(key);
     };
-    downloadbtn.textContent = "Delete";
+    downloadbtn.textContent = "Download";
   }
+  await invoke("plex_cancel_download", {
+    key: key,
+  });
 };
 (<any>window).delete = async (key: String) => {
   debug(`delete triggered with ${key}`);